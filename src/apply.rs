@@ -0,0 +1,480 @@
+//! Carrying out on disk what was decided in a [`State`].
+
+use std::{fmt::Display, io::Write};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{FilepersonError, Op, State, Tag};
+
+/// Something [`State::apply`] does to a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ActionKind {
+    /// Remove the file, for infos whose delete flag is `Some(true)`.
+    Delete,
+}
+
+/// One pending change to one file, see [`State::pending_actions`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Action {
+    /// The path as stored in the state; relative for [`State::new_relative`] states.
+    pub path: Utf8PathBuf,
+    pub kind: ActionKind,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ActionKind::Delete => write!(f, "delete {}", self.path),
+        }
+    }
+}
+
+/// Knobs for [`State::apply_with`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ApplyOptions {
+    /// Remove files for good instead of moving them to the system trash. Without the `trash`
+    /// feature, only permanent deletion is available and deleting otherwise fails.
+    pub permanent: bool,
+}
+
+/// The outcome of [`State::apply`], per action.
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    /// Actions carried out, in the order they were attempted.
+    pub done: Vec<Action>,
+    /// Actions that failed. Their files keep their pending flags, so a later apply retries them.
+    pub failed: Vec<(Action, std::io::Error)>,
+}
+
+impl ApplyReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// What [`State::apply`] would do and what might go wrong, see [`State::plan`]. The
+/// `Display` impl gives a summary for showing before asking to go ahead.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// In the order [`State::apply`] would carry them out.
+    pub actions: Vec<PlannedAction>,
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedAction {
+    pub action: Action,
+    /// Size of the file on disk, if it could be statted.
+    pub size: Option<u64>,
+}
+
+/// Something about a pending action the user probably wants to know first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// The file is to be deleted but still carries tags, which are lost with it.
+    Tagged { path: Utf8PathBuf, tags: Vec<Tag> },
+    /// There is nothing at the path, so the action would fail.
+    Missing(Utf8PathBuf),
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Bytes freed by the deletions, counting files of unknown size as empty.
+    pub fn reclaimed(&self) -> u64 {
+        self.actions
+            .iter()
+            .filter(|planned| planned.action.kind == ActionKind::Delete)
+            .filter_map(|planned| planned.size)
+            .sum()
+    }
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Conflict::Tagged { path, tags } => {
+                write!(f, "{path} is tagged {}", tags.iter().join(", "))
+            }
+            Conflict::Missing(path) => write!(f, "{path} does not exist"),
+        }
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "nothing to do");
+        }
+        writeln!(
+            f,
+            "{} actions, freeing {} bytes:",
+            self.actions.len(),
+            self.reclaimed()
+        )?;
+        for planned in &self.actions {
+            writeln!(f, "  {}", planned.action)?;
+        }
+        if !self.conflicts.is_empty() {
+            writeln!(f, "{} conflicts:", self.conflicts.len())?;
+            for conflict in &self.conflicts {
+                writeln!(f, "  {conflict}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One line of the manifest written by [`State::apply_deletions_with_manifest`].
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    path: Utf8PathBuf,
+    size: Option<u64>,
+    tags: Vec<&'a str>,
+}
+
+#[cfg(feature = "trash")]
+fn move_to_trash(path: &Utf8Path) -> std::io::Result<()> {
+    // `trash` succeeds quietly for paths that don't exist; report them like `remove_file`.
+    std::fs::symlink_metadata(path)?;
+    trash::delete(path).map_err(std::io::Error::other)
+}
+
+#[cfg(not(feature = "trash"))]
+fn move_to_trash(path: &Utf8Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("cannot move {path} to the trash: built without the trash feature"),
+    ))
+}
+
+impl State {
+    /// What [`State::apply`] would do, sorted by path.
+    pub fn pending_actions(&self) -> Vec<Action> {
+        self.infos
+            .iter()
+            .filter(|f| f.delete == Some(true))
+            .map(|f| Action {
+                path: f.path.clone(),
+                kind: ActionKind::Delete,
+            })
+            .sorted_by(|a, b| a.path.cmp(&b.path))
+            .collect()
+    }
+
+    /// Describe what [`State::apply`] would do, without changing anything. Looks up each file
+    /// on disk for its size and to flag missing ones.
+    pub fn plan(&self) -> Plan {
+        let mut plan = Plan::default();
+        for action in self.pending_actions() {
+            let metadata = std::fs::symlink_metadata(self.absolute_path(&action.path)).ok();
+            if metadata.is_none() {
+                plan.conflicts.push(Conflict::Missing(action.path.clone()));
+            }
+            let tags = self
+                .get(&action.path)
+                .map(|f| f.tags.clone())
+                .unwrap_or_default();
+            if action.kind == ActionKind::Delete && !tags.is_empty() {
+                plan.conflicts.push(Conflict::Tagged {
+                    path: action.path.clone(),
+                    tags,
+                });
+            }
+            plan.actions.push(PlannedAction {
+                action,
+                size: metadata.map(|m| m.len()),
+            });
+        }
+        plan
+    }
+
+    /// Carry out every [`State::pending_actions`] on disk, moving deleted files to the system
+    /// trash. Once all actions were attempted, the state is updated for the ones that
+    /// succeeded in one go: deleted files leave the trees and infos. Failed actions are
+    /// reported and stay pending.
+    pub fn apply(&mut self) -> ApplyReport {
+        self.apply_with(&ApplyOptions::default())
+    }
+
+    /// Like [`State::apply`], with non-default [`ApplyOptions`].
+    pub fn apply_with(&mut self, options: &ApplyOptions) -> ApplyReport {
+        let actions = self.pending_actions();
+        self.apply_actions(actions, options, move_to_trash)
+    }
+
+    /// Like [`State::apply_with`], but first writes one JSON line per file to be deleted to `w`,
+    /// with its on-disk path, size and tags, and flushes it before anything is removed. The
+    /// manifest thus also lists files whose removal then fails; those are only logged.
+    /// Returns the files actually deleted.
+    pub fn apply_deletions_with_manifest(
+        &mut self,
+        mut w: impl Write,
+        options: &ApplyOptions,
+    ) -> Result<Vec<Utf8PathBuf>, FilepersonError> {
+        let actions: Vec<Action> = self
+            .pending_actions()
+            .into_iter()
+            .filter(|action| action.kind == ActionKind::Delete)
+            .collect();
+        for action in &actions {
+            let on_disk = self.absolute_path(&action.path);
+            let info = self.get(&action.path);
+            let entry = ManifestEntry {
+                size: info
+                    .and_then(|f| f.size)
+                    .or_else(|| std::fs::metadata(&on_disk).ok().map(|m| m.len())),
+                tags: info
+                    .map(|f| f.tags.iter().map(|t| &*t.value).collect())
+                    .unwrap_or_default(),
+                path: on_disk,
+            };
+            serde_json::to_writer(&mut w, &entry)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+
+        let report = self.apply_actions(actions, options, move_to_trash);
+        for (action, e) in &report.failed {
+            log::warn!("could not {action}: {e}");
+        }
+        Ok(report.done.into_iter().map(|action| action.path).collect())
+    }
+
+    /// Carry out `actions`, moving files to the trash through `trash` unless
+    /// [`ApplyOptions::permanent`].
+    fn apply_actions(
+        &mut self,
+        actions: Vec<Action>,
+        options: &ApplyOptions,
+        mut trash: impl FnMut(&Utf8Path) -> std::io::Result<()>,
+    ) -> ApplyReport {
+        let mut report = ApplyReport::default();
+        for action in actions {
+            match self.perform(&action, options, &mut trash) {
+                Ok(()) => report.done.push(action),
+                Err(e) => report.failed.push((action, e)),
+            }
+        }
+        for action in &report.done {
+            match action.kind {
+                ActionKind::Delete => self.forget(&action.path),
+            }
+        }
+        report
+    }
+
+    fn perform(
+        &self,
+        action: &Action,
+        options: &ApplyOptions,
+        trash: &mut impl FnMut(&Utf8Path) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let path = self.absolute_path(&action.path);
+        match action.kind {
+            ActionKind::Delete if options.permanent => std::fs::remove_file(path),
+            ActionKind::Delete => trash(&path),
+        }
+    }
+
+    /// Drop `path` from the trees and infos, and its undo history: undoing an edit of a file
+    /// that's gone would only bring back a stale info.
+    fn forget(&mut self, path: &Utf8Path) {
+        self.record(Op::Untrack {
+            path: path.to_owned(),
+        });
+        self.forget_history(path);
+        self.root.remove_node(path);
+        self.flat.remove_node(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::tests::{fs_fixture, relative_files, snapshot};
+
+    use super::*;
+
+    #[test]
+    fn test_apply() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav", "keep.wav", "gone.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        for file in ["a.wav", "sub/b.wav", "gone.wav"] {
+            state.set_delete(root.join(file), Some(true));
+        }
+        state.set_delete(root.join("keep.wav"), Some(false));
+        std::fs::remove_file(root.join("gone.wav"))?;
+
+        let report = state.apply_with(&ApplyOptions { permanent: true });
+        assert!(!report.is_success());
+        let delete = |file: &str| Action {
+            path: root.join(file),
+            kind: ActionKind::Delete,
+        };
+        assert_eq!(report.done, vec![delete("a.wav"), delete("sub/b.wav")]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, delete("gone.wav"));
+        assert_eq!(report.failed[0].1.kind(), std::io::ErrorKind::NotFound);
+
+        assert!(!root.join("a.wav").exists());
+        assert!(!root.join("sub/b.wav").exists());
+        assert_eq!(
+            relative_files(&state.root, &root),
+            vec!["gone.wav", "keep.wav"]
+        );
+        assert_eq!(
+            relative_files(&state.flat, &root),
+            vec!["gone.wav", "keep.wav"]
+        );
+        assert_eq!(state.pending_actions(), vec![delete("gone.wav")]);
+        assert_eq!(state.infos.len(), 2);
+
+        let replayed = State::replay(state.root.clone(), state.op_log())?;
+        assert_eq!(snapshot(&replayed), snapshot(&state));
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_after_apply() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "keep.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("keep.wav"), "drums")?;
+        state.add_tag(root.join("a.wav"), "scratch")?;
+        state.set_delete(root.join("a.wav"), Some(true));
+
+        assert!(state
+            .apply_with(&ApplyOptions { permanent: true })
+            .is_success());
+        assert!(state.undo());
+        assert!(state.get(&root.join("a.wav")).is_none());
+        assert!(state.get(&root.join("keep.wav")).unwrap().tags().is_empty());
+        assert!(!state.undo());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_deletions_with_manifest() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav", "keep.wav"])?;
+        std::fs::write(root.join("a.wav"), "123")?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("a.wav"), "scratch")?;
+        state.add_tag(root.join("a.wav"), "drums")?;
+        state.set_delete(root.join("a.wav"), Some(true));
+        state.set_delete(root.join("sub/b.wav"), Some(true));
+
+        let mut manifest = vec![];
+        let options = ApplyOptions { permanent: true };
+        let deleted = state.apply_deletions_with_manifest(&mut manifest, &options)?;
+        assert_eq!(deleted, vec![root.join("a.wav"), root.join("sub/b.wav")]);
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&manifest)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({"path": root.join("a.wav"), "size": 3, "tags": ["drums", "scratch"]}),
+                serde_json::json!({"path": root.join("sub/b.wav"), "size": 0, "tags": []}),
+            ]
+        );
+        for path in &deleted {
+            assert!(!path.exists());
+        }
+        assert!(root.join("keep.wav").exists());
+        assert!(state.infos.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_trashes_unless_permanent() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav", "keep.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.set_delete(root.join("a.wav"), Some(true));
+        state.set_delete(root.join("sub/b.wav"), Some(true));
+
+        let mut trashed = vec![];
+        let actions = state.pending_actions();
+        let report = state.apply_actions(actions, &ApplyOptions::default(), |path| {
+            trashed.push(path.to_owned());
+            Ok(())
+        });
+        assert!(report.is_success(), "{:?}", report.failed);
+        assert_eq!(trashed, vec![root.join("a.wav"), root.join("sub/b.wav")]);
+        assert!(root.join("a.wav").exists(), "the stub leaves files alone");
+        assert_eq!(relative_files(&state.root, &root), vec!["keep.wav"]);
+        assert!(state.pending_actions().is_empty());
+        Ok(())
+    }
+
+    /// Moves a file into the real trash of whoever runs it, so it's only run on request.
+    #[cfg(feature = "trash")]
+    #[test]
+    #[ignore]
+    fn test_apply_moves_to_trash() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["fileperson-trash-test.wav", "keep.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.set_delete(root.join("fileperson-trash-test.wav"), Some(true));
+
+        let report = state.apply();
+        assert!(report.is_success(), "{:?}", report.failed);
+        assert_eq!(report.done.len(), 1);
+        assert!(!root.join("fileperson-trash-test.wav").exists());
+        assert_eq!(relative_files(&state.root, &root), vec!["keep.wav"]);
+        assert!(state.pending_actions().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "b.wav", "keep.wav"])?;
+        std::fs::write(root.join("a.wav"), "123")?;
+        std::fs::write(root.join("b.wav"), "45")?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        for file in ["a.wav", "b.wav", "gone.wav"] {
+            state.set_delete(root.join(file), Some(true));
+        }
+        state.add_tag(root.join("b.wav"), "drums")?;
+        state.add_tag(root.join("keep.wav"), "drums")?;
+
+        let plan = state.plan();
+        assert_eq!(
+            plan.actions
+                .iter()
+                .map(|planned| planned.size)
+                .collect::<Vec<_>>(),
+            vec![Some(3), Some(2), None]
+        );
+        assert_eq!(plan.reclaimed(), 5);
+        assert_eq!(
+            plan.conflicts,
+            vec![
+                Conflict::Tagged {
+                    path: root.join("b.wav"),
+                    tags: vec!["drums".into()]
+                },
+                Conflict::Missing(root.join("gone.wav")),
+            ]
+        );
+        assert_eq!(
+            plan.to_string(),
+            format!(
+                "3 actions, freeing 5 bytes:\n  delete {root}/a.wav\n  delete {root}/b.wav\n  \
+                 delete {root}/gone.wav\n2 conflicts:\n  {root}/b.wav is tagged drums\n  \
+                 {root}/gone.wav does not exist\n",
+                root = root
+            )
+        );
+        assert!(root.join("a.wav").exists(), "planning changes nothing");
+        assert_eq!(state.pending_actions().len(), 3);
+
+        assert_eq!(State::empty(root).plan().to_string(), "nothing to do\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+//! Decoding audio files to learn more about them than the filesystem can tell.
+
+use std::{fs::File, io::BufReader, time::Duration};
+
+use rodio::Source;
+
+use crate::{FileInfo, FilepersonError};
+
+impl FileInfo {
+    /// Decode the file and return its total play length. `Ok(None)` if the format can't
+    /// report one without decoding everything.
+    pub fn audio_duration(&self) -> Result<Option<Duration>, FilepersonError> {
+        let file = File::open(&self.path)?;
+        let decoder = rodio::Decoder::new(BufReader::new(file))?;
+        Ok(decoder.total_duration())
+    }
+
+    /// Like [`FileInfo::audio_duration`], but also stores a found duration in the info.
+    pub fn probe_duration(&mut self) -> Result<Option<Duration>, FilepersonError> {
+        let duration = self.audio_duration()?;
+        if duration.is_some() {
+            self.duration = duration;
+        }
+        Ok(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::*;
+
+    #[test]
+    fn test_audio_duration() -> anyhow::Result<()> {
+        let path = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/beep.wav");
+        let mut info = FileInfo::from(&path);
+        assert_eq!(info.duration(), None);
+
+        let duration = info.probe_duration()?.expect("WAV reports its duration");
+        assert!(
+            duration > Duration::from_millis(200) && duration < Duration::from_millis(300),
+            "{:?}",
+            duration
+        );
+        assert_eq!(info.duration(), Some(duration));
+        assert_eq!(info.audio_duration()?, Some(duration));
+        Ok(())
+    }
+
+    #[test]
+    fn test_audio_duration_not_audio() {
+        let path = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        let mut info = FileInfo::from(&path);
+        assert!(info.probe_duration().is_err());
+        assert_eq!(info.duration(), None);
+    }
+}
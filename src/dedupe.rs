@@ -0,0 +1,243 @@
+//! Finding byte-identical files by content hash, and deciding which copies to let go of.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::BufReader,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use rayon::{iter::Either, prelude::*};
+
+use crate::{ops::Op, State};
+
+fn hash_file(path: &Utf8Path) -> std::io::Result<blake3::Hash> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Outcome of [`State::find_duplicates`].
+#[derive(Debug, Default)]
+pub struct DuplicateReport {
+    /// Groups of two or more files with identical content. Paths within a group and the groups
+    /// themselves are sorted.
+    pub groups: Vec<Vec<Utf8PathBuf>>,
+    /// Files that couldn't be read, e.g. tracked ones deleted since, sorted by path. They're
+    /// left out of the groups.
+    pub failed: Vec<(Utf8PathBuf, std::io::Error)>,
+}
+
+impl DuplicateReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Which file of a duplicate set [`State::mark_duplicates`] keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KeepDuplicate {
+    /// A file already decided to keep, else the one with the most tags, else the first path.
+    #[default]
+    Tagged,
+    /// The first path in sort order.
+    First,
+    /// The path with the fewest components, e.g. the copy outside the `duplicates/` folder.
+    ShallowestPath,
+}
+
+impl KeepDuplicate {
+    /// The index of the file to keep in the sorted, non-empty `group`.
+    fn pick(self, state: &State, group: &[Utf8PathBuf]) -> usize {
+        let info = |i: usize| state.get(&group[i]);
+        let indices = 0..group.len();
+        // min_by_key settles ties on the first path
+        let best = match self {
+            KeepDuplicate::Tagged => indices.min_by_key(|&i| {
+                (
+                    info(i).and_then(|f| f.delete) != Some(false),
+                    Reverse(info(i).map_or(0, |f| f.tags.len())),
+                )
+            }),
+            KeepDuplicate::First => None,
+            KeepDuplicate::ShallowestPath => indices.min_by_key(|&i| group[i].components().count()),
+        };
+        best.unwrap_or(0)
+    }
+}
+
+impl State {
+    /// Groups of files with identical content, among the files in the tree and the tracked ones.
+    /// Only files sharing a size are hashed, in parallel. Files that can't be read are skipped
+    /// and reported, like [`State::stat_all`] does.
+    pub fn find_duplicates(&self) -> DuplicateReport {
+        let paths: BTreeSet<Utf8PathBuf> = self
+            .infos
+            .iter()
+            .map(|info| info.path.clone())
+            .chain(self.root.files().map(Utf8Path::to_owned))
+            .collect();
+        let (sizes, mut failed): (Vec<_>, Vec<_>) = paths
+            .into_par_iter()
+            .map(|path| match std::fs::metadata(self.absolute_path(&path)) {
+                Ok(metadata) => Either::Left((metadata.len(), path)),
+                Err(e) => Either::Right((path, e)),
+            })
+            .partition_map(|either| either);
+        let mut by_size: HashMap<u64, Vec<Utf8PathBuf>> = HashMap::new();
+        for (size, path) in sizes {
+            by_size.entry(size).or_default().push(path);
+        }
+
+        let (digests, unreadable): (Vec<_>, Vec<_>) = by_size
+            .into_values()
+            .filter(|group| group.len() >= 2)
+            .flatten()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|path| match hash_file(&self.absolute_path(&path)) {
+                Ok(digest) => Either::Left((digest, path)),
+                Err(e) => Either::Right((path, e)),
+            })
+            .partition_map(|either| either);
+        failed.extend(unreadable);
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut groups: HashMap<blake3::Hash, Vec<Utf8PathBuf>> = HashMap::new();
+        for (digest, path) in digests {
+            groups.entry(digest).or_default().push(path);
+        }
+        let mut duplicates: Vec<Vec<Utf8PathBuf>> = groups
+            .into_values()
+            .filter(|group| group.len() >= 2)
+            .map(|mut group| {
+                group.sort();
+                group
+            })
+            .collect();
+        duplicates.sort();
+        DuplicateReport {
+            groups: duplicates,
+            failed,
+        }
+    }
+
+    /// Mark all but one file of each of the `duplicates` for deletion, as one undoable edit,
+    /// tracking the files that aren't yet. `keep` chooses the survivor, which is left as it is.
+    /// Returns the number of files newly marked.
+    pub fn mark_duplicates(
+        &mut self,
+        duplicates: &[Vec<Utf8PathBuf>],
+        keep: KeepDuplicate,
+    ) -> usize {
+        let doomed: Vec<Utf8PathBuf> = duplicates
+            .iter()
+            .filter(|group| !group.is_empty())
+            .flat_map(|group| {
+                let kept = keep.pick(self, group);
+                group
+                    .iter()
+                    .enumerate()
+                    .filter(move |(i, _)| *i != kept)
+                    .map(|(_, path)| path.clone())
+            })
+            .collect();
+        self.track_edit(doomed.clone(), |state| {
+            doomed
+                .into_iter()
+                .map(|path| {
+                    state.record(Op::SetDelete {
+                        path,
+                        delete: Some(true),
+                    })
+                })
+                .filter(|&changed| changed > 0)
+                .count()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::fs_fixture, FileInfo};
+
+    use super::*;
+
+    #[test]
+    fn test_find_duplicates() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[])?;
+        std::fs::write(root.join("a.wav"), "same")?;
+        std::fs::write(root.join("b.wav"), "same")?;
+        std::fs::write(root.join("c.wav"), "different")?;
+
+        let mut state = State::new(&root, ["wav"].into())?;
+        state.extend(["a.wav", "b.wav", "c.wav"].map(|f| FileInfo::from(root.join(f))));
+        assert_eq!(
+            state.find_duplicates().groups,
+            vec![vec![root.join("a.wav"), root.join("b.wav")]]
+        );
+
+        // a stale tracked file doesn't stop the search
+        state.add(FileInfo::from(root.join("gone.wav")))?;
+        let report = state.find_duplicates();
+        assert_eq!(
+            report.groups,
+            vec![vec![root.join("a.wav"), root.join("b.wav")]]
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, root.join("gone.wav"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_duplicates() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["pack/dupes/x", "pack/old/copy/x"])?;
+        let [shallow, dupe, deep, snare] = [
+            "pack/kick.wav",
+            "pack/dupes/kick.wav",
+            "pack/old/copy/kick.wav",
+            "pack/snare.wav",
+        ]
+        .map(|f| root.join(f));
+        for path in [&shallow, &dupe, &deep] {
+            std::fs::write(path, "kick")?;
+        }
+        std::fs::write(&snare, "snare")?;
+
+        // untracked files are found too
+        let mut state = State::new(&root, ["wav"].into())?;
+        let groups = state.find_duplicates().groups;
+        assert_eq!(
+            groups,
+            vec![vec![dupe.clone(), shallow.clone(), deep.clone()]]
+        );
+
+        let marked = |state: &State| -> Vec<Utf8PathBuf> {
+            groups[0]
+                .iter()
+                .filter(|path| state.get(path).and_then(|f| f.delete()) == Some(true))
+                .cloned()
+                .collect()
+        };
+        assert_eq!(state.mark_duplicates(&groups, KeepDuplicate::First), 2);
+        assert_eq!(marked(&state), [shallow.clone(), deep.clone()]);
+        assert!(state.undo(), "one edit for all files");
+        assert!(marked(&state).is_empty());
+
+        state.mark_duplicates(&groups, KeepDuplicate::ShallowestPath);
+        assert_eq!(marked(&state), [dupe.clone(), deep.clone()]);
+        state.undo();
+
+        state.add_tag(&deep, "808")?;
+        state.mark_duplicates(&groups, KeepDuplicate::Tagged);
+        assert_eq!(marked(&state), [dupe.clone(), shallow.clone()]);
+        state.undo();
+        state.set_delete(&dupe, Some(false));
+        state.mark_duplicates(&groups, KeepDuplicate::Tagged);
+        assert_eq!(marked(&state), [shallow.clone(), deep.clone()]);
+        assert_eq!(state.mark_duplicates(&groups, KeepDuplicate::Tagged), 0);
+        Ok(())
+    }
+}
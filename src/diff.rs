@@ -0,0 +1,277 @@
+//! Comparing two states, e.g. the last saved one against the current one.
+
+use std::collections::{BTreeMap, HashMap};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{FileInfo, FilepersonError, Op, State, Tag};
+
+/// Differences going from one state to another. Borrows from both states.
+#[derive(Debug, Default, PartialEq)]
+pub struct StateDiff<'a> {
+    /// Files tracked only in the newer state.
+    pub added: Vec<&'a Utf8Path>,
+    /// Files tracked only in the older state.
+    pub removed: Vec<&'a Utf8Path>,
+    /// Files tracked in both whose tags differ.
+    pub changed: Vec<TagChange<'a>>,
+}
+
+impl StateDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TagChange<'a> {
+    pub path: &'a Utf8Path,
+    pub added: Vec<&'a Tag>,
+    pub removed: Vec<&'a Tag>,
+}
+
+/// Per-path tag changes turning one state into another, see [`State::changes_since`].
+/// Unlike [`StateDiff`] it owns its data and serializes compactly, so it can be sent elsewhere
+/// and applied with [`State::apply_patch`].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TagPatch {
+    pub files: BTreeMap<Utf8PathBuf, FilePatch>,
+}
+
+impl TagPatch {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// The changes to one file in a [`TagPatch`]. Tags compare by exact spelling, so a changed
+/// casing shows up as a removal plus an addition.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FilePatch {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<Tag>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<Tag>,
+    /// The new deletion flag, if it changed.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "present"
+    )]
+    pub delete: Option<Option<bool>>,
+    /// The file is no longer tracked at all.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub untrack: bool,
+}
+
+/// Reads a present field, even `null`, as `Some` so `delete: null` survives a round trip.
+fn present<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Option<bool>>, D::Error> {
+    Option::<bool>::deserialize(d).map(Some)
+}
+
+fn exactly_in(tag: &Tag, tags: &[Tag]) -> bool {
+    tags.iter().any(|t| t.value == tag.value)
+}
+
+impl State {
+    /// What changed going from `self` to `other`. All lists are sorted by path.
+    pub fn diff<'a>(&'a self, other: &'a State) -> StateDiff<'a> {
+        let before: HashMap<&Utf8Path, &FileInfo> =
+            self.infos.iter().map(|f| (f.path.as_path(), f)).collect();
+        let after: HashMap<&Utf8Path, &FileInfo> =
+            other.infos.iter().map(|f| (f.path.as_path(), f)).collect();
+
+        let mut diff = StateDiff::default();
+        for (path, new) in &after {
+            match before.get(path) {
+                None => diff.added.push(path),
+                Some(old) => {
+                    let added: Vec<&Tag> =
+                        new.tags.iter().filter(|t| !old.tags.contains(t)).collect();
+                    let removed: Vec<&Tag> =
+                        old.tags.iter().filter(|t| !new.tags.contains(t)).collect();
+                    if !added.is_empty() || !removed.is_empty() {
+                        diff.changed.push(TagChange {
+                            path,
+                            added,
+                            removed,
+                        });
+                    }
+                }
+            }
+        }
+        diff.removed = before
+            .keys()
+            .filter(|path| !after.contains_key(*path))
+            .copied()
+            .collect();
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort_by(|a, b| a.path.cmp(b.path));
+        diff
+    }
+
+    /// The patch turning `baseline` into `self`.
+    pub fn changes_since(&self, baseline: &State) -> TagPatch {
+        let mut patch = TagPatch::default();
+        for new in &self.infos {
+            let (old_tags, old_delete) = match baseline.get(&new.path) {
+                Some(old) => (old.tags.as_slice(), old.delete),
+                None => (&[][..], None),
+            };
+            let file = FilePatch {
+                added: new
+                    .tags
+                    .iter()
+                    .filter(|t| !exactly_in(t, old_tags))
+                    .cloned()
+                    .collect(),
+                removed: old_tags
+                    .iter()
+                    .filter(|t| !exactly_in(t, &new.tags))
+                    .cloned()
+                    .collect(),
+                delete: (old_delete != new.delete).then_some(new.delete),
+                untrack: false,
+            };
+            // A file tracked without any decision, in one state but not the other, is a no-op.
+            if file != FilePatch::default() {
+                patch.files.insert(new.path.clone(), file);
+            }
+        }
+        for old in &baseline.infos {
+            if self.get(&old.path).is_none() {
+                let file = FilePatch {
+                    untrack: true,
+                    ..FilePatch::default()
+                };
+                patch.files.insert(old.path.clone(), file);
+            }
+        }
+        patch
+    }
+
+    /// Apply a patch from [`State::changes_since`]. The tag changes form a single undoable edit.
+    /// Returns the number of files changed, and fails without changing anything if any added
+    /// tag is rejected, like [`State::add_tag`].
+    pub fn apply_patch(&mut self, patch: &TagPatch) -> Result<usize, FilepersonError> {
+        for tag in patch.files.values().flat_map(|file| &file.added) {
+            self.check_tag(tag)?;
+        }
+        let paths = patch.files.keys().cloned().collect();
+        Ok(self.track_edit(paths, |state| {
+            let mut changed = 0;
+            for (path, file) in &patch.files {
+                if file.untrack {
+                    changed += state.record(Op::Untrack { path: path.clone() });
+                    continue;
+                }
+                let mut ops: Vec<Op> = file
+                    .removed
+                    .iter()
+                    .map(|tag| Op::RemoveTag {
+                        path: path.clone(),
+                        tag: tag.clone(),
+                    })
+                    .collect();
+                ops.extend(file.added.iter().map(|tag| Op::AddTag {
+                    path: path.clone(),
+                    tag: tag.clone(),
+                }));
+                if let Some(delete) = file.delete {
+                    ops.push(Op::SetDelete {
+                        path: path.clone(),
+                        delete,
+                    });
+                }
+                let hits = ops.into_iter().map(|op| state.record(op)).sum::<usize>();
+                changed += (hits > 0) as usize;
+            }
+            changed
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{snapshot, state_fixture};
+
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let mut before = state_fixture();
+        before.add_tag("/music/kept.wav", "rock").unwrap();
+        before.add_tag("/music/kept.wav", "live").unwrap();
+        before.add_tag("/music/gone.wav", "jazz").unwrap();
+        before.add_tag("/music/same.wav", "jazz").unwrap();
+
+        let mut after = state_fixture();
+        after.add_tag("/music/kept.wav", "ROCK").unwrap();
+        after.add_tag("/music/kept.wav", "studio").unwrap();
+        after.add_tag("/music/new.wav", "jazz").unwrap();
+        after.add_tag("/music/same.wav", "jazz").unwrap();
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added, vec![Utf8Path::new("/music/new.wav")]);
+        assert_eq!(diff.removed, vec![Utf8Path::new("/music/gone.wav")]);
+        assert_eq!(
+            diff.changed,
+            vec![TagChange {
+                path: Utf8Path::new("/music/kept.wav"),
+                added: vec![&Tag::from("studio")],
+                removed: vec![&Tag::from("live")],
+            }]
+        );
+        assert!(after.diff(&after).is_empty());
+    }
+
+    fn baseline() -> State {
+        let mut state = state_fixture();
+        state.add_tag("/music/kept.wav", "rock").unwrap();
+        state.add_tag("/music/kept.wav", "live").unwrap();
+        state.add_tag("/music/gone.wav", "jazz").unwrap();
+        state.set_delete("/music/doomed.wav", Some(true));
+        state.set_delete("/music/spared.wav", Some(true));
+        state
+    }
+
+    #[test]
+    fn test_patch_round_trip() -> anyhow::Result<()> {
+        let mut target = baseline();
+        target.rename_tag(&"rock".into(), "Rock")?;
+        target.remove_tag("/music/kept.wav", &"live".into());
+        target.add_tag("/music/kept.wav", "studio")?;
+        target.add_tag("/music/new.wav", "demo")?;
+        target.set_delete("/music/spared.wav", None);
+        target.infos.remove(Utf8Path::new("/music/gone.wav"));
+
+        let patch = target.changes_since(&baseline());
+        assert_eq!(
+            patch.files.keys().map(|p| p.as_str()).collect::<Vec<_>>(),
+            vec![
+                "/music/gone.wav",
+                "/music/kept.wav",
+                "/music/new.wav",
+                "/music/spared.wav"
+            ]
+        );
+        let json = serde_json::to_string(&patch)?;
+        let patch: TagPatch = serde_json::from_str(&json)?;
+        assert_eq!(
+            patch.files[Utf8Path::new("/music/spared.wav")].delete,
+            Some(None)
+        );
+
+        let mut synced = baseline();
+        assert_eq!(synced.apply_patch(&patch)?, 4);
+        assert_eq!(snapshot(&synced), snapshot(&target));
+        assert!(target.changes_since(&synced).is_empty());
+
+        let replayed = State::replay(synced.root.clone(), synced.op_log())?;
+        assert_eq!(snapshot(&replayed), snapshot(&synced));
+        Ok(())
+    }
+}
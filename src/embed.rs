@@ -0,0 +1,256 @@
+//! Writing tags into the files' own metadata, so they show up in other players and DAWs:
+//! ID3v2 for MP3, WAV and AIFF, Vorbis comments for FLAC, Ogg Vorbis and Opus.
+
+use std::{fs::File, io};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use lofty::{
+    config::{ParseOptions, WriteOptions},
+    file::FileType,
+    flac::FlacFile,
+    id3::v2::Id3v2Tag,
+    iff::{aiff::AiffFile, wav::WavFile},
+    mpeg::MpegFile,
+    ogg::{tag::VorbisComments, OpusFile, VorbisFile},
+    prelude::*,
+    probe::Probe,
+};
+use rayon::prelude::*;
+
+use crate::{FileInfo, FilepersonError, State, Tag};
+
+/// Where the tags go: the description of an ID3v2 `TXXX` frame, or a Vorbis comment field name.
+/// An ID3v2 frame holds all tags as null-separated values; Vorbis comments repeat the field.
+pub const EMBEDDED_TAGS_KEY: &str = "FILEPERSON_TAGS";
+
+/// Outcome of [`State::embed_tags`].
+#[derive(Debug, Default)]
+pub struct EmbedReport {
+    /// Files whose embedded tags were rewritten. Files that already matched aren't touched.
+    pub written: Vec<Utf8PathBuf>,
+    pub failed: Vec<(Utf8PathBuf, FilepersonError)>,
+}
+
+impl EmbedReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// The tag a file format keeps our tags in, as read from the file.
+enum Embedded {
+    Id3v2(Id3v2Tag),
+    Vorbis(VorbisComments),
+}
+
+impl Embedded {
+    fn read(path: &Utf8Path) -> Result<Self, FilepersonError> {
+        let parse_error = |source| FilepersonError::Metadata {
+            path: path.to_owned(),
+            source,
+        };
+        let file_error = |source| FilepersonError::File {
+            path: path.to_owned(),
+            source,
+        };
+        let file_type = Probe::open(path)
+            .map_err(parse_error)?
+            .guess_file_type()
+            .map_err(file_error)?
+            .file_type();
+        let mut file = File::open(path).map_err(file_error)?;
+        let options = ParseOptions::new().read_properties(false);
+        Ok(match file_type {
+            Some(FileType::Mpeg) => Embedded::Id3v2(
+                MpegFile::read_from(&mut file, options)
+                    .map_err(parse_error)?
+                    .id3v2()
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            Some(FileType::Wav) => Embedded::Id3v2(
+                WavFile::read_from(&mut file, options)
+                    .map_err(parse_error)?
+                    .id3v2()
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            Some(FileType::Aiff) => Embedded::Id3v2(
+                AiffFile::read_from(&mut file, options)
+                    .map_err(parse_error)?
+                    .id3v2()
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            Some(FileType::Flac) => Embedded::Vorbis(
+                FlacFile::read_from(&mut file, options)
+                    .map_err(parse_error)?
+                    .vorbis_comments()
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            Some(FileType::Vorbis) => Embedded::Vorbis(
+                VorbisFile::read_from(&mut file, options)
+                    .map_err(parse_error)?
+                    .vorbis_comments()
+                    .clone(),
+            ),
+            Some(FileType::Opus) => Embedded::Vorbis(
+                OpusFile::read_from(&mut file, options)
+                    .map_err(parse_error)?
+                    .vorbis_comments()
+                    .clone(),
+            ),
+            other => {
+                return Err(file_error(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("cannot embed tags in {:?} files", other),
+                )))
+            }
+        })
+    }
+
+    fn values(&self) -> Vec<String> {
+        match self {
+            Embedded::Id3v2(tag) => tag
+                .get_user_text(EMBEDDED_TAGS_KEY)
+                .map(|text| text.split('\0').map(str::to_string).collect())
+                .unwrap_or_default(),
+            Embedded::Vorbis(tag) => tag.get_all(EMBEDDED_TAGS_KEY).map(str::to_string).collect(),
+        }
+    }
+
+    fn set_values(&mut self, values: Vec<String>) {
+        match self {
+            Embedded::Id3v2(tag) if values.is_empty() => {
+                tag.remove_user_text(EMBEDDED_TAGS_KEY);
+            }
+            Embedded::Id3v2(tag) => {
+                tag.insert_user_text(EMBEDDED_TAGS_KEY.to_string(), values.join("\0"));
+            }
+            Embedded::Vorbis(tag) => {
+                tag.remove(EMBEDDED_TAGS_KEY).for_each(drop);
+                for value in values {
+                    tag.push(EMBEDDED_TAGS_KEY.to_string(), value);
+                }
+            }
+        }
+    }
+
+    fn save(&self, path: &Utf8Path) -> Result<(), FilepersonError> {
+        let options = WriteOptions::default();
+        match self {
+            Embedded::Id3v2(tag) => tag.save_to_path(path, options),
+            Embedded::Vorbis(tag) => tag.save_to_path(path, options),
+        }
+        .map_err(|source| FilepersonError::MetadataWrite {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// Make the tags embedded in `on_disk` exactly `tags`. Returns whether the file was written.
+fn embed_at(on_disk: &Utf8Path, tags: &[Tag]) -> Result<bool, FilepersonError> {
+    let mut embedded = Embedded::read(on_disk)?;
+    let values: Vec<String> = tags.iter().map(Tag::to_string).collect();
+    if embedded.values() == values {
+        return Ok(false);
+    }
+    embedded.set_values(values);
+    embedded.save(on_disk)?;
+    Ok(true)
+}
+
+impl FileInfo {
+    /// The tags embedded in the file by [`FileInfo::embed_tags`], or by another fileperson.
+    pub fn embedded_tags(&self) -> Result<Vec<Tag>, FilepersonError> {
+        let values = Embedded::read(&self.path)?.values();
+        Ok(values
+            .iter()
+            .map(|value| Tag::from(value.as_str()))
+            .collect())
+    }
+
+    /// Write the info's tags into the file's own metadata under [`EMBEDDED_TAGS_KEY`],
+    /// replacing what was embedded before; no tags removes the field. Other metadata is kept.
+    /// Returns `false` if the file already carried exactly these tags and was left alone.
+    pub fn embed_tags(&self) -> Result<bool, FilepersonError> {
+        embed_at(&self.path, &self.tags)
+    }
+}
+
+impl State {
+    /// [`FileInfo::embed_tags`] for every tracked file, on the rayon thread pool.
+    pub fn embed_tags(&self) -> EmbedReport {
+        let results: Vec<(Utf8PathBuf, Result<bool, FilepersonError>)> = self
+            .infos
+            .par_iter()
+            .map(|info| {
+                let on_disk = self.absolute_path(&info.path);
+                (info.path.clone(), embed_at(&on_disk, &info.tags))
+            })
+            .collect();
+        let mut report = EmbedReport::default();
+        for (path, result) in results {
+            match result {
+                Ok(true) => report.written.push(path),
+                Ok(false) => {}
+                Err(e) => report.failed.push((path, e)),
+            }
+        }
+        report.written.sort();
+        report.failed.sort_by(|a, b| a.0.cmp(&b.0));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::tests::fs_fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_embed_tags_round_trip() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["notes.wav"])?;
+        let beep = root.join("beep.wav");
+        std::fs::copy(
+            Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/beep.wav"),
+            &beep,
+        )?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(&beep, "warm")?;
+        state.add_tag(&beep, "Drum Loop")?;
+        // not actually audio
+        state.add_tag(root.join("notes.wav"), "text")?;
+
+        let report = state.embed_tags();
+        assert_eq!(report.written, vec![beep.clone()]);
+        assert_eq!(
+            report
+                .failed
+                .iter()
+                .map(|(p, _)| p.clone())
+                .collect::<Vec<_>>(),
+            vec![root.join("notes.wav")]
+        );
+        let info = state.get(&beep).unwrap();
+        assert_eq!(info.embedded_tags()?, info.tags().clone());
+        assert_eq!(
+            info.read_metadata()?.sample_rate,
+            Some(8000),
+            "the audio is still readable"
+        );
+
+        assert!(state.embed_tags().written.is_empty(), "nothing changed");
+
+        state.remove_tag(&beep, &"warm".into());
+        state.remove_tag(&beep, &"drum loop".into());
+        assert_eq!(state.embed_tags().written, vec![beep.clone()]);
+        assert!(state.get(&beep).unwrap().embedded_tags()?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! Error types of the public API.
+
+use std::path::{PathBuf, StripPrefixError};
+
+use camino::Utf8PathBuf;
+use thiserror::Error;
+
+use crate::TagViolation;
+
+/// A problem with a single entry encountered while walking a tree.
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error(transparent)]
+    Walk(#[from] walkdir::Error),
+    #[error(transparent)]
+    Strip(#[from] StripPrefixError),
+    #[error("path is not valid UTF-8: {0:?}")]
+    NonUtf8Path(PathBuf),
+}
+
+/// Everything that can go wrong in this crate.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FilepersonError {
+    #[error("cannot read root {path}")]
+    Root {
+        path: Utf8PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Load(#[from] LoadError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("{path}: {source}")]
+    File {
+        path: Utf8PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error("malformed CSV row on line {line}: {source}")]
+    MalformedCsvRow {
+        line: u64,
+        #[source]
+        source: csv::Error,
+    },
+    #[error(transparent)]
+    TomlDe(#[from] toml::de::Error),
+    #[error(transparent)]
+    TomlSer(#[from] toml::ser::Error),
+    #[error(transparent)]
+    Glob(#[from] globset::Error),
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("malformed state: {0}")]
+    MalformedState(String),
+    #[error("state format version {found} is newer than the supported version {supported}")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error("FileInfoBuilder needs a path")]
+    MissingPath,
+    #[error("tag {tag:?} rejected: {reason}")]
+    TagRejected {
+        tag: String,
+        #[source]
+        reason: TagViolation,
+    },
+    #[cfg(feature = "audio")]
+    #[error(transparent)]
+    Decode(#[from] rodio::decoder::DecoderError),
+    #[cfg(feature = "metadata")]
+    #[error("cannot read metadata of {path}: {source}")]
+    Metadata {
+        path: Utf8PathBuf,
+        #[source]
+        source: lofty::error::FileParseError,
+    },
+    #[cfg(feature = "metadata")]
+    #[error("cannot write metadata of {path}: {source}")]
+    MetadataWrite {
+        path: Utf8PathBuf,
+        #[source]
+        source: lofty::error::FileEncodingError,
+    },
+    #[cfg(feature = "finder")]
+    #[error("cannot read Finder tags of {path}: {source}")]
+    FinderTags {
+        path: Utf8PathBuf,
+        #[source]
+        source: plist::Error,
+    },
+    #[cfg(feature = "fingerprint")]
+    #[error("cannot fingerprint {path}: {source}")]
+    Fingerprint {
+        path: Utf8PathBuf,
+        #[source]
+        source: rusty_chromaprint::ResetError,
+    },
+    #[cfg(feature = "itunes")]
+    #[error("cannot read iTunes library: {0}")]
+    Itunes(#[source] plist::Error),
+    #[cfg(feature = "sqlite")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "watch")]
+    #[error(transparent)]
+    Watch(#[from] notify::Error),
+    #[cfg(feature = "watch")]
+    #[error("watcher shut down")]
+    WatchDisconnected,
+}
@@ -0,0 +1,270 @@
+//! Interchange formats for tag data, so tags can be edited or moved around outside of the JSON state.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::{FileInfo, FilepersonError, Op, State, Tag};
+
+/// Separator between tags inside the CSV `tags` column.
+const CSV_TAG_SEPARATOR: char = ';';
+
+#[derive(Serialize, Deserialize)]
+struct CsvRow {
+    path: Utf8PathBuf,
+    tags: String,
+    delete: Option<bool>,
+}
+
+impl CsvRow {
+    fn tags(&self) -> impl Iterator<Item = Tag> + '_ {
+        self.tags
+            .split(CSV_TAG_SEPARATOR)
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(Tag::from)
+    }
+}
+
+/// One file in the TOML form: a table keyed by the file's path.
+#[derive(Serialize, Deserialize)]
+struct TomlEntry {
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delete: Option<bool>,
+}
+
+impl State {
+    /// Write all tracked files as `path,tags,delete` rows (with a header), sorted by path.
+    /// Tags within a row are separated by `;`.
+    pub fn export_csv(&self, w: impl Write) -> Result<(), FilepersonError> {
+        let mut writer = csv::Writer::from_writer(w);
+        let mut infos: Vec<&FileInfo> = self.infos.iter().collect();
+        infos.sort_by(|a, b| a.path.cmp(&b.path));
+        for info in infos {
+            writer.serialize(CsvRow {
+                path: info.path.clone(),
+                tags: info
+                    .tags
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(&CSV_TAG_SEPARATOR.to_string()),
+                delete: info.delete,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Merge `path,tags,delete` rows (as written by [`State::export_csv`]) into the tracked files.
+    ///
+    /// Unknown paths get a fresh `FileInfo`, known ones have the row's tags unioned into their
+    /// existing tags. A non-empty `delete` column overrides the current flag. The merge is one
+    /// undoable edit, recorded in the [`OpLog`](crate::OpLog).
+    /// The whole input is parsed and its tags checked against the policy and vocabulary before
+    /// anything is merged, so a malformed row or a rejected tag leaves `self` untouched.
+    /// Returns the number of files that were created or changed.
+    pub fn import_csv(&mut self, r: impl Read) -> Result<usize, FilepersonError> {
+        let mut reader = csv::Reader::from_reader(r);
+        let mut rows = vec![];
+        for result in reader.deserialize::<CsvRow>() {
+            let row = result.map_err(|source| match source.position() {
+                Some(pos) => FilepersonError::MalformedCsvRow {
+                    line: pos.line(),
+                    source,
+                },
+                None => FilepersonError::Csv(source),
+            })?;
+            rows.push(row);
+        }
+        for tag in rows.iter().flat_map(CsvRow::tags) {
+            self.check_tag(&tag)?;
+        }
+
+        Ok(self.merge_rows(rows.into_iter().map(|row| {
+            let tags = row.tags().collect();
+            (row.path, tags, row.delete)
+        })))
+    }
+
+    /// All tracked files as TOML, one `["path"]` table with `tags` and `delete` per file.
+    pub fn export_toml(&self) -> Result<String, FilepersonError> {
+        let entries: BTreeMap<&Utf8PathBuf, TomlEntry> = self
+            .infos
+            .iter()
+            .map(|info| {
+                let entry = TomlEntry {
+                    tags: info.tags.iter().map(|t| t.to_string()).collect(),
+                    delete: info.delete,
+                };
+                (&info.path, entry)
+            })
+            .collect();
+        Ok(toml::to_string(&entries)?)
+    }
+
+    /// Track each `(path, tags, delete)` and merge its tags and delete flag in, as one undoable
+    /// edit. The tags must have passed [`State::check_tag`] already. Returns the number of files
+    /// that were created or changed.
+    fn merge_rows(
+        &mut self,
+        rows: impl Iterator<Item = (Utf8PathBuf, Vec<Tag>, Option<bool>)>,
+    ) -> usize {
+        let rows: Vec<_> = rows.collect();
+        let paths = rows.iter().map(|(path, ..)| path.clone()).collect();
+        self.track_edit(paths, |state| {
+            rows.into_iter()
+                .filter(|(path, tags, delete)| {
+                    let mut changed = state.record(Op::Track { path: path.clone() });
+                    for tag in tags {
+                        changed += state.record(Op::AddTag {
+                            path: path.clone(),
+                            tag: tag.clone(),
+                        });
+                    }
+                    if delete.is_some() {
+                        changed += state.record(Op::SetDelete {
+                            path: path.clone(),
+                            delete: *delete,
+                        });
+                    }
+                    changed > 0
+                })
+                .count()
+        })
+    }
+
+    /// Merge tables written by [`State::export_toml`] into the tracked files, with the same
+    /// rules as [`State::import_csv`]. Returns the number of files that were created or changed.
+    pub fn import_toml(&mut self, s: &str) -> Result<usize, FilepersonError> {
+        let entries: BTreeMap<Utf8PathBuf, TomlEntry> = toml::from_str(s)?;
+        for tag in entries.values().flat_map(|entry| &entry.tags) {
+            self.check_tag(&Tag::from(tag.as_str()))?;
+        }
+        Ok(self.merge_rows(entries.into_iter().map(|(path, entry)| {
+            let tags = entry
+                .tags
+                .iter()
+                .map(|tag| Tag::from(tag.as_str()))
+                .collect();
+            (path, tags, entry.delete)
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use crate::tests::{snapshot, state_fixture};
+
+    use super::*;
+
+    fn find<'a>(state: &'a State, path: &str) -> &'a FileInfo {
+        state
+            .infos
+            .iter()
+            .find(|f| f.path == Utf8Path::new(path))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_import_csv_creates_files() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        let csv = "path,tags,delete\n/music/a.wav,Rock;live,\n/music/b.wav,,true\n";
+        assert_eq!(state.import_csv(csv.as_bytes())?, 2);
+
+        let a = find(&state, "/music/a.wav");
+        assert_eq!(a.tags, vec![Tag::from("live"), Tag::from("rock")]);
+        assert_eq!(a.delete, None);
+        let b = find(&state, "/music/b.wav");
+        assert!(b.tags.is_empty());
+        assert_eq!(b.delete, Some(true));
+
+        let replayed = State::replay(state.root.clone(), state.op_log())?;
+        assert_eq!(snapshot(&replayed), snapshot(&state));
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_csv_merges_into_existing() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        let mut existing = FileInfo::from("/music/a.wav");
+        existing.add_tag("rock");
+        state.add(existing)?;
+        state.add(FileInfo::from("/music/b.wav"))?;
+
+        let csv = "path,tags,delete\n/music/a.wav,ROCK;jazz,\n/music/b.wav,,\n";
+        assert_eq!(state.import_csv(csv.as_bytes())?, 1);
+        assert_eq!(state.infos.len(), 2);
+        assert_eq!(
+            find(&state, "/music/a.wav").tags,
+            vec![Tag::from("jazz"), Tag::from("rock")]
+        );
+        assert!(state.undo());
+        assert_eq!(find(&state, "/music/a.wav").tags, vec![Tag::from("rock")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_toml_round_trip() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "Rock")?;
+        state.add_tag("/music/a.wav", "live")?;
+        state.set_delete("/music/b \"quoted\" [1].wav", Some(true));
+        state.add_tag("/music/ünïcode = x.wav", "jazz")?;
+        state.add(FileInfo::from("/music/untouched.wav"))?;
+
+        let toml = state.export_toml()?;
+        assert!(
+            toml.contains(r#"['/music/b "quoted" [1].wav']"#),
+            "{}",
+            toml
+        );
+
+        let mut imported = state_fixture();
+        assert_eq!(imported.import_toml(&toml)?, 4);
+        assert_eq!(snapshot(&imported), snapshot(&state));
+        let replayed = State::replay(imported.root.clone(), imported.op_log())?;
+        assert_eq!(snapshot(&replayed), snapshot(&imported));
+        assert!(imported.undo());
+        assert!(imported.files_with_tag(&"rock".into()).next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_csv_reports_line() {
+        let mut state = state_fixture();
+        let csv = "path,tags,delete\n/music/a.wav,rock,\n/music/b.wav,jazz,maybe\n";
+        let err = state.import_csv(csv.as_bytes()).unwrap_err();
+        assert!(
+            matches!(err, FilepersonError::MalformedCsvRow { line: 3, .. }),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("line 3"), "{}", err);
+        assert!(state.infos.is_empty());
+    }
+
+    #[test]
+    fn test_import_checks_vocabulary() {
+        let mut state = state_fixture().with_vocabulary(["rock".into()].into());
+        let csv = "path,tags,delete\n/music/a.wav,rock,\n/music/b.wav,kazoo,\n";
+        assert!(matches!(
+            state.import_csv(csv.as_bytes()),
+            Err(FilepersonError::TagRejected { .. })
+        ));
+        let toml = "['/music/a.wav']\ntags = ['rock', 'kazoo']\n";
+        assert!(matches!(
+            state.import_toml(toml),
+            Err(FilepersonError::TagRejected { .. })
+        ));
+        assert!(state.infos.is_empty());
+    }
+}
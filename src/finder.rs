@@ -0,0 +1,499 @@
+//! Two-way sync with the tags macOS Finder keeps in the `com.apple.metadata:_kMDItemUserTags`
+//! extended attribute, so tags applied in either place show up in the other.
+
+use std::{collections::HashSet, io::ErrorKind};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{Color, FilepersonError, State, Tag, TagCasing};
+
+/// Name of the extended attribute Finder keeps a file's tags in, as a binary plist array.
+pub const FINDER_TAGS_XATTR: &str = "com.apple.metadata:_kMDItemUserTags";
+
+/// The seven label colors Finder offers, numbered like it stores them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FinderColor {
+    Gray = 1,
+    Green = 2,
+    Purple = 3,
+    Blue = 4,
+    Yellow = 5,
+    Red = 6,
+    Orange = 7,
+}
+
+impl FinderColor {
+    const ALL: [FinderColor; 7] = [
+        FinderColor::Gray,
+        FinderColor::Green,
+        FinderColor::Purple,
+        FinderColor::Blue,
+        FinderColor::Yellow,
+        FinderColor::Red,
+        FinderColor::Orange,
+    ];
+
+    fn from_label(label: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| *c as u8 == label)
+    }
+
+    /// How Finder draws the label.
+    pub fn rgb(self) -> Color {
+        let (r, g, b) = match self {
+            FinderColor::Gray => (0x8e, 0x8e, 0x93),
+            FinderColor::Green => (0x34, 0xc7, 0x59),
+            FinderColor::Purple => (0xaf, 0x52, 0xde),
+            FinderColor::Blue => (0x00, 0x7a, 0xff),
+            FinderColor::Yellow => (0xff, 0xcc, 0x00),
+            FinderColor::Red => (0xff, 0x3b, 0x30),
+            FinderColor::Orange => (0xff, 0x95, 0x00),
+        };
+        Color { r, g, b }
+    }
+
+    /// The label closest to `color`.
+    pub fn nearest(color: Color) -> Self {
+        let distance = |c: &FinderColor| {
+            let rgb = c.rgb();
+            [(rgb.r, color.r), (rgb.g, color.g), (rgb.b, color.b)]
+                .iter()
+                .map(|&(a, b)| (i32::from(a) - i32::from(b)).pow(2))
+                .sum::<i32>()
+        };
+        *Self::ALL.iter().min_by_key(|c| distance(c)).unwrap()
+    }
+}
+
+/// One entry of a file's Finder tags: a name and optionally a label color.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FinderTag {
+    pub name: String,
+    pub color: Option<FinderColor>,
+}
+
+impl FinderTag {
+    /// Parse the stored form, the name optionally followed by a newline and the label number.
+    fn parse(entry: &str) -> Self {
+        match entry.rsplit_once('\n') {
+            Some((name, label)) => FinderTag {
+                name: name.to_string(),
+                color: label.parse().ok().and_then(FinderColor::from_label),
+            },
+            None => FinderTag {
+                name: entry.to_string(),
+                color: None,
+            },
+        }
+    }
+
+    fn format(&self) -> String {
+        match self.color {
+            Some(color) => format!("{}\n{}", self.name, color as u8),
+            None => self.name.clone(),
+        }
+    }
+
+    fn to_tag(&self) -> Tag {
+        let tag = Tag::from(self.name.as_str());
+        match self.color {
+            Some(color) => tag.with_color(color.rgb()),
+            None => tag,
+        }
+    }
+
+    fn from_tag(tag: &Tag) -> Self {
+        FinderTag {
+            name: tag.to_string(),
+            color: tag.color().map(FinderColor::nearest),
+        }
+    }
+}
+
+/// The Finder tags of the file at `path`. `None` if it has no attribute, or lives on a filesystem
+/// that can't carry one, which is different from an attribute listing no tags.
+pub fn read_finder_tags(path: &Utf8Path) -> Result<Option<Vec<FinderTag>>, FilepersonError> {
+    let value = match xattr::get(path, FINDER_TAGS_XATTR) {
+        Ok(Some(value)) => value,
+        Ok(None) => return Ok(None),
+        // filesystems without extended attributes can't carry Finder tags either
+        Err(e) if e.kind() == ErrorKind::Unsupported => return Ok(None),
+        Err(source) => {
+            return Err(FilepersonError::File {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    };
+    let entries: Vec<String> =
+        plist::from_bytes(&value).map_err(|source| FilepersonError::FinderTags {
+            path: path.to_owned(),
+            source,
+        })?;
+    Ok(Some(
+        entries
+            .iter()
+            .map(|entry| FinderTag::parse(entry))
+            .collect(),
+    ))
+}
+
+/// Replace the Finder tags of the file at `path`. No tags removes the attribute.
+pub fn write_finder_tags(path: &Utf8Path, tags: &[FinderTag]) -> Result<(), FilepersonError> {
+    let file_error = |source| FilepersonError::File {
+        path: path.to_owned(),
+        source,
+    };
+    if tags.is_empty() {
+        if xattr::get(path, FINDER_TAGS_XATTR)
+            .map_err(file_error)?
+            .is_some()
+        {
+            xattr::remove(path, FINDER_TAGS_XATTR).map_err(file_error)?;
+        }
+        return Ok(());
+    }
+    let entries: Vec<String> = tags.iter().map(FinderTag::format).collect();
+    let mut value = vec![];
+    plist::to_writer_binary(&mut value, &entries).map_err(|source| {
+        FilepersonError::FinderTags {
+            path: path.to_owned(),
+            source,
+        }
+    })?;
+    xattr::set(path, FINDER_TAGS_XATTR, &value).map_err(file_error)
+}
+
+/// Which side wins when fileperson and Finder disagree about a file's tags, see
+/// [`State::sync_finder_tags`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FinderConflict {
+    /// Give both sides the tags of either.
+    #[default]
+    Union,
+    /// Make Finder show what fileperson has.
+    Fileperson,
+    /// Make fileperson take what Finder shows.
+    Finder,
+}
+
+/// What one file's sync should change, if anything.
+#[derive(Debug, PartialEq, Eq)]
+struct Reconciled {
+    state: Option<Vec<Tag>>,
+    finder: Option<Vec<FinderTag>>,
+}
+
+/// Decide what to write where for a file. `tracked` is `None` for files fileperson doesn't track,
+/// which have no say against Finder. `finder` is `None` for files without the attribute, which
+/// have no say against fileperson: they are never imported from, only exported to.
+fn reconcile(
+    tracked: Option<&[Tag]>,
+    finder: Option<&[FinderTag]>,
+    policy: FinderConflict,
+    casing: TagCasing,
+) -> Reconciled {
+    let finder = match finder {
+        Some(finder) => finder,
+        None if policy == FinderConflict::Finder => {
+            return Reconciled {
+                state: None,
+                finder: None,
+            }
+        }
+        None => &[],
+    };
+    let from_finder: Vec<Tag> = finder.iter().map(FinderTag::to_tag).collect();
+    let contains = |tags: &[Tag], tag: &Tag| tags.iter().any(|t| casing.matches(t, tag));
+    let ours = match tracked {
+        Some(tags) => tags,
+        None if policy == FinderConflict::Fileperson || finder.is_empty() => {
+            return Reconciled {
+                state: None,
+                finder: None,
+            }
+        }
+        None => &[],
+    };
+    let agree = ours.iter().all(|t| contains(&from_finder, t))
+        && from_finder.iter().all(|t| contains(ours, t));
+    if agree {
+        return Reconciled {
+            state: None,
+            finder: None,
+        };
+    }
+    // Finder entries for `tags`, keeping the labels of entries Finder already has
+    let to_finder = |tags: &[Tag]| -> Vec<FinderTag> {
+        let mut entries: Vec<FinderTag> = finder
+            .iter()
+            .filter(|entry| contains(tags, &entry.to_tag()))
+            .cloned()
+            .collect();
+        entries.extend(
+            tags.iter()
+                .filter(|t| !contains(&from_finder, t))
+                .map(FinderTag::from_tag),
+        );
+        entries
+    };
+    match policy {
+        FinderConflict::Union => {
+            let mut union = ours.to_vec();
+            union.extend(from_finder.iter().filter(|t| !contains(ours, t)).cloned());
+            let state_changes = union.len() > ours.len();
+            let finder_changes = ours.iter().any(|t| !contains(&from_finder, t));
+            Reconciled {
+                finder: finder_changes.then(|| to_finder(&union)),
+                state: state_changes.then_some(union),
+            }
+        }
+        FinderConflict::Fileperson => Reconciled {
+            state: None,
+            finder: Some(to_finder(ours)),
+        },
+        FinderConflict::Finder => Reconciled {
+            state: Some(from_finder),
+            finder: None,
+        },
+    }
+}
+
+/// Outcome of [`State::sync_finder_tags`].
+#[derive(Debug, Default)]
+pub struct FinderSyncReport {
+    /// Files whose fileperson tags were changed to follow Finder.
+    pub imported: Vec<Utf8PathBuf>,
+    /// Files whose Finder tags were rewritten.
+    pub exported: Vec<Utf8PathBuf>,
+    pub failed: Vec<(Utf8PathBuf, FilepersonError)>,
+}
+
+impl FinderSyncReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl State {
+    /// Bring the tags of every tracked file and of every file in the tree in line with their
+    /// Finder tags, both ways. Where the two disagree, `policy` picks the outcome; files the
+    /// state doesn't track only ever import, and only if they have Finder tags. Files without the
+    /// Finder attribute, e.g. on filesystems other than APFS and HFS+, only ever export, so
+    /// [`FinderConflict::Finder`] leaves their tags alone.
+    ///
+    /// Tags are compared by name under the state's [`TagCasing`]. A tag crossing over brings its
+    /// color along, as the nearest Finder label or that label's color. Each imported file is one
+    /// undoable edit, and imported tags must pass the state's policy and vocabulary like any
+    /// other.
+    pub fn sync_finder_tags(&mut self, policy: FinderConflict) -> FinderSyncReport {
+        let mut paths: Vec<Utf8PathBuf> = self
+            .infos
+            .iter()
+            .map(|info| info.path.clone())
+            .chain(self.root.files().map(Utf8Path::to_owned))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        paths.sort();
+
+        let mut report = FinderSyncReport::default();
+        for path in paths {
+            let on_disk = self.absolute_path(&path);
+            let finder = match read_finder_tags(&on_disk) {
+                Ok(finder) => finder,
+                Err(e) => {
+                    report.failed.push((path, e));
+                    continue;
+                }
+            };
+            let tracked = self.infos.get(&path).map(|info| info.tags.clone());
+            let reconciled = reconcile(tracked.as_deref(), finder.as_deref(), policy, self.casing);
+            if let Some(tags) = reconciled.state {
+                match self.set_tags(&path, tags) {
+                    Ok(_) => report.imported.push(path.clone()),
+                    Err(e) => {
+                        report.failed.push((path, e));
+                        continue;
+                    }
+                }
+            }
+            if let Some(entries) = reconciled.finder {
+                match write_finder_tags(&on_disk, &entries) {
+                    Ok(()) => report.exported.push(path),
+                    Err(e) => report.failed.push((path, e)),
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finder(entries: &[&str]) -> Vec<FinderTag> {
+        entries
+            .iter()
+            .map(|entry| FinderTag::parse(entry))
+            .collect()
+    }
+
+    fn tags(values: &[&str]) -> Vec<Tag> {
+        values.iter().map(|v| Tag::from(*v)).collect()
+    }
+
+    fn names(tags: &[Tag]) -> Vec<String> {
+        tags.iter().map(Tag::to_string).collect()
+    }
+
+    #[test]
+    fn test_finder_tag_format() {
+        let red = FinderTag::parse("Red\n6");
+        assert_eq!(red.color, Some(FinderColor::Red));
+        assert_eq!(red.format(), "Red\n6");
+        assert_eq!(red.to_tag().color(), Some(FinderColor::Red.rgb()));
+        let plain = FinderTag::parse("drums");
+        assert_eq!(plain.color, None);
+        assert_eq!(plain.format(), "drums");
+        assert_eq!(FinderTag::parse("odd\n9").color, None);
+
+        let teal = Tag::from("keep").with_color("#1080f0".parse().unwrap());
+        assert_eq!(FinderTag::from_tag(&teal).color, Some(FinderColor::Blue));
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let casing = TagCasing::Insensitive;
+        let ours = tags(&["drums", "Live"]);
+        let theirs = finder(&["live", "Red\n6"]);
+
+        let union = reconcile(Some(&ours), Some(&theirs), FinderConflict::Union, casing);
+        assert_eq!(names(&union.state.unwrap()), ["drums", "Live", "Red"]);
+        assert_eq!(
+            union.finder.unwrap(),
+            finder(&["live", "Red\n6", "drums"]),
+            "Finder's entries keep their place and label"
+        );
+
+        let ours_wins = reconcile(
+            Some(&ours),
+            Some(&theirs),
+            FinderConflict::Fileperson,
+            casing,
+        );
+        assert_eq!(ours_wins.state, None);
+        assert_eq!(ours_wins.finder.unwrap(), finder(&["live", "drums"]));
+
+        let theirs_win = reconcile(Some(&ours), Some(&theirs), FinderConflict::Finder, casing);
+        assert_eq!(names(&theirs_win.state.unwrap()), ["live", "Red"]);
+        assert_eq!(theirs_win.finder, None);
+
+        // only one side needs to change when the other already has everything
+        let subset = reconcile(
+            Some(&ours[..1]),
+            Some(&theirs),
+            FinderConflict::Union,
+            casing,
+        );
+        assert_eq!(names(&subset.state.unwrap()), ["drums", "live", "Red"]);
+        let superset = reconcile(
+            Some(&tags(&["live", "red", "drums"])),
+            Some(&theirs),
+            FinderConflict::Union,
+            casing,
+        );
+        assert_eq!(superset.state, None);
+        assert!(superset.finder.is_some());
+
+        let agreed = reconcile(
+            Some(&tags(&["LIVE", "red"])),
+            Some(&theirs),
+            FinderConflict::Fileperson,
+            casing,
+        );
+        assert_eq!(agreed.state, None);
+        assert_eq!(agreed.finder, None);
+        let differs_in_case = reconcile(
+            Some(&tags(&["LIVE", "red"])),
+            Some(&theirs),
+            FinderConflict::Fileperson,
+            TagCasing::Sensitive,
+        );
+        assert!(differs_in_case.finder.is_some());
+
+        // untracked files only import
+        let untracked = reconcile(None, Some(&theirs), FinderConflict::Union, casing);
+        assert_eq!(names(&untracked.state.unwrap()), ["live", "Red"]);
+        assert_eq!(untracked.finder, None);
+        let ignored = reconcile(None, Some(&theirs), FinderConflict::Fileperson, casing);
+        assert_eq!(ignored.state, None);
+        assert_eq!(ignored.finder, None);
+        assert_eq!(
+            reconcile(None, Some(&[]), FinderConflict::Union, casing).state,
+            None
+        );
+
+        // no attribute is not the same as an empty one
+        let cleared = reconcile(Some(&ours), Some(&[]), FinderConflict::Finder, casing);
+        assert_eq!(cleared.state, Some(vec![]));
+        let absent = reconcile(Some(&ours), None, FinderConflict::Finder, casing);
+        assert_eq!(absent.state, None);
+        assert_eq!(absent.finder, None);
+        let exported = reconcile(Some(&ours), None, FinderConflict::Union, casing);
+        assert_eq!(exported.state, None);
+        assert_eq!(exported.finder.unwrap(), finder(&["drums", "Live"]));
+        assert_eq!(
+            reconcile(None, None, FinderConflict::Union, casing),
+            Reconciled {
+                state: None,
+                finder: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_sync_finder_tags_without_attribute() -> anyhow::Result<()> {
+        use std::collections::HashSet;
+
+        // a fresh file has no Finder attribute anywhere, and off macOS can't get one
+        let (_dir, root) = crate::tests::fs_fixture(&["a.wav"])?;
+        let a = root.join("a.wav");
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(&a, "rock")?;
+
+        let report = state.sync_finder_tags(FinderConflict::Finder);
+        assert!(report.is_success());
+        assert!(report.imported.is_empty());
+        assert_eq!(state.get(&a).unwrap().tags(), &vec![Tag::from("rock")]);
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_sync_finder_tags() -> anyhow::Result<()> {
+        use std::collections::HashSet;
+
+        let (_dir, root) = crate::tests::fs_fixture(&["a.wav", "b.wav"])?;
+        let (a, b) = (root.join("a.wav"), root.join("b.wav"));
+        write_finder_tags(&b, &finder(&["Green\n2"]))?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(&a, "drums")?;
+
+        let report = state.sync_finder_tags(FinderConflict::Union);
+        assert!(report.is_success());
+        assert_eq!(report.exported, vec![a.clone()]);
+        assert_eq!(report.imported, vec![b.clone()]);
+        assert_eq!(read_finder_tags(&a)?, Some(finder(&["drums"])));
+        assert_eq!(
+            state.get(&b).unwrap().tags()[0].color(),
+            Some(FinderColor::Green.rgb())
+        );
+
+        state.remove_tag(&a, &"drums".into());
+        let report = state.sync_finder_tags(FinderConflict::Fileperson);
+        assert_eq!(report.exported, vec![a.clone()]);
+        assert_eq!(read_finder_tags(&a)?, None);
+        Ok(())
+    }
+}
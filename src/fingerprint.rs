@@ -0,0 +1,216 @@
+//! Acoustic fingerprints (Chromaprint's algorithm), to find files that sound the same without
+//! being byte-identical: renders at different bitrates or sample rates, or with trimmed silence.
+
+use std::{collections::BTreeSet, fs::File, io::BufReader};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use rayon::prelude::*;
+use rodio::Source;
+use rusty_chromaprint::{match_fingerprints, Configuration, Fingerprinter};
+
+use crate::{FileInfo, FilepersonError, State};
+
+/// The default similarity above which [`State::find_near_duplicates`] groups two files.
+pub const NEAR_DUPLICATE_THRESHOLD: f32 = 0.8;
+
+fn config() -> Configuration {
+    Configuration::preset_test2()
+}
+
+/// How a file's audio sounds, condensed, see [`FileInfo::fingerprint`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint(Vec<u32>);
+
+impl Fingerprint {
+    /// How much of the shorter of the two sounds like a part of the other, from 0 to 1. Whatever
+    /// the longer one has in addition, like leading silence or an extra bar, doesn't count.
+    pub fn similarity(&self, other: &Fingerprint) -> f32 {
+        let shorter = self.0.len().min(other.0.len());
+        if shorter == 0 {
+            return 0.0;
+        }
+        // only fails for fingerprints of many hours
+        let matched: usize = match_fingerprints(&self.0, &other.0, &config())
+            .map(|segments| segments.iter().map(|s| s.items_count).sum())
+            .unwrap_or_default();
+        matched.min(shorter) as f32 / shorter as f32
+    }
+
+    /// Whether the audio was too short or too quiet to fingerprint.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn fingerprint(path: &Utf8Path) -> Result<Fingerprint, FilepersonError> {
+    let mut decoder = rodio::Decoder::new(BufReader::new(File::open(path)?))?;
+    let mut printer = Fingerprinter::new(&config());
+    printer
+        .start(decoder.sample_rate(), decoder.channels().into())
+        .map_err(|source| FilepersonError::Fingerprint {
+            path: path.to_owned(),
+            source,
+        })?;
+    let mut chunk = Vec::with_capacity(4096);
+    loop {
+        chunk.clear();
+        chunk.extend(decoder.by_ref().take(4096));
+        if chunk.is_empty() {
+            break;
+        }
+        printer.consume(&chunk);
+    }
+    printer.finish();
+    Ok(Fingerprint(printer.fingerprint().to_vec()))
+}
+
+impl FileInfo {
+    /// Decode the file and fingerprint its audio.
+    pub fn fingerprint(&self) -> Result<Fingerprint, FilepersonError> {
+        fingerprint(&self.path)
+    }
+}
+
+impl State {
+    /// Groups of files in the tree and tracked ones that sound alike, i.e. that are connected
+    /// through pairs with a [`Fingerprint::similarity`] of at least `threshold`, e.g.
+    /// [`NEAR_DUPLICATE_THRESHOLD`]. Groups of two or more only; the paths within a group and the
+    /// groups themselves are sorted, like [`State::find_duplicates`] (`blake3` feature) returns
+    /// them.
+    ///
+    /// Every file is decoded; those that can't be, e.g. because they aren't audio, are left out.
+    /// Comparing is quadratic in the number of files, in parallel.
+    pub fn find_near_duplicates(&self, threshold: f32) -> Vec<Vec<Utf8PathBuf>> {
+        let paths: BTreeSet<Utf8PathBuf> = self
+            .infos
+            .iter()
+            .map(|info| info.path.clone())
+            .chain(self.root.files().map(Utf8Path::to_owned))
+            .collect();
+        let prints: Vec<(Utf8PathBuf, Fingerprint)> = paths
+            .into_par_iter()
+            .filter_map(|path| match fingerprint(&self.absolute_path(&path)) {
+                Ok(print) if !print.is_empty() => Some((path, print)),
+                Ok(_) => None,
+                Err(e) => {
+                    log::debug!("not fingerprinting {}: {}", path, e);
+                    None
+                }
+            })
+            .collect();
+
+        let pairs: Vec<(usize, usize)> = (0..prints.len())
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let prints = &prints;
+                (i + 1..prints.len())
+                    .filter(move |&j| prints[i].1.similarity(&prints[j].1) >= threshold)
+                    .map(move |j| (i, j))
+            })
+            .collect();
+
+        // union-find over the similar pairs
+        let mut parent: Vec<usize> = (0..prints.len()).collect();
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for (i, j) in pairs {
+            let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+            parent[a.max(b)] = a.min(b);
+        }
+        let mut groups: Vec<Vec<Utf8PathBuf>> = vec![vec![]; prints.len()];
+        for (i, (path, _)) in prints.into_iter().enumerate() {
+            groups[root(&mut parent, i)].push(path);
+        }
+        // paths were sorted going in, and each group collects its members in order
+        let mut groups: Vec<Vec<Utf8PathBuf>> = groups
+            .into_iter()
+            .filter(|group| group.len() >= 2)
+            .collect();
+        groups.sort();
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use crate::tests::fs_fixture;
+
+    use super::*;
+
+    /// A 16-bit mono WAV of `notes` (MIDI numbers), a quarter second each, after `silence`
+    /// seconds of silence. `bits` below 16 coarsen the samples like a cheap encode would.
+    fn melody(
+        path: &Utf8Path,
+        notes: &[u8],
+        sample_rate: u32,
+        silence: f32,
+        bits: u32,
+    ) -> anyhow::Result<()> {
+        let note_len = sample_rate as usize / 4;
+        let lead = (silence * sample_rate as f32) as usize;
+        let mut samples = vec![0i16; lead];
+        for (n, &note) in notes.iter().enumerate() {
+            let freq = 440.0 * 2f32.powf((f32::from(note) - 69.0) / 12.0);
+            samples.extend((0..note_len).map(|i| {
+                let t = (n * note_len + i) as f32 / sample_rate as f32;
+                let s = (TAU * freq * t).sin() * 0.3 + (TAU * 2.0 * freq * t).sin() * 0.1;
+                let s = (s * f32::from(i16::MAX)) as i16;
+                s >> (16 - bits) << (16 - bits)
+            }));
+        }
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes()); // PCM
+        wav.extend(1u16.to_le_bytes()); // mono
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend((sample_rate * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        std::fs::write(path, wav)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_near_duplicates() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["notes.txt"])?;
+        let tune: Vec<u8> = (0..32).map(|i| 57 + (i * 7 % 12) as u8).collect();
+        let other: Vec<u8> = (0..32).map(|i| 50 + (i * 5 % 17) as u8).collect();
+        melody(&root.join("render.wav"), &tune, 22050, 0.0, 16)?;
+        melody(&root.join("bounce.wav"), &tune, 44100, 1.5, 10)?;
+        melody(&root.join("trimmed.wav"), &tune[8..], 22050, 0.0, 16)?;
+        melody(&root.join("other.wav"), &other, 22050, 0.0, 16)?;
+
+        let print = |name: &str| FileInfo::from(root.join(name)).fingerprint();
+        let render = print("render.wav")?;
+        assert!(!render.is_empty());
+        assert!(render.similarity(&print("bounce.wav")?) >= NEAR_DUPLICATE_THRESHOLD);
+        assert!(render.similarity(&print("other.wav")?) < 0.5);
+        assert!(FileInfo::from(root.join("notes.txt"))
+            .fingerprint()
+            .is_err());
+
+        let state = State::new(&root, ["wav", "txt"].into())?;
+        assert_eq!(
+            state.find_near_duplicates(NEAR_DUPLICATE_THRESHOLD),
+            vec![vec![
+                root.join("bounce.wav"),
+                root.join("render.wav"),
+                root.join("trimmed.wav")
+            ]]
+        );
+        Ok(())
+    }
+}
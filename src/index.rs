@@ -0,0 +1,255 @@
+//! The tracked files of a [`State`](crate::State), with an inverted index from tag to files kept
+//! in step with every change so tag lookups and listings don't scan the whole library.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    iter::FromIterator,
+    sync::Arc,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{persist, FileInfo, Tag, TagCasing};
+
+/// A tag's folded value and exact spelling. Ordering by this is [`TagCasing::Sensitive`] order,
+/// so all spellings of a tag are adjacent and a namespace's tags follow it.
+type Spelling = (Arc<str>, Arc<str>);
+
+/// The files carrying one tag spelling.
+#[derive(Debug)]
+struct Posting {
+    /// The first file's copy of the tag, handed out by [`Infos::tags`].
+    tag: Tag,
+    paths: HashSet<Utf8PathBuf>,
+}
+
+/// A set of [`FileInfo`]s, one per path. Files can't be changed in place; take them out and put
+/// them back so the index sees the change.
+#[derive(Debug, Default)]
+pub(crate) struct Infos {
+    files: HashSet<FileInfo>,
+    by_tag: BTreeMap<Spelling, Posting>,
+}
+
+fn spelling(tag: &Tag) -> Spelling {
+    (Arc::clone(&tag.folded), Arc::clone(&tag.value))
+}
+
+impl Infos {
+    pub(crate) fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> std::collections::hash_set::Iter<'_, FileInfo> {
+        self.files.iter()
+    }
+
+    pub(crate) fn par_iter(&self) -> rayon::collections::hash_set::Iter<'_, FileInfo> {
+        self.files.par_iter()
+    }
+
+    pub(crate) fn get(&self, path: &Utf8Path) -> Option<&FileInfo> {
+        self.files.get(path)
+    }
+
+    /// Add `info` unless its path is already tracked. Returns whether it was added.
+    pub(crate) fn insert(&mut self, info: FileInfo) -> bool {
+        if self.files.contains(info.path.as_path()) {
+            return false;
+        }
+        self.index(&info);
+        self.files.insert(info)
+    }
+
+    /// Add `info`, returning the info it replaces.
+    pub(crate) fn replace(&mut self, info: FileInfo) -> Option<FileInfo> {
+        let old = self.take(&info.path);
+        self.insert(info);
+        old
+    }
+
+    pub(crate) fn take(&mut self, path: &Utf8Path) -> Option<FileInfo> {
+        let info = self.files.take(path)?;
+        for tag in &info.tags {
+            let key = spelling(tag);
+            if let Some(posting) = self.by_tag.get_mut(&key) {
+                posting.paths.remove(&info.path);
+                if posting.paths.is_empty() {
+                    self.by_tag.remove(&key);
+                }
+            }
+        }
+        Some(info)
+    }
+
+    pub(crate) fn remove(&mut self, path: &Utf8Path) -> bool {
+        self.take(path).is_some()
+    }
+
+    #[cfg(any(feature = "watch", test))]
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&FileInfo) -> bool) {
+        let dropped: Vec<Utf8PathBuf> = self
+            .files
+            .iter()
+            .filter(|info| !keep(info))
+            .map(|info| info.path.clone())
+            .collect();
+        for path in dropped {
+            self.take(&path);
+        }
+    }
+
+    fn index(&mut self, info: &FileInfo) {
+        for tag in &info.tags {
+            self.by_tag
+                .entry(spelling(tag))
+                .or_insert_with(|| Posting {
+                    tag: tag.clone(),
+                    paths: HashSet::new(),
+                })
+                .paths
+                .insert(info.path.clone());
+        }
+    }
+
+    /// One tag per spelling in use, ordered like [`TagCasing::Sensitive`] compares them.
+    pub(crate) fn tags(&self) -> impl Iterator<Item = &Tag> {
+        self.by_tag.values().map(|posting| &posting.tag)
+    }
+
+    /// The paths of the files carrying a tag `tag` covers under `casing`, sorted. Only looks at
+    /// the spellings that can match.
+    pub(crate) fn paths_covered_by(&self, tag: &Tag, casing: TagCasing) -> Vec<&Utf8Path> {
+        let start: Spelling = (Arc::clone(&tag.folded), Arc::from(""));
+        let candidates = self.by_tag.range(start..).take_while(|((folded, _), _)| {
+            if tag.is_namespace() {
+                folded.starts_with(&*tag.folded)
+            } else {
+                **folded == *tag.folded
+            }
+        });
+        let paths: BTreeSet<&Utf8Path> = candidates
+            .filter(|(_, posting)| casing.covers(tag, &posting.tag))
+            .flat_map(|(_, posting)| posting.paths.iter().map(Utf8PathBuf::as_path))
+            .collect();
+        paths.into_iter().collect()
+    }
+}
+
+impl FromIterator<FileInfo> for Infos {
+    fn from_iter<T: IntoIterator<Item = FileInfo>>(iter: T) -> Self {
+        let mut infos = Infos::default();
+        infos.extend(iter);
+        infos
+    }
+}
+
+/// Like a set, later infos for an already tracked path are ignored.
+impl Extend<FileInfo> for Infos {
+    fn extend<T: IntoIterator<Item = FileInfo>>(&mut self, iter: T) {
+        for info in iter {
+            self.insert(info);
+        }
+    }
+}
+
+impl IntoIterator for Infos {
+    type Item = FileInfo;
+    type IntoIter = std::collections::hash_set::IntoIter<FileInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Infos {
+    type Item = &'a FileInfo;
+    type IntoIter = std::collections::hash_set::Iter<'a, FileInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.iter()
+    }
+}
+
+impl Serialize for Infos {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        persist::sorted_infos(&self.files, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Infos {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<FileInfo>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(path: &str, tags: &[&str]) -> FileInfo {
+        let mut info = FileInfo::from(path);
+        for tag in tags {
+            info.add_tag(*tag);
+        }
+        info
+    }
+
+    fn covered<'a>(infos: &'a Infos, tag: &str, casing: TagCasing) -> Vec<&'a str> {
+        infos
+            .paths_covered_by(&tag.into(), casing)
+            .into_iter()
+            .map(Utf8Path::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn test_index_follows_changes() {
+        let mut infos: Infos = vec![
+            info("/a.wav", &["rock", "genre:techno"]),
+            info("/b.wav", &["Rock"]),
+            info("/c.wav", &["genre:house", "genres"]),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            covered(&infos, "ROCK", TagCasing::Insensitive),
+            ["/a.wav", "/b.wav"]
+        );
+        assert_eq!(covered(&infos, "Rock", TagCasing::Sensitive), ["/b.wav"]);
+        assert_eq!(
+            covered(&infos, "genre:", TagCasing::Insensitive),
+            ["/a.wav", "/c.wav"]
+        );
+        assert_eq!(
+            covered(&infos, "genre", TagCasing::Insensitive),
+            Vec::<&str>::new()
+        );
+
+        let mut a = infos.take("/a.wav".into()).unwrap();
+        assert_eq!(covered(&infos, "rock", TagCasing::Insensitive), ["/b.wav"]);
+        a.remove_tag(&"genre:techno".into());
+        infos.insert(a);
+        assert_eq!(
+            covered(&infos, "genre:", TagCasing::Insensitive),
+            ["/c.wav"]
+        );
+
+        infos.retain(|info| info.path != "/c.wav");
+        let tags: Vec<String> = infos.tags().map(Tag::to_string).collect();
+        assert_eq!(tags, ["Rock", "rock"]);
+        assert!(!infos.insert(info("/b.wav", &["jazz"])));
+        assert!(infos.replace(info("/b.wav", &["jazz"])).is_some());
+        assert_eq!(covered(&infos, "rock", TagCasing::Insensitive), ["/a.wav"]);
+        assert_eq!(covered(&infos, "jazz", TagCasing::Insensitive), ["/b.wav"]);
+    }
+}
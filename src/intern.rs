@@ -0,0 +1,77 @@
+//! Sharing one allocation between all tags of the same spelling.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{FileInfo, State, Tag};
+
+/// The distinct tag spellings seen by a [`State`], with their case folding. Spellings are kept
+/// exactly, so "Rock" and "rock" are separate entries; tag comparison stays caseless regardless.
+///
+/// `Arc` rather than `Rc` because infos are shared across rayon threads.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TagInterner {
+    values: HashMap<Arc<str>, Arc<str>>,
+}
+
+impl TagInterner {
+    pub(crate) fn intern(&mut self, mut tag: Tag) -> Tag {
+        match self.values.get_key_value(&tag.value) {
+            Some((value, folded)) => {
+                tag.value = Arc::clone(value);
+                tag.folded = Arc::clone(folded);
+            }
+            None => {
+                self.values
+                    .insert(Arc::clone(&tag.value), Arc::clone(&tag.folded));
+            }
+        }
+        tag
+    }
+
+    pub(crate) fn intern_info(&mut self, info: &mut FileInfo) {
+        let tags = std::mem::take(&mut info.tags);
+        info.tags = tags.into_iter().map(|tag| self.intern(tag)).collect();
+    }
+}
+
+impl State {
+    /// `tag` with its value shared with every other tag of the same spelling in this state.
+    /// Tags are interned when they enter the state, so this is only needed to avoid holding on
+    /// to duplicates outside of it.
+    pub fn intern(&mut self, tag: impl Into<Tag>) -> Tag {
+        self.interner.intern(tag.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::state_fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_tags_share_allocation() {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.add_tag("/music/b.wav", "rock").unwrap();
+        state.add_tag("/music/c.wav", "Rock").unwrap();
+
+        let value_of = |path: &str| {
+            let info = state.infos.iter().find(|f| f.path == path).unwrap();
+            Arc::clone(&info.tags[0].value)
+        };
+        assert!(Arc::ptr_eq(
+            &value_of("/music/a.wav"),
+            &value_of("/music/b.wav")
+        ));
+        assert!(!Arc::ptr_eq(
+            &value_of("/music/a.wav"),
+            &value_of("/music/c.wav")
+        ));
+        assert_eq!(
+            state.files_with_tag(&"ROCK".into()).count(),
+            3,
+            "matching stays caseless"
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Importing an iTunes / Music.app library export (`Library.xml`), so ratings, genres and
+//! playlists curated there become tags instead of starting from zero.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::Read,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+use crate::{ops::Op, FilepersonError, State, Tag};
+
+#[derive(Deserialize)]
+struct Library {
+    #[serde(rename = "Tracks", default)]
+    tracks: BTreeMap<String, Track>,
+    #[serde(rename = "Playlists", default)]
+    playlists: Vec<Playlist>,
+}
+
+#[derive(Deserialize)]
+struct Track {
+    #[serde(rename = "Location")]
+    location: Option<String>,
+    #[serde(rename = "Genre")]
+    genre: Option<String>,
+    /// 0 to 100, 20 per star.
+    #[serde(rename = "Rating")]
+    rating: Option<u64>,
+    /// Set when the rating is inherited from the album rather than given to the track.
+    #[serde(rename = "Rating Computed", default)]
+    rating_computed: bool,
+}
+
+#[derive(Deserialize)]
+struct Playlist {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Playlist Persistent ID")]
+    id: Option<String>,
+    #[serde(rename = "Parent Persistent ID")]
+    parent: Option<String>,
+    /// The whole library.
+    #[serde(rename = "Master", default)]
+    master: bool,
+    /// Built-in lists like Music or Podcasts.
+    #[serde(rename = "Distinguished Kind")]
+    distinguished_kind: Option<i64>,
+    #[serde(rename = "Playlist Items", default)]
+    items: Vec<PlaylistItem>,
+}
+
+#[derive(Deserialize)]
+struct PlaylistItem {
+    #[serde(rename = "Track ID")]
+    track_id: u64,
+}
+
+/// The outcome of [`State::import_itunes`].
+#[derive(Debug, Default)]
+pub struct ItunesReport {
+    /// Tracks that were mapped onto a file.
+    pub matched: usize,
+    /// Files that got at least one new tag.
+    pub tagged: usize,
+    /// Locations of tracks that match no file, or several equally well.
+    pub unmatched: Vec<String>,
+    /// Tags the state's policy or vocabulary rejected, with the file they were meant for.
+    pub rejected: Vec<(Utf8PathBuf, FilepersonError)>,
+}
+
+/// The path of a `file://` location, percent-decoded. `None` for other URLs.
+fn location_path(location: &str) -> Option<Utf8PathBuf> {
+    let rest = location.strip_prefix("file://")?;
+    let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+    let mut bytes = vec![];
+    let mut raw = rest.bytes();
+    while let Some(b) = raw.next() {
+        if b == b'%' {
+            let hex = [raw.next()?, raw.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    // Windows libraries write file://localhost/C:/...
+    let path = match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].to_string(),
+        _ => path,
+    };
+    Some(path.into())
+}
+
+/// The file in `by_name` that shares the longest run of trailing components with `wanted`, so a
+/// library exported on another machine or mount still finds its files. `None` if there's a tie.
+fn best_match<'a>(
+    wanted: &Utf8Path,
+    by_name: &HashMap<&str, Vec<&'a Utf8Path>>,
+) -> Option<&'a Utf8Path> {
+    let candidates = by_name.get(wanted.file_name()?)?;
+    let shared = |path: &Utf8Path| {
+        path.components()
+            .rev()
+            .zip(wanted.components().rev())
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+    let best = candidates.iter().map(|path| shared(path)).max()?;
+    let mut winners = candidates.iter().filter(|path| shared(path) == best);
+    match (winners.next(), winners.next()) {
+        (Some(path), None) => Some(path),
+        _ => None,
+    }
+}
+
+/// Playlist names with their folders, like `Gigs/Berlin`, by persistent ID.
+fn playlist_names(playlists: &[Playlist]) -> Vec<String> {
+    let by_id: HashMap<&str, &Playlist> = playlists
+        .iter()
+        .filter_map(|p| p.id.as_deref().map(|id| (id, p)))
+        .collect();
+    playlists
+        .iter()
+        .map(|playlist| {
+            let mut name = playlist.name.clone();
+            let mut parent = playlist.parent.as_deref();
+            // bounded, in case of a cycle
+            for _ in 0..playlists.len() {
+                match parent.and_then(|id| by_id.get(id)) {
+                    Some(folder) => {
+                        name = format!("{}/{}", folder.name, name);
+                        parent = folder.parent.as_deref();
+                    }
+                    None => break,
+                }
+            }
+            name
+        })
+        .collect()
+}
+
+impl State {
+    /// Read the iTunes library export at `path`, see [`State::import_itunes_from`].
+    pub fn import_itunes(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+    ) -> Result<ItunesReport, FilepersonError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|source| FilepersonError::File {
+            path: path.to_owned(),
+            source,
+        })?;
+        self.import_itunes_from(file)
+    }
+
+    /// Tag the files of an iTunes library export with what the library knows about them:
+    /// `rating:1` to `rating:5` for rated tracks, `genre:<genre>`, and `playlist:<name>` for every
+    /// playlist a track is in, with folders as in `playlist:Gigs/Berlin`. Tags are added; the
+    /// files keep the ones they have. The whole import is one undoable edit.
+    ///
+    /// Tracks are mapped onto the files in the tree and the tracked ones by path, or, for a
+    /// library from another machine, by as much of the path's tail as possible. The whole
+    /// library is parsed before anything changes.
+    pub fn import_itunes_from(&mut self, r: impl Read) -> Result<ItunesReport, FilepersonError> {
+        let library: Library = plist::from_reader_xml(r).map_err(FilepersonError::Itunes)?;
+
+        let files: Vec<Utf8PathBuf> = self
+            .root
+            .files()
+            .map(Utf8Path::to_owned)
+            .chain(self.infos.iter().map(|info| info.path.clone()))
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let mut by_name: HashMap<&str, Vec<&Utf8Path>> = HashMap::new();
+        for file in &files {
+            if let Some(name) = file.file_name() {
+                by_name.entry(name).or_default().push(file);
+            }
+        }
+
+        let mut report = ItunesReport::default();
+        let mut tags: BTreeMap<Utf8PathBuf, Vec<Tag>> = BTreeMap::new();
+        let mut track_paths: HashMap<&str, Utf8PathBuf> = HashMap::new();
+        for (id, track) in &library.tracks {
+            // streamed and cloud-only tracks have no file
+            let location = match &track.location {
+                Some(location) => location,
+                None => continue,
+            };
+            let path = match location_path(location).and_then(|p| best_match(&p, &by_name)) {
+                Some(path) => path.to_owned(),
+                None => {
+                    report.unmatched.push(location.clone());
+                    continue;
+                }
+            };
+            report.matched += 1;
+            let track_tags = tags.entry(path.clone()).or_default();
+            match track.rating {
+                Some(rating) if rating >= 10 && !track.rating_computed => {
+                    track_tags.push(Tag::from(format!("rating:{}", (rating + 10) / 20).as_str()));
+                }
+                _ => {}
+            }
+            if let Some(genre) = track.genre.as_deref().filter(|g| !g.trim().is_empty()) {
+                track_tags.push(Tag::from(format!("genre:{}", genre).as_str()));
+            }
+            track_paths.insert(id, path);
+        }
+        let names = playlist_names(&library.playlists);
+        for (playlist, name) in library.playlists.iter().zip(names) {
+            if playlist.master || playlist.distinguished_kind.is_some() {
+                continue;
+            }
+            let tag = Tag::from(format!("playlist:{}", name).as_str());
+            for item in &playlist.items {
+                if let Some(path) = track_paths.get(item.track_id.to_string().as_str()) {
+                    tags.entry(path.clone()).or_default().push(tag.clone());
+                }
+            }
+        }
+
+        let mut accepted: Vec<(Utf8PathBuf, Tag)> = vec![];
+        for (path, file_tags) in tags {
+            for tag in file_tags {
+                match self.check_tag(&tag) {
+                    Ok(()) => accepted.push((path.clone(), tag)),
+                    Err(e) => report.rejected.push((path.clone(), e)),
+                }
+            }
+        }
+        let mut paths: Vec<Utf8PathBuf> = accepted.iter().map(|(path, _)| path.clone()).collect();
+        paths.dedup();
+        report.tagged = self.track_edit(paths, |state| {
+            let mut tagged: Vec<Utf8PathBuf> = accepted
+                .into_iter()
+                .filter_map(|(path, tag)| {
+                    let op = Op::AddTag {
+                        path: path.clone(),
+                        tag,
+                    };
+                    (state.record(op) > 0).then_some(path)
+                })
+                .collect();
+            tagged.dedup();
+            tagged.len()
+        });
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::fs_fixture;
+
+    use super::*;
+
+    const LIBRARY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Major Version</key><integer>1</integer>
+	<key>Music Folder</key><string>file:///Users/dj/Music/iTunes/iTunes%20Media/</string>
+	<key>Tracks</key>
+	<dict>
+		<key>101</key>
+		<dict>
+			<key>Track ID</key><integer>101</integer>
+			<key>Name</key><string>Dark Pad</string>
+			<key>Genre</key><string>Ambient</string>
+			<key>Rating</key><integer>80</integer>
+			<key>Location</key><string>file:///Users/dj/Music/iTunes/iTunes%20Media/Music/Pads/dark%20pad.wav</string>
+		</dict>
+		<key>102</key>
+		<dict>
+			<key>Track ID</key><integer>102</integer>
+			<key>Rating</key><integer>60</integer>
+			<key>Rating Computed</key><true/>
+			<key>Location</key><string>file:///Users/dj/Music/iTunes/iTunes%20Media/Music/kick.wav</string>
+		</dict>
+		<key>103</key>
+		<dict>
+			<key>Track ID</key><integer>103</integer>
+			<key>Location</key><string>file:///Volumes/Old/gone.wav</string>
+		</dict>
+		<key>104</key>
+		<dict>
+			<key>Track ID</key><integer>104</integer>
+			<key>Name</key><string>Streamed</string>
+		</dict>
+	</dict>
+	<key>Playlists</key>
+	<array>
+		<dict>
+			<key>Name</key><string>Library</string>
+			<key>Master</key><true/>
+			<key>Playlist Items</key>
+			<array>
+				<dict><key>Track ID</key><integer>101</integer></dict>
+				<dict><key>Track ID</key><integer>102</integer></dict>
+			</array>
+		</dict>
+		<dict>
+			<key>Name</key><string>Music</string>
+			<key>Distinguished Kind</key><integer>4</integer>
+			<key>Playlist Items</key>
+			<array><dict><key>Track ID</key><integer>101</integer></dict></array>
+		</dict>
+		<dict>
+			<key>Name</key><string>Gigs</string>
+			<key>Playlist Persistent ID</key><string>AAAA</string>
+			<key>Folder</key><true/>
+		</dict>
+		<dict>
+			<key>Name</key><string>Berlin</string>
+			<key>Playlist Persistent ID</key><string>BBBB</string>
+			<key>Parent Persistent ID</key><string>AAAA</string>
+			<key>Playlist Items</key>
+			<array>
+				<dict><key>Track ID</key><integer>101</integer></dict>
+				<dict><key>Track ID</key><integer>102</integer></dict>
+			</array>
+		</dict>
+	</array>
+</dict>
+</plist>
+"#;
+
+    #[test]
+    fn test_location_path() {
+        assert_eq!(
+            location_path("file://localhost/Users/dj/a%20b%C3%A9.wav").unwrap(),
+            "/Users/dj/a bé.wav"
+        );
+        assert_eq!(
+            location_path("file://localhost/C:/Music/a.wav").unwrap(),
+            "C:/Music/a.wav"
+        );
+        assert_eq!(location_path("http://example.com/a.mp3"), None);
+    }
+
+    #[test]
+    fn test_import_itunes() -> anyhow::Result<()> {
+        // the library lived on another machine; only the tails of the paths match
+        let (_dir, root) = fs_fixture(&[
+            "samples/Music/Pads/dark pad.wav",
+            "samples/Other/dark pad.wav",
+            "samples/Music/kick.wav",
+        ])?;
+        let mut state = State::new(&root, ["wav"].into())?;
+        state.add_tag(root.join("samples/Music/kick.wav"), "drums")?;
+
+        let report = state.import_itunes_from(LIBRARY.as_bytes())?;
+        assert_eq!(report.matched, 2);
+        assert_eq!(report.tagged, 2);
+        assert_eq!(report.unmatched, ["file:///Volumes/Old/gone.wav"]);
+        assert!(report.rejected.is_empty());
+
+        let tags = |state: &State, path: &str| -> Vec<String> {
+            let info = state.get(&root.join(path)).unwrap();
+            info.tags().iter().map(Tag::to_string).collect()
+        };
+        assert_eq!(
+            tags(&state, "samples/Music/Pads/dark pad.wav"),
+            ["genre:Ambient", "playlist:Gigs/Berlin", "rating:4"]
+        );
+        assert_eq!(
+            tags(&state, "samples/Music/kick.wav"),
+            ["drums", "playlist:Gigs/Berlin"]
+        );
+        assert!(state
+            .get(&root.join("samples/Other/dark pad.wav"))
+            .is_none());
+
+        assert!(state.undo());
+        assert_eq!(tags(&state, "samples/Music/kick.wav"), ["drums"]);
+        assert!(state
+            .get(&root.join("samples/Music/Pads/dark pad.wav"))
+            .is_none_or(|info| info.tags().is_empty()));
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+//! An append-only file of the edits behind [`State::undo`] and [`State::redo`], so the history
+//! outlives the process that made it.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{ops::Edit, FilepersonError, State};
+
+/// One line of the journal, as JSON.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum JournalEntry {
+    Edit(Edit),
+    Undo,
+    Redo,
+    /// The history of `from` now belongs to `to`, see [`State::move_file`].
+    Move {
+        from: Utf8PathBuf,
+        to: Utf8PathBuf,
+    },
+    /// The history of a path that stopped being tracked was dropped.
+    Forget(Utf8PathBuf),
+}
+
+/// The open journal of a [`State`], see [`State::open_journal`].
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: Utf8PathBuf,
+    file: File,
+}
+
+impl State {
+    /// Record every undoable edit, undo and redo in the journal at `path` from now on.
+    ///
+    /// An existing journal is read first and replaces the in-memory history, so `undo` picks up
+    /// where the session that wrote it left off. The journal must belong to this state, i.e. have
+    /// been written alongside the edits that are in it. Returns the number of entries read.
+    ///
+    /// Only what [`State::undo`] can revert is journaled, plus where moved files' history went
+    /// and which removed files' history was dropped; changes left out of the undo history are
+    /// left out of the journal too, and only survive through [`State::save`].
+    pub fn open_journal(&mut self, path: impl AsRef<Utf8Path>) -> Result<usize, FilepersonError> {
+        let path = path.as_ref();
+        let file_error = |source| FilepersonError::File {
+            path: path.to_owned(),
+            source,
+        };
+        self.edits.clear();
+        let mut read = 0;
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(file_error)?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line)? {
+                        JournalEntry::Edit(edit) => self.edits.push(edit),
+                        JournalEntry::Undo => {
+                            self.edits.step_back();
+                        }
+                        JournalEntry::Redo => {
+                            self.edits.step_forward();
+                        }
+                        JournalEntry::Move { from, to } => self.edits.move_paths(&from, &to),
+                        JournalEntry::Forget(path) => self.edits.forget(&path),
+                    }
+                    read += 1;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(file_error(e)),
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(file_error)?;
+        self.journal = Some(Journal {
+            path: path.to_owned(),
+            file,
+        });
+        Ok(read)
+    }
+
+    /// Stop journaling. The in-memory history is kept.
+    pub fn close_journal(&mut self) {
+        self.journal = None;
+    }
+
+    /// Append `entry` to the open journal, if any. A journal that can't be written to is closed
+    /// rather than failing the edit it records.
+    pub(crate) fn journal(&mut self, entry: JournalEntry) {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(journal.file, "{}", line));
+        if let Err(e) = written {
+            log::error!("closing journal {}: {}", journal.path, e);
+            self.journal = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        tests::{fs_fixture, snapshot, state_fixture},
+        Tag,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_journal_across_sessions() -> anyhow::Result<()> {
+        let (_tmp, dir) = fs_fixture(&[])?;
+        let journal = dir.join("edits.jsonl");
+        let a = Utf8Path::new("/music/a.wav");
+
+        let mut state = state_fixture();
+        assert_eq!(state.open_journal(&journal)?, 0);
+        state.add_tag(a, "rock")?;
+        state.set_delete(a, Some(true));
+        state.add_tag(a, "live")?;
+        state.undo();
+        let before_live = snapshot(&state);
+        state.save(dir.join("state.json"))?;
+        drop(state);
+
+        let mut state = State::load(dir.join("state.json"))?;
+        assert_eq!(state.open_journal(&journal)?, 4);
+        assert!(state.redo());
+        assert_eq!(state.get(a).unwrap().tags().len(), 2);
+        assert!(state.undo());
+        assert_eq!(snapshot(&state), before_live);
+        assert!(state.undo());
+        assert_eq!(state.get(a).unwrap().delete(), None);
+        state.save(dir.join("state.json"))?;
+
+        // the second session's steps are journaled too
+        let mut state = State::load(dir.join("state.json"))?;
+        assert_eq!(state.open_journal(&journal)?, 7);
+        assert!(state.redo());
+        assert!(state.redo());
+        assert!(!state.redo());
+        assert_eq!(state.get(a).unwrap().tags()[0], Tag::from("live"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_close_journal() -> anyhow::Result<()> {
+        let (_tmp, dir) = fs_fixture(&[])?;
+        let journal = dir.join("edits.jsonl");
+        let mut state = state_fixture();
+        state.open_journal(&journal)?;
+        state.add_tag("/music/a.wav", "rock")?;
+        state.close_journal();
+        state.add_tag("/music/a.wav", "live")?;
+        assert!(state.undo());
+
+        let mut fresh = state_fixture();
+        assert_eq!(fresh.open_journal(&journal)?, 1);
+        Ok(())
+    }
+}
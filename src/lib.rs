@@ -1,42 +1,264 @@
 use std::{
-    borrow::{Borrow, Cow},
-    collections::{BTreeSet, HashMap, HashSet},
+    borrow::Borrow,
+    cell::{Cell, OnceCell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     convert::Infallible,
-    error::Error,
     fmt::Display,
-    fs::{DirEntry, File},
-    hash::Hash,
-    iter::Filter,
+    fs::DirEntry,
+    hash::{Hash, Hasher},
+    io::Write,
     path::{Component, Components, StripPrefixError},
-    rc::Rc,
     str::FromStr,
-    sync::atomic::AtomicU32,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
 
 use camino::{Utf8Path, Utf8PathBuf};
-use caseless::default_case_fold_str;
+use globset::{GlobBuilder, GlobSetBuilder};
 use itertools::Itertools;
-use log::{debug, error, info};
+use log::{error, info};
 use rayon::prelude::*;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use walkdir::WalkDir;
 
-#[derive(Serialize, Deserialize, Clone)]
+mod apply;
+#[cfg(feature = "audio")]
+mod audio;
+#[cfg(feature = "blake3")]
+mod dedupe;
+mod diff;
+#[cfg(feature = "metadata")]
+mod embed;
+mod error;
+mod exchange;
+#[cfg(feature = "finder")]
+mod finder;
+#[cfg(feature = "fingerprint")]
+mod fingerprint;
+mod index;
+mod intern;
+#[cfg(feature = "itunes")]
+mod itunes;
+mod journal;
+mod metadata;
+mod ops;
+mod persist;
+mod playlist;
+mod policy;
+mod query;
+mod rekordbox;
+mod rules;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "watch")]
+mod watch;
+#[cfg(feature = "xattr")]
+mod xattrs;
 
+pub use apply::{Action, ActionKind, ApplyOptions, ApplyReport, Conflict, Plan, PlannedAction};
+#[cfg(feature = "blake3")]
+pub use dedupe::{DuplicateReport, KeepDuplicate};
+pub use diff::{FilePatch, StateDiff, TagChange, TagPatch};
+#[cfg(feature = "metadata")]
+pub use embed::{EmbedReport, EMBEDDED_TAGS_KEY};
+pub use error::{FilepersonError, LoadError};
+#[cfg(feature = "finder")]
+pub use finder::{
+    read_finder_tags, write_finder_tags, FinderColor, FinderConflict, FinderSyncReport, FinderTag,
+    FINDER_TAGS_XATTR,
+};
+#[cfg(feature = "fingerprint")]
+pub use fingerprint::{Fingerprint, NEAR_DUPLICATE_THRESHOLD};
+use index::Infos;
+use intern::TagInterner;
+#[cfg(feature = "itunes")]
+pub use itunes::ItunesReport;
+use journal::Journal;
+pub use metadata::AudioMetadata;
+use ops::EditLog;
+pub use ops::{Op, OpLog};
+pub use persist::STATE_VERSION;
+pub use playlist::M3uOptions;
+pub use policy::{TagPolicy, TagViolation};
+pub use query::{QueryError, TagQuery};
+pub use rules::{GlobOrRegex, TagRule};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+#[cfg(feature = "watch")]
+pub use watch::{WatchEvent, WatchHandle};
+#[cfg(feature = "xattr")]
+pub use xattrs::TAGS_XATTR;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "TagFields")]
 pub struct Tag {
-    color: Option<String>,
-    value: String,
+    color: Option<Color>,
+    value: Arc<str>,
+    /// Human-readable explanation for legends, e.g. "isolated instrument tracks" for `stems`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// `value`, case-folded once so comparing and hashing don't fold (and allocate) each time.
+    #[serde(skip)]
+    folded: Arc<str>,
+}
+
+/// What a serialized [`Tag`] holds; deserializing goes through here to fill in `folded`.
+#[derive(Deserialize)]
+struct TagFields {
+    color: Option<Color>,
+    value: Arc<str>,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+impl From<TagFields> for Tag {
+    fn from(fields: TagFields) -> Self {
+        Self {
+            color: fields.color,
+            description: fields.description,
+            ..Self::from_value(fields.value)
+        }
+    }
+}
+
+/// A 24-bit RGB color, written as `#rrggbb`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid hex color {0:?}, expected #rrggbb")]
+pub struct InvalidColor(String);
+
+impl FromStr for Color {
+    type Err = InvalidColor;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(InvalidColor(s.to_string()));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(Color {
+            r: channel(0),
+            g: channel(2),
+            b: channel(4),
+        })
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Accepts `#rrggbb` and, as written by early versions, plain `rrggbb`.
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Tag {
+    /// A plain tag with exactly `value`, folded once up front.
+    pub(crate) fn from_value(value: Arc<str>) -> Self {
+        Self {
+            color: None,
+            folded: caseless::default_case_fold_str(&value).into(),
+            value,
+            description: None,
+        }
+    }
+
+    pub fn with_color(mut self, color: Color) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn color(&self) -> Option<Color> {
+        self.color
+    }
+
+    /// Whether this tag names a namespace rather than a single tag: it ends in one of the
+    /// separators `:` or `/`, like `genre:` or `mood/`. A namespace covers all tags beneath it,
+    /// see [`Tag::covers`].
+    pub fn is_namespace(&self) -> bool {
+        self.value.ends_with(TAG_SEPARATORS)
+    }
+
+    /// The parts of a hierarchical tag: `genre:techno/minimal` is `genre`, `techno` and
+    /// `minimal`. A plain tag is a single segment.
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.value
+            .strip_suffix(TAG_SEPARATORS)
+            .unwrap_or(&self.value)
+            .split(TAG_SEPARATORS)
+    }
+
+    /// The namespace directly enclosing this tag, separator included: `genre:` for
+    /// `genre:techno`, and for `genre:techno/` itself. `None` for top-level tags.
+    pub fn parent(&self) -> Option<Tag> {
+        let inner = self
+            .value
+            .strip_suffix(TAG_SEPARATORS)
+            .unwrap_or(&self.value);
+        let end = inner.rfind(TAG_SEPARATORS)?;
+        Some(Tag::from_value(inner[..=end].into()))
+    }
+
+    /// Whether `other` is this tag or, if this is a namespace, anywhere beneath it. Caseless,
+    /// like equality; see [`TagCasing::covers`] for the case-sensitive flavor.
+    pub fn covers(&self, other: &Tag) -> bool {
+        TagCasing::Insensitive.covers(self, other)
+    }
+
+    /// The value wrapped in a 24-bit ANSI color escape, or the plain value without a color.
+    pub fn ansi(&self) -> String {
+        match self.color() {
+            Some(Color { r, g, b }) => format!("\x1b[38;2;{r};{g};{b}m{}\x1b[0m", self.value),
+            None => self.value.to_string(),
+        }
+    }
 }
 
+/// What separates the levels of a hierarchical tag like `genre:techno` or `mood/dark`.
+const TAG_SEPARATORS: [char; 2] = [':', '/'];
+
+/// 64-bit FNV-1a parameters, for [`State::tree_checksum`].
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
 impl FromStr for Tag {
     type Err = Infallible;
 
+    /// Trims surrounding whitespace and collapses internal runs of whitespace to one space.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self {
-            color: None,
-            value: s.to_string(),
-        })
+        Ok(Self::from_value(s.split_whitespace().join(" ").into()))
     }
 }
 
@@ -54,13 +276,13 @@ impl Display for Tag {
 
 impl Hash for Tag {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        caseless::default_case_fold_str(&self.value).hash(state);
+        self.folded.hash(state);
     }
 }
 
 impl PartialEq for Tag {
     fn eq(&self, other: &Self) -> bool {
-        caseless::default_caseless_match_str(&self.value, &other.value)
+        self.folded == other.folded
     }
 }
 
@@ -68,8 +290,7 @@ impl Eq for Tag {}
 
 impl Ord for Tag {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        caseless::default_case_fold_str(&self.value)
-            .cmp(&caseless::default_case_fold_str(&other.value))
+        self.folded.cmp(&other.folded)
     }
 }
 impl PartialOrd for Tag {
@@ -78,17 +299,83 @@ impl PartialOrd for Tag {
     }
 }
 
-type TagRef = Tag;
+/// How state-level operations (dedup in [`State::tags`], [`State::tag_counts`], membership)
+/// compare tags. `Tag`'s own `Eq`/`Hash`/`Ord` are always caseless.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TagCasing {
+    /// "Live" and "live" are the same tag.
+    #[default]
+    Insensitive,
+    /// "Live" and "live" are distinct tags.
+    Sensitive,
+}
+
+impl TagCasing {
+    pub fn matches(self, a: &Tag, b: &Tag) -> bool {
+        match self {
+            TagCasing::Insensitive => a == b,
+            TagCasing::Sensitive => a.value == b.value,
+        }
+    }
+
+    /// Like [`TagCasing::matches`], except that a namespace such as `genre:` also matches every
+    /// tag beneath it, see [`Tag::is_namespace`].
+    pub fn covers(self, parent: &Tag, tag: &Tag) -> bool {
+        if !parent.is_namespace() {
+            return self.matches(parent, tag);
+        }
+        match self {
+            TagCasing::Insensitive => tag.folded.starts_with(&*parent.folded),
+            TagCasing::Sensitive => tag.value.starts_with(&*parent.value),
+        }
+    }
 
-struct Action<'a> {
-    file: &'a FileInfo,
+    /// Caseless order, with exact spelling as a tie breaker when case-sensitive.
+    pub fn compare(self, a: &Tag, b: &Tag) -> std::cmp::Ordering {
+        match self {
+            TagCasing::Insensitive => a.cmp(b),
+            TagCasing::Sensitive => a.cmp(b).then_with(|| a.value.cmp(&b.value)),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+type TagRef = Tag;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FileInfo {
     path: Utf8PathBuf,
     delete: Option<bool>,
     tags: Vec<TagRef>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified: Option<SystemTime>,
+    /// Decoded play length, filled in by `FileInfo::probe_duration` (`audio` feature).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration: Option<Duration>,
+    /// Read from the file by `FileInfo::probe_metadata` (`metadata` feature).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    audio: Option<AudioMetadata>,
+    /// Cached thumbnail or preview image; generating it is up to the caller.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preview: Option<Utf8PathBuf>,
+}
+
+/// Equality and hashing only cover the path, matching the `Borrow<Utf8Path>` impl: a set of
+/// infos holds at most one per path, and retagging or re-`stat`ing a file never changes which
+/// bucket it lives in.
+impl PartialEq for FileInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for FileInfo {}
+
+impl Hash for FileInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
 }
 
 impl<P: AsRef<Utf8Path>> From<P> for FileInfo {
@@ -97,6 +384,11 @@ impl<P: AsRef<Utf8Path>> From<P> for FileInfo {
             path: p.as_ref().to_path_buf(),
             delete: None,
             tags: vec![],
+            size: None,
+            modified: None,
+            duration: None,
+            audio: None,
+            preview: None,
         }
     }
 }
@@ -108,13 +400,97 @@ impl Borrow<Utf8Path> for FileInfo {
 }
 
 impl FileInfo {
+    pub fn builder() -> FileInfoBuilder {
+        FileInfoBuilder::default()
+    }
+
     pub fn touched(&self) -> bool {
         self.delete.is_some() || !self.tags.is_empty()
     }
 
-    pub fn set_tags(&mut self, tags: Vec<TagRef>) {
-        if self.delete.is_none() {
-            self.delete = Some(true);
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    pub fn delete(&self) -> Option<bool> {
+        self.delete
+    }
+
+    pub fn set_delete(&mut self, delete: Option<bool>) {
+        self.delete = delete;
+    }
+
+    /// File size in bytes, as of the last [`FileInfo::stat`].
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Modification time, as of the last [`FileInfo::stat`].
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    pub fn preview(&self) -> Option<&Utf8Path> {
+        self.preview.as_deref()
+    }
+
+    pub fn set_preview(&mut self, preview: Option<Utf8PathBuf>) {
+        self.preview = preview;
+    }
+
+    /// The play length stored by the last successful probe, if any.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Capture size and modification time from the filesystem.
+    pub fn stat(&mut self) -> std::io::Result<()> {
+        let path = self.path.clone();
+        self.stat_at(&path)
+    }
+
+    /// [`FileInfo::stat`], reading the metadata of `on_disk` instead of the stored path.
+    fn stat_at(&mut self, on_disk: &Utf8Path) -> std::io::Result<()> {
+        let metadata = std::fs::metadata(on_disk)?;
+        self.size = Some(metadata.len());
+        self.modified = metadata.modified().ok();
+        Ok(())
+    }
+
+    pub fn set_tags(&mut self, mut tags: Vec<TagRef>) {
+        tags.sort();
+        tags.dedup();
+        self.tags = tags;
+    }
+
+    /// Add a tag, keeping `tags` sorted and free of (caseless) duplicates.
+    /// Returns `false` if the file already carried the tag.
+    pub fn add_tag(&mut self, tag: impl Into<TagRef>) -> bool {
+        self.add_tag_with(tag.into(), TagCasing::Insensitive)
+    }
+
+    /// Remove a tag (caseless). Returns `false` if the file didn't carry it.
+    pub fn remove_tag(&mut self, tag: &TagRef) -> bool {
+        self.remove_tag_with(tag, TagCasing::Insensitive)
+    }
+
+    fn add_tag_with(&mut self, tag: TagRef, casing: TagCasing) -> bool {
+        match self.tags.binary_search_by(|t| casing.compare(t, &tag)) {
+            Ok(_) => false,
+            Err(idx) => {
+                self.tags.insert(idx, tag);
+                true
+            }
+        }
+    }
+
+    fn remove_tag_with(&mut self, tag: &TagRef, casing: TagCasing) -> bool {
+        match self.tags.binary_search_by(|t| casing.compare(t, tag)) {
+            Ok(idx) => {
+                self.tags.remove(idx);
+                true
+            }
+            Err(_) => false,
         }
     }
 
@@ -128,13 +504,133 @@ impl FileInfo {
     }
 }
 
+/// Step-by-step construction of a [`FileInfo`], see [`FileInfo::builder`].
+#[derive(Clone, Debug, Default)]
+pub struct FileInfoBuilder {
+    path: Option<Utf8PathBuf>,
+    delete: Option<bool>,
+    tags: Vec<TagRef>,
+}
+
+impl FileInfoBuilder {
+    pub fn path(mut self, path: impl AsRef<Utf8Path>) -> Self {
+        self.path = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Add a tag. Can be called repeatedly; (caseless) duplicates are dropped.
+    pub fn tag(mut self, tag: impl Into<TagRef>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = Some(delete);
+        self
+    }
+
+    /// Fails if no path was given.
+    pub fn build(self) -> Result<FileInfo, FilepersonError> {
+        let path = self.path.ok_or(FilepersonError::MissingPath)?;
+        let mut info = FileInfo::from(path);
+        info.delete = self.delete;
+        info.set_tags(self.tags);
+        Ok(info)
+    }
+}
+
+/// Problems worth showing to a user before they commit to anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The file is marked for deletion but still carries tags.
+    DeleteWithTags(Utf8PathBuf),
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::DeleteWithTags(path) => {
+                write!(f, "{path} is marked for deletion but has tags")
+            }
+        }
+    }
+}
+
+/// Result of [`State::validate`]: how a (possibly deserialized) state differs from the disk.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Tracked files that no longer exist.
+    pub missing: Vec<Utf8PathBuf>,
+    /// Files on disk under the root that aren't tracked, among those the load's extension filter
+    /// includes.
+    pub untracked: Vec<Utf8PathBuf>,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.untracked.is_empty()
+    }
+}
+
+/// Headline counters over the tracked files, see [`State::stats`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Stats {
+    pub files: usize,
+    pub tagged: usize,
+    pub untagged: usize,
+    /// Files whose delete flag is `Some(true)`.
+    pub marked_for_deletion: usize,
+    pub distinct_tags: usize,
+    pub warnings: usize,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files ({} tagged, {} untagged), {} marked for deletion, {} distinct tags, {} warnings",
+            self.files,
+            self.tagged,
+            self.untagged,
+            self.marked_for_deletion,
+            self.distinct_tags,
+            self.warnings
+        )
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct State {
+    /// Format version of the serialized state, see [`STATE_VERSION`].
+    version: u32,
     root: Directory,
     flat: Directory,
-    infos: HashSet<FileInfo>,
+    infos: Infos,
+    #[serde(default)]
+    ops: OpLog,
+    #[serde(default)]
+    casing: TagCasing,
+    #[serde(default)]
+    policy: TagPolicy,
+    /// Set by [`State::with_vocabulary`]: the only tags that may be added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vocabulary: Option<BTreeSet<Tag>>,
+    #[serde(skip)]
+    edits: EditLog,
+    /// Set by [`State::open_journal`].
+    #[serde(skip)]
+    journal: Option<Journal>,
+    #[serde(skip)]
+    interner: TagInterner,
+    /// Set for states loaded with [`State::new_relative`]: every stored path is relative to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base: Option<Utf8PathBuf>,
+    /// The lowercased extensions the trees were loaded with; empty for every file.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    include: BTreeSet<String>,
 }
 
+#[allow(dead_code)]
 trait DirEntryExt {
     fn file_name_lossy(&self) -> String;
 }
@@ -145,10 +641,64 @@ impl DirEntryExt for DirEntry {
     }
 }
 
+/// What to do when a file's metadata can't be read while summing sizes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MetadataErrorPolicy {
+    /// Return the first error.
+    #[default]
+    Fail,
+    /// Leave the file out of the total.
+    Skip,
+}
+
+/// One file as shown in a table view, see [`State::file_rows`].
+#[derive(Debug)]
+pub struct FileRow<'a> {
+    pub path: &'a Utf8Path,
+    /// Empty for files that aren't tracked.
+    pub tags: &'a [Tag],
+    pub delete: Option<bool>,
+    known_size: Option<u64>,
+    on_disk: Utf8PathBuf,
+    size: OnceCell<Option<u64>>,
+}
+
+impl FileRow<'_> {
+    /// The file size in bytes, statting the file on first call unless it was already known.
+    /// `None` if the file can't be statted.
+    pub fn size(&self) -> Option<u64> {
+        *self.size.get_or_init(|| {
+            self.known_size
+                .or_else(|| std::fs::metadata(&self.on_disk).ok().map(|m| m.len()))
+        })
+    }
+}
+
+/// How many nodes a tree holds, see [`Directory::count`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct NodeCounts {
+    pub files: usize,
+    /// Directories beneath the counted one, not including itself.
+    pub directories: usize,
+}
+
+/// Per-directory size rollup, as produced by [`Directory::size_tree`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SizeTree {
+    pub path: Utf8PathBuf,
+    /// Total bytes of all files beneath `path`.
+    pub size: u64,
+    pub children: Vec<SizeTree>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Directory {
     this: Utf8PathBuf,
     entries: Vec<FsNode>,
+    /// Entries of this directory whose on-disk name wasn't valid UTF-8 and whose path is only
+    /// a lossy rendering, see [`NonUtf8Policy::Lossy`].
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    lossy: BTreeSet<Utf8PathBuf>,
 }
 
 impl Directory {
@@ -157,6 +707,328 @@ impl Directory {
     pub fn entries(&self) -> &[FsNode] {
         self.entries.as_ref()
     }
+
+    /// Whether `path` (anywhere beneath this directory) is a lossy rendering of a non-UTF-8
+    /// name, and so doesn't exist on disk under that name.
+    pub fn is_lossy(&self, path: &Utf8Path) -> bool {
+        self.lossy.contains(path)
+            || self.entries.iter().any(|node| match node {
+                FsNode::Directory(dir) => path.starts_with(&dir.this) && dir.is_lossy(path),
+                FsNode::File(_) => false,
+            })
+    }
+
+    /// Recursively iterate over all file paths beneath this directory.
+    pub fn files(&self) -> Box<dyn Iterator<Item = &Utf8Path> + '_> {
+        Box::new(
+            self.entries
+                .iter()
+                .flat_map(|node| -> Box<dyn Iterator<Item = &Utf8Path>> {
+                    match node {
+                        FsNode::File(path) => Box::new(std::iter::once(path.as_path())),
+                        FsNode::Directory(dir) => dir.files(),
+                    }
+                }),
+        )
+    }
+
+    /// Total bytes of all files beneath this directory. Fails on the first unreadable file.
+    pub fn total_size(&self) -> std::io::Result<u64> {
+        self.total_size_with(MetadataErrorPolicy::Fail)
+    }
+
+    pub fn total_size_with(&self, policy: MetadataErrorPolicy) -> std::io::Result<u64> {
+        Ok(self.size_tree(policy)?.size)
+    }
+
+    /// Like [`Directory::total_size_with`], with a rollup for every subdirectory.
+    pub fn size_tree(&self, policy: MetadataErrorPolicy) -> std::io::Result<SizeTree> {
+        let mut size = 0;
+        let mut children = vec![];
+        for entry in &self.entries {
+            match entry {
+                FsNode::File(path) => match std::fs::metadata(path) {
+                    Ok(metadata) => size += metadata.len(),
+                    Err(e) if policy == MetadataErrorPolicy::Skip => {
+                        log::debug!("skipping size of {path:?}: {e}")
+                    }
+                    Err(e) => return Err(e),
+                },
+                FsNode::Directory(dir) => {
+                    let child = dir.size_tree(policy)?;
+                    size += child.size;
+                    children.push(child);
+                }
+            }
+        }
+        Ok(SizeTree {
+            path: self.this.clone(),
+            size,
+            children,
+        })
+    }
+
+    /// All files beneath this directory relative to `root`, natord-sorted.
+    /// Fails if any file isn't under `root`.
+    pub fn relative_files(&self, root: &Utf8Path) -> Result<Vec<Utf8PathBuf>, StripPrefixError> {
+        let mut relative = self
+            .files()
+            .map(|path| path.strip_prefix(root).map(Utf8Path::to_path_buf))
+            .collect::<Result<Vec<_>, _>>()?;
+        relative.sort_by(|a, b| natord::compare_ignore_case(a.as_str(), b.as_str()));
+        Ok(relative)
+    }
+
+    /// Replace every path in this tree (including lossy markers) for which `f` returns a new one.
+    fn rewrite_paths(&mut self, f: &mut impl FnMut(&Utf8Path) -> Option<Utf8PathBuf>) {
+        if let Some(this) = f(&self.this) {
+            self.this = this;
+        }
+        self.lossy = std::mem::take(&mut self.lossy)
+            .into_iter()
+            .map(|path| f(&path).unwrap_or(path))
+            .collect();
+        for node in &mut self.entries {
+            match node {
+                FsNode::File(path) => {
+                    if let Some(new) = f(path) {
+                        *path = new;
+                    }
+                }
+                FsNode::Directory(dir) => dir.rewrite_paths(f),
+            }
+        }
+    }
+
+    /// The node for `path` beneath this directory, descending only into directories that
+    /// contain it.
+    pub fn get(&self, path: &Utf8Path) -> Option<&FsNode> {
+        self.entries.iter().find_map(|node| match node {
+            _ if node.path() == path => Some(node),
+            FsNode::Directory(dir) if path.starts_with(&dir.this) => dir.get(path),
+            _ => None,
+        })
+    }
+
+    /// Remove the node (file or directory) for `path` from this tree and return it.
+    fn remove_node(&mut self, path: &Utf8Path) -> Option<FsNode> {
+        let found = self.entries.iter().position(|node| node.path() == path);
+        if let Some(idx) = found {
+            self.lossy.remove(path);
+            return Some(self.entries.remove(idx));
+        }
+        self.entries.iter_mut().find_map(|node| match node {
+            FsNode::Directory(dir) if path.starts_with(&dir.this) => dir.remove_node(path),
+            _ => None,
+        })
+    }
+
+    /// Insert a node into its parent directory within this tree, natord-sorted among its
+    /// siblings. Returns `false` if the parent directory isn't part of the tree.
+    fn insert_node(&mut self, node: FsNode) -> bool {
+        if node.path().parent() == Some(self.this.as_path()) {
+            let name = node.path().file_name().unwrap_or_default();
+            let idx = self
+                .entries
+                .iter()
+                .position(|other| {
+                    let other = other.path().file_name().unwrap_or_default();
+                    natord::compare_ignore_case(name, other) == std::cmp::Ordering::Less
+                })
+                .unwrap_or(self.entries.len());
+            self.entries.insert(idx, node);
+            return true;
+        }
+        for dir in &mut self.entries {
+            if let FsNode::Directory(dir) = dir {
+                if node.path().starts_with(&dir.this) {
+                    return dir.insert_node(node);
+                }
+            }
+        }
+        false
+    }
+
+    /// Files sharing a (case-folded) file name in different places, keyed by the folded name.
+    /// Names that occur only once are left out.
+    pub fn duplicate_names(&self) -> BTreeMap<String, Vec<Utf8PathBuf>> {
+        let mut by_name: BTreeMap<String, Vec<Utf8PathBuf>> = BTreeMap::new();
+        for path in self.files() {
+            if let Some(name) = path.file_name() {
+                by_name
+                    .entry(caseless::default_case_fold_str(name))
+                    .or_default()
+                    .push(path.to_owned());
+            }
+        }
+        by_name.retain(|_, paths| paths.len() > 1);
+        by_name
+    }
+
+    /// A pruned copy keeping only files whose lowercased extension is in `include`.
+    /// Subdirectories left without files are dropped; `self` is untouched.
+    pub fn filter_extensions(&self, include: &HashSet<String>) -> Directory {
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|node| match node {
+                FsNode::File(path) => path
+                    .extension()
+                    .filter(|ext| include.contains(&ext.to_lowercase()))
+                    .map(|_| node.clone()),
+                FsNode::Directory(dir) => {
+                    let dir = dir.filter_extensions(include);
+                    (!dir.entries.is_empty()).then_some(FsNode::Directory(dir))
+                }
+            })
+            .collect::<Vec<_>>();
+        let lossy = self
+            .lossy
+            .iter()
+            .filter(|path| entries.iter().any(|node| node.path() == path.as_path()))
+            .cloned()
+            .collect();
+        Directory {
+            this: self.this.clone(),
+            entries,
+            lossy,
+        }
+    }
+
+    /// A copy with the entries of every directory natord-sorted by their path relative to this
+    /// one, so the order doesn't depend on how the tree was walked. For `flat` this sorts by
+    /// full relative path.
+    pub fn sorted(&self) -> Directory {
+        let mut sorted = self.clone();
+        sorted.sort_in_place();
+        sorted
+    }
+
+    /// [`Directory::sorted`], in place.
+    pub fn sort_in_place(&mut self) {
+        let this = &self.this;
+        let relative = |node: &FsNode| {
+            node.path()
+                .strip_prefix(this)
+                .map_or(node.path().as_str(), Utf8Path::as_str)
+                .to_owned()
+        };
+        self.entries
+            .sort_by(|a, b| natord::compare_ignore_case(&relative(a), &relative(b)));
+        for node in &mut self.entries {
+            if let FsNode::Directory(dir) = node {
+                dir.sort_in_place();
+            }
+        }
+    }
+
+    /// Drop subdirectories that contain no files, even transitively, e.g. after filtering.
+    pub fn prune_empty(&mut self) {
+        for node in &mut self.entries {
+            if let FsNode::Directory(dir) = node {
+                dir.prune_empty();
+            }
+        }
+        let lossy = &mut self.lossy;
+        self.entries.retain(|node| match node {
+            FsNode::Directory(dir) if dir.entries.is_empty() => {
+                lossy.remove(&dir.this);
+                false
+            }
+            _ => true,
+        });
+    }
+
+    /// Write a `tree(1)`-style drawing of this directory, entries in stored (natord) order.
+    pub fn render_tree(&self, w: impl Write) -> std::io::Result<()> {
+        self.render_tree_impl(w, None)
+    }
+
+    /// Like [`Directory::render_tree`], with each file's tags from `state` after its name.
+    pub fn render_tree_with_tags(&self, w: impl Write, state: &State) -> std::io::Result<()> {
+        self.render_tree_impl(w, Some(state))
+    }
+
+    fn render_tree_impl(&self, mut w: impl Write, state: Option<&State>) -> std::io::Result<()> {
+        writeln!(w, "{}", self.this)?;
+        self.render_entries(&mut w, "", state)
+    }
+
+    fn render_entries(
+        &self,
+        w: &mut dyn Write,
+        prefix: &str,
+        state: Option<&State>,
+    ) -> std::io::Result<()> {
+        for (i, node) in self.entries.iter().enumerate() {
+            let last = i + 1 == self.entries.len();
+            let connector = if last { "└── " } else { "├── " };
+            let name = node.path().file_name().unwrap_or(node.path().as_str());
+            write!(w, "{prefix}{connector}{name}")?;
+            match node {
+                FsNode::File(path) => {
+                    let tags = state
+                        .and_then(|state| state.get(path))
+                        .map(|f| f.tags.as_slice())
+                        .unwrap_or_default();
+                    if !tags.is_empty() {
+                        write!(w, " [{}]", tags.iter().join(", "))?;
+                    }
+                    writeln!(w)?;
+                }
+                FsNode::Directory(dir) => {
+                    writeln!(w)?;
+                    let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+                    dir.render_entries(w, &prefix, state)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every node beneath this directory in pre-order, with its depth: this directory's own
+    /// entries are at depth 0.
+    pub fn walk(&self) -> impl Iterator<Item = (usize, &FsNode)> + '_ {
+        self.walk_from(0)
+    }
+
+    /// Files and directories beneath this directory, recursively.
+    pub fn count(&self) -> NodeCounts {
+        self.walk()
+            .fold(NodeCounts::default(), |mut counts, (_, node)| {
+                match node {
+                    FsNode::File(_) => counts.files += 1,
+                    FsNode::Directory(_) => counts.directories += 1,
+                }
+                counts
+            })
+    }
+
+    fn walk_from(&self, depth: usize) -> Box<dyn Iterator<Item = (usize, &FsNode)> + '_> {
+        Box::new(self.entries.iter().flat_map(move |node| {
+            let children: Box<dyn Iterator<Item = (usize, &FsNode)>> = match node {
+                FsNode::File(_) => Box::new(std::iter::empty()),
+                FsNode::Directory(dir) => dir.walk_from(depth + 1),
+            };
+            std::iter::once((depth, node)).chain(children)
+        }))
+    }
+
+    /// Recursively iterate over all paths (files and directories) beneath this directory.
+    pub fn paths(&self) -> Box<dyn Iterator<Item = &Utf8Path> + '_> {
+        Box::new(
+            self.entries
+                .iter()
+                .flat_map(|node| -> Box<dyn Iterator<Item = &Utf8Path>> {
+                    match node {
+                        FsNode::File(path) => Box::new(std::iter::once(path.as_path())),
+                        FsNode::Directory(dir) => {
+                            Box::new(std::iter::once(dir.this.as_path()).chain(dir.paths()))
+                        }
+                    }
+                }),
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -165,6 +1037,16 @@ pub enum FsNode {
     Directory(Directory),
 }
 
+impl FsNode {
+    pub fn path(&self) -> &Utf8Path {
+        match self {
+            FsNode::File(path) => path,
+            FsNode::Directory(dir) => &dir.this,
+        }
+    }
+}
+
+#[allow(dead_code)]
 impl FsNode {
     fn entry_iter(&mut self, components: Components) {
         for comp in components {
@@ -172,7 +1054,7 @@ impl FsNode {
         }
     }
     fn entry(&self, comp: Component) -> Option<FsNode> {
-        if let Component::Normal(n) = comp {
+        if let Component::Normal(_n) = comp {
             match self {
                 FsNode::File(_) => todo!(),
                 FsNode::Directory(_) => todo!(),
@@ -182,167 +1064,2749 @@ impl FsNode {
     }
 }
 
-#[derive(Error, Debug)]
-enum LoadError {
-    #[error("Walk")]
-    Walk(#[from] walkdir::Error),
-    #[error("Strip")]
-    Strip(#[from] StripPrefixError),
-    #[error("Bork")]
-    NonUtf8Path(PathBuf),
-}
+use std::path::{Path, PathBuf};
 
-use std::path::PathBuf;
-fn load_rec(
-    parent: &mut Directory,
-    flat: &mut Directory,
-    include: &HashSet<String>,
-    count: &AtomicU32,
-) {
-    let parent_as_path = parent.this.clone();
-    for entry in WalkDir::new(parent_as_path.clone())
-        .min_depth(1)
-        .max_depth(1)
-        .sort_by(|a, b| {
-            natord::compare_ignore_case(
-                a.file_name().to_string_lossy().borrow(),
-                b.file_name().to_string_lossy().borrow(),
-            )
-        })
-    {
-        let val = count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        if val % 100 == 0 {
-            info!("(load) {val}");
-        }
+/// What to do with entries whose path isn't valid UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum NonUtf8Policy {
+    /// Log an error and leave the entry out.
+    #[default]
+    Skip,
+    /// Keep the entry under its `to_string_lossy` rendering and flag it, see
+    /// [`Directory::is_lossy`].
+    Lossy,
+}
 
-        let maybe_path = entry.map_err(LoadError::from).and_then(|entry| {
-            entry
-                .path()
-                .strip_prefix(parent_as_path.clone())
-                .map_err(LoadError::from)
-                .and_then(|_path| {
-                    Utf8PathBuf::from_path_buf(entry.path().to_owned())
-                        .map_err(|e| LoadError::NonUtf8Path(e))
-                })
-                .and_then(|path| {
-                    if path.is_dir() {
-                        let cs = path.components();
-                        let mut dir = Directory {
-                            this: path,
-                            entries: vec![],
-                        };
-                        load_rec(&mut dir, flat, include, count);
-                        parent.entries.push(FsNode::Directory(dir));
-                    } else if path.is_file() {
-                        if let Some(name) = path.file_name() {
-                            if let Some(extension) = name.split(".").last() {
-                                if !include.contains(&extension.to_lowercase()) {
-                                    // log::warn!("includeping {name:?}");
-                                }
-                            }
-                        }
-                        let node = FsNode::File(path.into());
-                        flat.entries.push(node.clone());
-                        parent.entries.push(node);
-                    } else {
-                        log::debug!("skipping {path:?}");
-                    };
-
-                    Ok(())
-                })
-        });
+/// How the entries of each directory are ordered during a walk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SortMode {
+    /// Numbers compared by value, letters caselessly: `a2`, `A10`, `b1`.
+    #[default]
+    NaturalIgnoreCase,
+    /// Numbers compared by value, uppercase before lowercase: `A10`, `a2`, `b1`.
+    Natural,
+    /// Plain byte order, like `ls` in the C locale: `A10`, `a2`, `b1`, but `a10` before `a2`.
+    Lexical,
+}
 
-        if let Err(e) = maybe_path {
-            error!("{e:?}")
+impl SortMode {
+    pub fn compare(self, a: &str, b: &str) -> std::cmp::Ordering {
+        match self {
+            Self::NaturalIgnoreCase => natord::compare_ignore_case(a, b),
+            Self::Natural => natord::compare(a, b),
+            Self::Lexical => a.cmp(b),
         }
     }
 }
-pub fn load(
-    root: impl AsRef<Utf8Path>,
+
+/// Called with the number of entries seen so far, once per entry.
+pub type ProgressFn = Arc<dyn Fn(u32) + Send + Sync>;
+
+/// Knobs for [`load_with_options`].
+#[derive(Clone, Default)]
+pub struct LoadOptions {
+    pub non_utf8: NonUtf8Policy,
+    /// Once set, the walk stops descending and returns what it has so far.
+    pub cancel: Option<Arc<AtomicBool>>,
+    pub progress: Option<ProgressFn>,
+    /// Keep the files left out by the include filter, see [`Loaded::skipped`].
+    pub collect_skipped: bool,
+    /// Order of the entries within each directory.
+    pub sort: SortMode,
+}
+
+impl std::fmt::Debug for LoadOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoadOptions")
+            .field("non_utf8", &self.non_utf8)
+            .field("cancel", &self.cancel)
+            .field("progress", &self.progress.as_ref().map(|_| ".."))
+            .field("collect_skipped", &self.collect_skipped)
+            .field("sort", &self.sort)
+            .finish()
+    }
+}
+
+/// The result of [`load_with_options`].
+#[derive(Debug)]
+pub struct Loaded {
+    pub root: Directory,
+    pub flat: Directory,
+    /// Whether the walk was cut short via [`LoadOptions::cancel`]. The trees then hold the
+    /// entries visited up to that point, still in walk order.
+    pub cancelled: bool,
+    /// Entries that couldn't be read or represented and were left out of the trees.
+    pub errors: Vec<LoadError>,
+    /// Files left out because their extension isn't included, in walk order. Only collected
+    /// with [`LoadOptions::collect_skipped`].
+    pub skipped: Vec<Utf8PathBuf>,
+}
+
+/// Lowercase an include filter, the way [`Walk::included`] compares extensions.
+fn lowercase_include(include: HashSet<impl AsRef<str>>) -> BTreeSet<String> {
+    include
+        .into_iter()
+        .map(|s| s.as_ref().to_lowercase())
+        .collect()
+}
+
+/// Settings and shared bookkeeping for one walk over a tree.
+struct Walk<'a> {
+    include: HashSet<String>,
+    non_utf8: NonUtf8Policy,
+    /// Files for which this returns `false` are left out of both trees.
+    keep_file: Box<dyn Fn(&Utf8Path) -> bool + 'a>,
+    count: AtomicU32,
+    cancel: Option<Arc<AtomicBool>>,
+    cancelled: Cell<bool>,
+    progress: Option<ProgressFn>,
+    errors: RefCell<Vec<LoadError>>,
+    collect_skipped: bool,
+    skipped: RefCell<Vec<Utf8PathBuf>>,
+    sort: SortMode,
+    /// Canonical paths of the directories entered so far, so that symlinks, bind mounts and
+    /// junctions pointing back up the tree can't make the walk recurse forever.
+    visited: RefCell<HashSet<PathBuf>>,
+}
+
+impl<'a> Walk<'a> {
+    fn new(include: HashSet<String>) -> Self {
+        Self {
+            include,
+            non_utf8: NonUtf8Policy::default(),
+            keep_file: Box::new(|_| true),
+            count: AtomicU32::new(0),
+            cancel: None,
+            cancelled: Cell::new(false),
+            progress: None,
+            errors: RefCell::new(vec![]),
+            collect_skipped: false,
+            skipped: RefCell::new(vec![]),
+            sort: SortMode::default(),
+            visited: RefCell::new(HashSet::new()),
+        }
+    }
+
+    /// Records `dir` as entered. Returns `false` if it (or whatever it resolves to) was
+    /// already walked.
+    fn enter(&self, dir: &Path) -> bool {
+        match dir.canonicalize() {
+            Ok(canonical) => self.visited.borrow_mut().insert(canonical),
+            Err(_) => true,
+        }
+    }
+
+    fn keep_file(mut self, keep_file: impl Fn(&Utf8Path) -> bool + 'a) -> Self {
+        self.keep_file = Box::new(keep_file);
+        self
+    }
+
+    fn options(mut self, options: &LoadOptions) -> Self {
+        self.non_utf8 = options.non_utf8;
+        self.cancel = options.cancel.clone();
+        self.progress = options.progress.clone();
+        self.collect_skipped = options.collect_skipped;
+        self.sort = options.sort;
+        self
+    }
+
+    /// Whether `path` passes the include filter; an empty filter includes everything.
+    fn included(&self, path: &Utf8Path) -> bool {
+        self.include.is_empty()
+            || path
+                .extension()
+                .is_some_and(|ext| self.include.contains(&ext.to_lowercase()))
+    }
+
+    /// Log a non-fatal problem and keep it for [`Loaded::errors`].
+    fn error(&self, e: LoadError) {
+        error!("{e:?}");
+        self.errors.borrow_mut().push(e);
+    }
+
+    /// Whether the walk should stop. Remembers that it did, for [`Loaded::cancelled`].
+    fn should_stop(&self) -> bool {
+        let stop = self
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+        if stop {
+            self.cancelled.set(true);
+        }
+        stop
+    }
+
+    /// Walk `root`. A file root yields its parent directory holding just that file, whatever
+    /// the filters say, since the user named it explicitly.
+    fn run(&self, root: &Utf8Path) -> (Directory, Directory) {
+        if root.is_file() {
+            let tree = Directory {
+                this: root.parent().unwrap_or(root).to_owned(),
+                entries: vec![FsNode::File(root.to_owned())],
+                lossy: BTreeSet::new(),
+            };
+            return (tree.clone(), tree);
+        }
+        let mut node_root = Directory {
+            this: root.to_owned(),
+            entries: vec![],
+            lossy: BTreeSet::new(),
+        };
+        let mut flat = node_root.clone();
+        if self.enter(root.as_std_path()) {
+            load_rec(&mut node_root, root.as_std_path(), &mut flat, self);
+        }
+        (node_root, flat)
+    }
+}
+
+/// The canonical form of `root`, so every stored path shares a normalized prefix. Fails with
+/// [`FilepersonError::Root`] if `root` can't be read at all, or with
+/// [`LoadError::NonUtf8Path`] if it resolves to a non-UTF-8 path; problems further down the
+/// tree only affect the entries concerned.
+fn check_root(root: &Utf8Path) -> Result<Utf8PathBuf, FilepersonError> {
+    let canonical = std::fs::canonicalize(root).map_err(|source| FilepersonError::Root {
+        path: root.to_owned(),
+        source,
+    })?;
+    Utf8PathBuf::from_path_buf(canonical).map_err(|path| LoadError::NonUtf8Path(path).into())
+}
+
+/// Loads the entries of `dir` (the on-disk path of `parent`, which differs from `parent.this`
+/// for lossily rendered names) into `parent` and `flat`.
+fn load_rec(parent: &mut Directory, dir: &Path, flat: &mut Directory, walk: &Walk) {
+    let sort = walk.sort;
+    for entry in WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by(move |a, b| {
+            sort.compare(
+                a.file_name().to_string_lossy().borrow(),
+                b.file_name().to_string_lossy().borrow(),
+            )
+        })
+    {
+        if walk.should_stop() {
+            log::debug!("(load) cancelled in {dir:?}");
+            break;
+        }
+        let val = walk.count.fetch_add(1, Ordering::Relaxed);
+        if val.is_multiple_of(100) {
+            info!("(load) {val}");
+        }
+        if let Some(progress) = &walk.progress {
+            progress(val + 1);
+        }
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                walk.error(LoadError::from(e));
+                continue;
+            }
+        };
+        let (path, lossy) = match Utf8PathBuf::from_path_buf(entry.path().to_owned()) {
+            Ok(path) => (path, false),
+            Err(raw) if walk.non_utf8 == NonUtf8Policy::Lossy => {
+                let path = Utf8PathBuf::from(raw.to_string_lossy().into_owned());
+                log::debug!("keeping lossy rendering {path:?} of {raw:?}");
+                (path, true)
+            }
+            Err(raw) => {
+                walk.error(LoadError::NonUtf8Path(raw));
+                continue;
+            }
+        };
+
+        let fs_path = entry.path();
+        if fs_path.is_dir() {
+            if !walk.enter(fs_path) {
+                log::debug!("skipping already visited directory {path:?}");
+                continue;
+            }
+            let mut dir = Directory {
+                this: path.clone(),
+                entries: vec![],
+                lossy: BTreeSet::new(),
+            };
+            load_rec(&mut dir, fs_path, flat, walk);
+            parent.entries.push(FsNode::Directory(dir));
+        } else if fs_path.is_file() {
+            if !walk.included(&path) {
+                log::debug!("not included: {path:?}");
+                if walk.collect_skipped {
+                    walk.skipped.borrow_mut().push(path);
+                }
+                continue;
+            }
+            if !(walk.keep_file)(&path) {
+                log::debug!("filtered {path:?}");
+                continue;
+            }
+            if lossy {
+                flat.lossy.insert(path.clone());
+            }
+            let node = FsNode::File(path.clone());
+            flat.entries.push(node.clone());
+            parent.entries.push(node);
+        } else {
+            log::debug!("skipping {path:?}");
+            continue;
+        }
+        if lossy {
+            parent.lossy.insert(path);
+        }
+    }
+}
+
+/// Walk `root` into a tree and a flat list of its files whose extension is in `include`
+/// (compared lowercased; an empty set includes every file). Unreadable entries are only
+/// logged; use [`load_with_options`] to get them back as [`Loaded::errors`], and to learn which
+/// files the filter left out.
+///
+/// `root` is canonicalized first, so `./media` or `music/../media` are stored as absolute,
+/// normalized paths. This applies to all `load_*` functions.
+pub fn load(
+    root: impl AsRef<Utf8Path>,
     include: HashSet<impl AsRef<str>>,
-) -> anyhow::Result<(Directory, Directory)> {
-    let root = root.as_ref();
-
-    let mut node_root = Directory {
-        this: root.to_owned(),
-        entries: vec![],
-    };
-
-    let mut flat = node_root.clone();
-    let count = AtomicU32::new(0);
-    load_rec(
-        &mut node_root,
-        &mut flat,
-        &(include
-            .into_iter()
-            .map(|s| s.as_ref().to_lowercase())
-            .collect()),
-        &count,
-    );
+) -> Result<(Directory, Directory), FilepersonError> {
+    let loaded = load_with_options(root, include, &LoadOptions::default())?;
+    Ok((loaded.root, loaded.flat))
+}
+
+/// Like [`load`], with non-default [`LoadOptions`].
+pub fn load_with_options(
+    root: impl AsRef<Utf8Path>,
+    include: HashSet<impl AsRef<str>>,
+    options: &LoadOptions,
+) -> Result<Loaded, FilepersonError> {
+    let include = include
+        .into_iter()
+        .map(|s| s.as_ref().to_lowercase())
+        .collect();
+    let root = &check_root(root.as_ref())?;
+    let walk = Walk::new(include).options(options);
+    let (root, flat) = walk.run(root);
+    Ok(Loaded {
+        root,
+        flat,
+        cancelled: walk.cancelled.get(),
+        errors: walk.errors.into_inner(),
+        skipped: walk.skipped.into_inner(),
+    })
+}
+
+/// Like [`load`], but only keeps files whose path relative to `root` matches at least one of
+/// the glob `patterns`, e.g. `**/*.mp3` or `drums/*.wav`. `*` does not cross directory
+/// boundaries; use `**` for that.
+pub fn load_with_globs(
+    root: impl AsRef<Utf8Path>,
+    patterns: &[&str],
+) -> Result<(Directory, Directory), FilepersonError> {
+    let root = &check_root(root.as_ref())?;
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    let globs = builder.build()?;
+
+    let walk = Walk::new(HashSet::new()).keep_file(|path| {
+        path.strip_prefix(root)
+            .map(|relative| globs.is_match(relative))
+            .unwrap_or(false)
+    });
+    Ok(walk.run(root))
+}
+
+/// Like [`load`], but only keeps files whose file name (not the full path) matches `re`.
+/// All directories are still descended into.
+pub fn load_with_name_regex(
+    root: impl AsRef<Utf8Path>,
+    re: Regex,
+) -> Result<(Directory, Directory), FilepersonError> {
+    let root = &check_root(root.as_ref())?;
+    let walk = Walk::new(HashSet::new()).keep_file(|path| {
+        path.file_name()
+            .map(|name| re.is_match(name))
+            .unwrap_or(false)
+    });
+    Ok(walk.run(root))
+}
 
-    Ok((node_root, flat))
+/// An inclusive file size range in bytes; `None` leaves that side open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeFilter {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl SizeFilter {
+    pub fn contains(&self, size: u64) -> bool {
+        self.min.is_none_or(|min| size >= min) && self.max.is_none_or(|max| size <= max)
+    }
+}
+
+/// Like [`load`], but only keeps files whose size is within `filter`. Directories are always
+/// kept. Files whose size can't be read are kept as well.
+pub fn load_with_size(
+    root: impl AsRef<Utf8Path>,
+    include: HashSet<impl AsRef<str>>,
+    filter: SizeFilter,
+) -> Result<(Directory, Directory), FilepersonError> {
+    let root = &check_root(root.as_ref())?;
+    let include = include
+        .into_iter()
+        .map(|s| s.as_ref().to_lowercase())
+        .collect();
+    let walk = Walk::new(include).keep_file(|path| match std::fs::metadata(path) {
+        Ok(metadata) => filter.contains(metadata.len()),
+        Err(e) => {
+            log::debug!("keeping {path:?}, size unknown: {e}");
+            true
+        }
+    });
+    Ok(walk.run(root))
+}
+
+/// A modification time window; `None` leaves that side open. Both bounds are inclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModifiedFilter {
+    pub after: Option<SystemTime>,
+    pub before: Option<SystemTime>,
+}
+
+impl ModifiedFilter {
+    pub fn contains(&self, modified: SystemTime) -> bool {
+        self.after.is_none_or(|after| modified >= after)
+            && self.before.is_none_or(|before| modified <= before)
+    }
+}
+
+/// Like [`load`], but only keeps files last modified within `filter`, e.g. in the last seven
+/// days. Directories are always kept, and so are files whose mtime isn't available.
+pub fn load_with_modified(
+    root: impl AsRef<Utf8Path>,
+    include: HashSet<impl AsRef<str>>,
+    filter: ModifiedFilter,
+) -> Result<(Directory, Directory), FilepersonError> {
+    let root = &check_root(root.as_ref())?;
+    let include = include
+        .into_iter()
+        .map(|s| s.as_ref().to_lowercase())
+        .collect();
+    let walk = Walk::new(include).keep_file(|path| {
+        match std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => filter.contains(modified),
+            Err(e) => {
+                log::debug!("keeping {path:?}, mtime unknown: {e}");
+                true
+            }
+        }
+    });
+    Ok(walk.run(root))
 }
 
 impl State {
     pub fn new(
         root: impl AsRef<Utf8Path>,
         include: HashSet<impl AsRef<str>>,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self, FilepersonError> {
         let root = root.as_ref();
+        let include = lowercase_include(include);
+
+        let (root, flat) = load(root, include.iter().collect::<HashSet<_>>())?;
+        let mut state = Self::from_parts(root, flat);
+        state.include = include;
+        Ok(state)
+    }
+
+    /// Load several roots into one state. The top-level directory is synthetic: it has an empty
+    /// path and one entry per root, in the order given. `flat` holds the files of all roots.
+    pub fn new_multi(
+        roots: &[impl AsRef<Utf8Path>],
+        include: HashSet<impl AsRef<str>>,
+    ) -> Result<Self, FilepersonError> {
+        let include = lowercase_include(include);
+        let walk = Walk::new(include.iter().cloned().collect());
+        let mut root = Directory {
+            this: Utf8PathBuf::new(),
+            entries: vec![],
+            lossy: BTreeSet::new(),
+        };
+        let mut flat = root.clone();
+        for path in roots {
+            let (tree, files) = walk.run(&check_root(path.as_ref())?);
+            root.entries.push(FsNode::Directory(tree));
+            flat.entries.extend(files.entries);
+        }
+        let mut state = Self::from_parts(root, flat);
+        state.include = include;
+        Ok(state)
+    }
+
+    /// Like [`State::new`], but every path in the trees and infos is stored relative to `root`,
+    /// which is kept as the state's [`State::base`]. A saved state can then be moved to another
+    /// machine and pointed at the library's new location with [`State::set_base`].
+    ///
+    /// Methods that touch the filesystem resolve paths through [`State::absolute_path`];
+    /// [`State::watch`] doesn't support relative states.
+    pub fn new_relative(
+        root: impl AsRef<Utf8Path>,
+        include: HashSet<impl AsRef<str>>,
+    ) -> Result<Self, FilepersonError> {
+        let root = &check_root(root.as_ref())?;
+        let include = lowercase_include(include);
+        let (mut tree, mut flat) = load(root, include.iter().collect::<HashSet<_>>())?;
+        let mut strip = |path: &Utf8Path| path.strip_prefix(root).ok().map(Utf8Path::to_owned);
+        tree.rewrite_paths(&mut strip);
+        flat.rewrite_paths(&mut strip);
+        let mut state = Self::from_parts(tree, flat);
+        state.base = Some(root.to_owned());
+        state.include = include;
+        Ok(state)
+    }
+
+    /// The directory relative paths are resolved against, for [`State::new_relative`] states.
+    pub fn base(&self) -> Option<&Utf8Path> {
+        self.base.as_deref()
+    }
+
+    /// Point a relative state at a new location of its library. Stored paths are unchanged.
+    pub fn set_base(&mut self, base: impl Into<Utf8PathBuf>) {
+        self.base = Some(base.into());
+    }
+
+    /// Where a stored path lives on disk: joined onto [`State::base`] for relative states,
+    /// unchanged otherwise.
+    pub fn absolute_path(&self, rel: &Utf8Path) -> Utf8PathBuf {
+        match &self.base {
+            Some(base) => base.join(rel),
+            None => rel.to_owned(),
+        }
+    }
+
+    /// A walk with the extension filter the trees were loaded with.
+    pub(crate) fn loaded_walk(&self) -> Walk<'static> {
+        Walk::new(self.include.iter().cloned().collect())
+    }
+
+    /// The directories that were actually walked: the root itself, or each root of a
+    /// [`State::new_multi`] state.
+    pub(crate) fn walked_roots(&self) -> Vec<&Utf8Path> {
+        if self.root.this.as_str().is_empty() && self.base.is_none() {
+            self.root.entries.iter().map(FsNode::path).collect()
+        } else {
+            vec![self.root.this.as_path()]
+        }
+    }
+
+    /// The tree as loaded from the root, kept current by [`State::move_file`] and friends.
+    pub fn tree(&self) -> &Directory {
+        &self.root
+    }
+
+    /// Build a state around an already-loaded tree, without touching the filesystem.
+    pub fn from_tree(tree: Directory) -> Self {
+        let flat = Directory {
+            this: tree.this.clone(),
+            entries: tree
+                .files()
+                .map(|path| FsNode::File(path.to_owned()))
+                .collect(),
+            lossy: tree
+                .files()
+                .filter(|path| tree.is_lossy(path))
+                .map(Utf8Path::to_owned)
+                .collect(),
+        };
+        Self::from_parts(tree, flat)
+    }
+
+    /// A state with empty trees rooted at `root` and no tracked files, without touching the
+    /// filesystem. Files can still be tracked through [`State::add`] and the mutation methods.
+    pub fn empty(root: Utf8PathBuf) -> Self {
+        Self::from_tree(Directory {
+            this: root,
+            entries: vec![],
+            lossy: BTreeSet::new(),
+        })
+    }
 
-        let (root, flat) = load(root, include)?;
-        Ok(Self {
+    fn from_parts(root: Directory, flat: Directory) -> Self {
+        Self {
+            version: STATE_VERSION,
             root,
             flat,
-            infos: HashSet::new(),
-        })
+            infos: Infos::default(),
+            ops: OpLog::default(),
+            casing: TagCasing::default(),
+            policy: TagPolicy::default(),
+            vocabulary: None,
+            edits: EditLog::default(),
+            journal: None,
+            interner: TagInterner::default(),
+            base: None,
+            include: BTreeSet::new(),
+        }
+    }
+
+    /// Cheap change detection: hashes the sorted set of all paths in the tree (not their contents).
+    /// Identical trees produce identical checksums regardless of walk order. The hash is 64-bit
+    /// FNV-1a, so checksums stay comparable across runs, platforms and Rust releases.
+    pub fn tree_checksum(&self) -> u64 {
+        let paths: BTreeSet<&Utf8Path> = self.root.paths().collect();
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut feed = |path: &Utf8Path| {
+            // the NUL ends each path, so `a` + `bc` and `ab` + `c` differ
+            for &byte in path.as_str().as_bytes().iter().chain(&[0]) {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+        feed(&self.root.this);
+        for path in paths {
+            feed(path);
+        }
+        hash
+    }
+
+    pub fn tag_casing(&self) -> TagCasing {
+        self.casing
+    }
+
+    /// Switch how tags are compared from now on. Existing per-file tags are kept as they are.
+    pub fn set_tag_casing(&mut self, casing: TagCasing) {
+        self.casing = casing;
+    }
+
+    /// Re-establish per-file tag invariants after outside edits: tags sorted and free of
+    /// duplicates under the state's [`TagCasing`], keeping the first spelling seen.
+    pub fn normalize(&mut self) {
+        let casing = self.casing;
+        self.infos = std::mem::take(&mut self.infos)
+            .into_iter()
+            .map(|mut f| {
+                f.tags.sort_by(|a, b| casing.compare(a, b));
+                f.tags.dedup_by(|a, b| casing.matches(a, b));
+                self.interner.intern_info(&mut f);
+                f
+            })
+            .collect();
     }
 
     pub fn tags_filter<P: FnMut(&&FileInfo) -> bool>(
         &self,
         predicate: P,
     ) -> impl Iterator<Item = &Tag> {
+        let casing = self.casing;
         self.infos
             .iter()
             .filter(predicate)
             .flat_map(|f| f.tags())
-            .sorted()
-            .dedup()
+            .sorted_by(move |a, b| casing.compare(a, b))
+            .dedup_by(move |a, b| casing.matches(a, b))
     }
 
+    /// Distinct tags under the state's [`TagCasing`], sorted. Read off the tag index, so this
+    /// doesn't touch the files.
     pub fn tags(&self) -> impl Iterator<Item = &Tag> {
-        self.infos.iter().flat_map(|f| f.tags()).sorted().dedup()
+        let casing = self.casing;
+        self.infos.tags().dedup_by(move |a, b| casing.matches(a, b))
+    }
+
+    /// Write all tags on one line, space separated and colored via [`Tag::ansi`].
+    pub fn print_tags_colored(&self, mut w: impl Write) -> std::io::Result<()> {
+        writeln!(w, "{}", self.tags().map(Tag::ansi).join(" "))
+    }
+
+    /// Give every tag without a color one from `palette`, cycling through it in tag order so
+    /// the same tags get the same colors on every run. Tags that already have a color anywhere
+    /// keep it, and copies of theirs that lack one are filled in with it.
+    pub fn assign_colors(&mut self, palette: &[(u8, u8, u8)]) {
+        if palette.is_empty() {
+            return;
+        }
+        let casing = self.casing;
+        let mut next = palette.iter().cycle();
+        let colors: Vec<(Tag, Color)> = self
+            .tags()
+            .map(|tag| {
+                let existing = self
+                    .infos
+                    .iter()
+                    .flat_map(|f| &f.tags)
+                    .find(|t| t.color.is_some() && casing.matches(t, tag))
+                    .and_then(|t| t.color);
+                let color = existing.unwrap_or_else(|| {
+                    let &(r, g, b) = next.next().expect("palette is not empty");
+                    Color { r, g, b }
+                });
+                (tag.clone(), color)
+            })
+            .collect();
+
+        self.infos = std::mem::take(&mut self.infos)
+            .into_iter()
+            .map(|mut f| {
+                for tag in f.tags.iter_mut().filter(|t| t.color.is_none()) {
+                    tag.color = colors
+                        .iter()
+                        .find(|(t, _)| casing.matches(t, tag))
+                        .map(|(_, color)| *color);
+                }
+                f
+            })
+            .collect();
+    }
+
+    /// Tracked files carrying `tag`, compared according to the state's [`TagCasing`], sorted by
+    /// path. For a namespace like `genre:`, files carrying any tag beneath it. Looked up in the tag
+    /// index, so this costs the number of matches rather than the size of the library.
+    pub fn files_with_tag<'a>(&'a self, tag: &Tag) -> impl Iterator<Item = &'a FileInfo> + 'a {
+        self.infos
+            .paths_covered_by(tag, self.casing)
+            .into_iter()
+            .filter_map(move |path| self.infos.get(path))
+    }
+
+    /// Distinct tags starting with `prefix`, compared caselessly. Sorted like [`State::tags`].
+    pub fn tags_with_prefix<'a>(&'a self, prefix: &str) -> impl Iterator<Item = &'a Tag> + 'a {
+        let prefix = caseless::default_case_fold_str(prefix);
+        self.tags()
+            .filter(move |tag| tag.folded.starts_with(&prefix))
+    }
+
+    /// The info tracked for `path`, if any.
+    pub fn get(&self, path: &Utf8Path) -> Option<&FileInfo> {
+        self.infos.get(path)
+    }
+
+    /// Remove and return the info tracked for `path`, if any.
+    fn take_info(&mut self, path: &Utf8Path) -> Option<FileInfo> {
+        self.infos.take(path)
+    }
+
+    /// How many files carry each tag, sorted in tag order. Tags are bucketed according to the
+    /// state's [`TagCasing`]; each bucket is represented by its most common casing (ties go to
+    /// the lexically smallest spelling).
+    ///
+    /// This is a sorted `Vec` rather than a `BTreeMap<Tag, usize>` on purpose: `Tag`'s `Ord` is
+    /// always caseless, so a map would merge `Rock` and `rock` even under
+    /// [`TagCasing::Sensitive`]. Under the default casing, the order is the same as a map's.
+    pub fn tag_counts(&self) -> Vec<(Tag, usize)> {
+        count_tags(self.infos.iter().flat_map(|f| f.tags()), self.casing)
+    }
+
+    /// Total bytes of the files carrying each tag, e.g. to see what deleting everything tagged
+    /// `scratch` would reclaim. Only files with a known size count, so [`State::stat_all`]
+    /// first. A file with several tags counts towards each. Tags are keyed caselessly.
+    pub fn reclaimable_by_tag(&self) -> BTreeMap<Tag, u64> {
+        let mut totals: BTreeMap<Tag, u64> = BTreeMap::new();
+        for info in &self.infos {
+            if let Some(size) = info.size {
+                for tag in &info.tags {
+                    *totals.entry(tag.clone()).or_default() += size;
+                }
+            }
+        }
+        totals
+    }
+
+    /// [`State::tag_counts`], counted on the rayon thread pool. Worth it for very large states.
+    pub fn par_tag_counts(&self) -> Vec<(Tag, usize)> {
+        let casing = self.casing;
+        let bucket_key = |tag: &Tag| match casing {
+            TagCasing::Insensitive => tag.folded.to_string(),
+            TagCasing::Sensitive => tag.value.to_string(),
+        };
+        // bucket -> spelling -> (a tag with that spelling, count)
+        type Buckets<'a> = HashMap<String, HashMap<&'a str, (&'a Tag, usize)>>;
+        let buckets: Buckets = self
+            .infos
+            .par_iter()
+            .fold(Buckets::new, |mut buckets, info| {
+                for tag in info.tags() {
+                    let spelling = buckets
+                        .entry(bucket_key(tag))
+                        .or_default()
+                        .entry(&tag.value)
+                        .or_insert((tag, 0));
+                    spelling.1 += 1;
+                }
+                buckets
+            })
+            .reduce(Buckets::new, |mut a, b| {
+                for (key, spellings) in b {
+                    let bucket = a.entry(key).or_default();
+                    for (value, (tag, count)) in spellings {
+                        bucket.entry(value).or_insert((tag, 0)).1 += count;
+                    }
+                }
+                a
+            });
+
+        let mut counts: Vec<(Tag, usize)> = buckets
+            .into_values()
+            .map(|spellings| {
+                let total = spellings.values().map(|(_, count)| count).sum();
+                let (_, (tag, _)) = spellings
+                    .into_iter()
+                    .max_by(|a, b| (a.1).1.cmp(&(b.1).1).then_with(|| b.0.cmp(a.0)))
+                    .expect("buckets are never empty");
+                (tag.clone(), total)
+            })
+            .collect();
+        counts.sort_by(|a, b| casing.compare(&a.0, &b.0));
+        counts
+    }
+
+    /// Rename `from` to `to` on disk and carry its tags (and tree entries) over to the new path.
+    /// An untracked `from` is still moved, and tracked afterwards as a fresh untagged file.
+    /// Both are stored paths, resolved through [`State::absolute_path`].
+    ///
+    /// Refuses to replace anything: fails with [`std::io::ErrorKind::AlreadyExists`] if `to`
+    /// exists on disk or is tracked. The move is recorded in the [`OpLog`], but isn't an
+    /// undoable edit, as [`State::undo`] doesn't touch files; the undo history of `from` moves
+    /// along to `to`, so undoing an earlier tag edit changes the file where it now lives.
+    pub fn move_file(&mut self, from: &Utf8Path, to: Utf8PathBuf) -> Result<(), FilepersonError> {
+        let on_disk = self.absolute_path(&to);
+        if self.get(&to).is_some() || std::fs::symlink_metadata(&on_disk).is_ok() {
+            return Err(FilepersonError::File {
+                path: on_disk,
+                source: std::io::ErrorKind::AlreadyExists.into(),
+            });
+        }
+        let from_on_disk = self.absolute_path(from);
+        std::fs::rename(&from_on_disk, &on_disk).map_err(|source| FilepersonError::File {
+            path: from_on_disk,
+            source,
+        })?;
+
+        self.record(Op::MoveFile {
+            from: from.to_owned(),
+            to: to.clone(),
+        });
+        // whatever history `to` had belongs to a file that's gone
+        self.forget_history(&to);
+        self.move_history(from, &to);
+
+        self.root.remove_node(from);
+        if !self.root.insert_node(FsNode::File(to.clone())) {
+            log::debug!("{to:?} is outside of the loaded tree");
+        }
+        self.flat
+            .entries
+            .retain(|node| !matches!(node, FsNode::File(p) if *p == to));
+        match self
+            .flat
+            .entries
+            .iter_mut()
+            .find(|node| matches!(node, FsNode::File(p) if p == from))
+        {
+            Some(node) => *node = FsNode::File(to),
+            None => self.flat.entries.push(FsNode::File(to)),
+        }
+        Ok(())
+    }
+
+    /// Rewrite the `old_root` prefix of every path in the trees and infos to `new_root`, e.g.
+    /// after copying the library to a new drive. Nothing on disk changes. Paths outside of
+    /// `old_root` are left alone. Returns the number of distinct paths changed and the number
+    /// of distinct paths left untouched. The undo history follows the paths to `new_root`.
+    pub fn reroot(&mut self, old_root: &Utf8Path, new_root: &Utf8Path) -> (usize, usize) {
+        let mut changed: HashSet<Utf8PathBuf> = HashSet::new();
+        let mut untouched: HashSet<Utf8PathBuf> = HashSet::new();
+        let mut rewrite = |path: &Utf8Path| match path.strip_prefix(old_root) {
+            Ok(relative) => {
+                changed.insert(path.to_owned());
+                Some(if relative.as_str().is_empty() {
+                    new_root.to_owned()
+                } else {
+                    new_root.join(relative)
+                })
+            }
+            Err(_) => {
+                if !path.as_str().is_empty() {
+                    untouched.insert(path.to_owned());
+                }
+                None
+            }
+        };
+        self.root.rewrite_paths(&mut rewrite);
+        self.flat.rewrite_paths(&mut rewrite);
+        self.infos = std::mem::take(&mut self.infos)
+            .into_iter()
+            .map(|mut info| {
+                if let Some(path) = rewrite(&info.path) {
+                    info.path = path;
+                }
+                info
+            })
+            .collect();
+        self.move_history(old_root, new_root);
+        (changed.len(), untouched.len())
+    }
+
+    /// [`FileInfo::stat`] every tracked file on the rayon thread pool. Returns the files that
+    /// couldn't be statted, e.g. because they were deleted since loading; their metadata is
+    /// left as it was.
+    pub fn stat_all(&mut self) -> Vec<(Utf8PathBuf, std::io::Error)> {
+        let mut infos: Vec<FileInfo> = std::mem::take(&mut self.infos).into_iter().collect();
+        let this = &*self;
+        let mut failed: Vec<(Utf8PathBuf, std::io::Error)> = infos
+            .par_iter_mut()
+            .filter_map(|info| {
+                let on_disk = this.absolute_path(&info.path);
+                info.stat_at(&on_disk).err().map(|e| (info.path.clone(), e))
+            })
+            .collect();
+        self.infos = infos.into_iter().collect();
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        failed
+    }
+
+    /// Check the tracked files against the filesystem without changing anything.
+    pub fn validate(&self) -> ValidationReport {
+        let missing = self
+            .infos
+            .iter()
+            .filter(|f| !self.absolute_path(&f.path).exists())
+            .map(|f| f.path.clone())
+            .sorted()
+            .collect();
+
+        let tracked: HashSet<&Utf8Path> = self.infos.iter().map(|f| f.path.as_path()).collect();
+        let walk = self.loaded_walk();
+        let untracked = self
+            .walked_roots()
+            .into_iter()
+            .flat_map(|root| WalkDir::new(self.absolute_path(root)))
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| Utf8PathBuf::from_path_buf(entry.into_path()).ok())
+            .filter(|path| walk.included(path))
+            .map(|path| match &self.base {
+                Some(base) => path
+                    .strip_prefix(base)
+                    .map_or(path.clone(), Utf8Path::to_owned),
+                None => path,
+            })
+            .filter(|path| !tracked.contains(path.as_path()))
+            .sorted()
+            .collect();
+
+        ValidationReport { missing, untracked }
+    }
+
+    /// [`State::tag_counts`] ordered by descending count; ties keep tag order.
+    pub fn tag_histogram(&self) -> Vec<(Tag, usize)> {
+        let mut histogram = self.tag_counts();
+        histogram.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        histogram
+    }
+
+    /// Other tags on the files carrying `tag`, with how many of those files carry them, ordered
+    /// by descending count (ties keep tag order). `tag` itself is left out.
+    pub fn cooccurrences(&self, tag: &Tag) -> Vec<(Tag, usize)> {
+        let casing = self.casing;
+        let others = self
+            .files_with_tag(tag)
+            .flat_map(|f| f.tags())
+            .filter(|t| !casing.matches(t, tag));
+        let mut counts = count_tags(others, casing);
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts
+    }
+
+    /// The `n` most used tags, see [`State::tag_histogram`].
+    pub fn top_n(&self, n: usize) -> Vec<(Tag, usize)> {
+        let mut histogram = self.tag_histogram();
+        histogram.truncate(n);
+        histogram
+    }
+
+    /// Tracked files matching `predicate`, e.g. on their [`FileInfo::audio`] metadata.
+    pub fn files_where<'a>(
+        &'a self,
+        predicate: impl FnMut(&&FileInfo) -> bool + 'a,
+    ) -> impl Iterator<Item = &'a FileInfo> + 'a {
+        self.infos.iter().filter(predicate)
+    }
+
+    /// Tracked files that don't carry any tags yet.
+    pub fn untagged(&self) -> impl Iterator<Item = &FileInfo> {
+        self.infos.iter().filter(|f| f.tags.is_empty())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let tagged = self.infos.iter().filter(|f| !f.tags.is_empty()).count();
+        Stats {
+            files: self.infos.len(),
+            tagged,
+            untagged: self.infos.len() - tagged,
+            marked_for_deletion: self.infos.iter().filter(|f| f.delete == Some(true)).count(),
+            distinct_tags: self.tags().count(),
+            warnings: self.warnings().len(),
+        }
+    }
+
+    /// Touched files (tagged or marked) that have no preview yet.
+    pub fn previews_missing(&self) -> impl Iterator<Item = &FileInfo> {
+        self.infos
+            .iter()
+            .filter(|f| f.touched() && f.preview.is_none())
+    }
+
+    /// Every file in the tree, tracked or not, in tree order, followed by tracked files outside
+    /// of the tree sorted by path. Nothing is statted until [`FileRow::size`] is asked for.
+    pub fn file_rows(&self) -> impl Iterator<Item = FileRow<'_>> + '_ {
+        let mut by_path: HashMap<&Utf8Path, &FileInfo> =
+            self.infos.iter().map(|f| (f.path.as_path(), f)).collect();
+        let in_tree: Vec<(&Utf8Path, Option<&FileInfo>)> = self
+            .root
+            .files()
+            .map(|path| (path, by_path.remove(path)))
+            .collect();
+        let outside = by_path
+            .into_values()
+            .sorted_by_key(|f| &f.path)
+            .map(|f| (f.path.as_path(), Some(f)));
+        in_tree
+            .into_iter()
+            .chain(outside)
+            .map(move |(path, info)| FileRow {
+                path,
+                tags: info.map(|f| f.tags.as_slice()).unwrap_or_default(),
+                delete: info.and_then(|f| f.delete),
+                known_size: info.and_then(|f| f.size),
+                on_disk: self.absolute_path(path),
+                size: OnceCell::new(),
+            })
+    }
+
+    /// The files of `flat` that were tagged or given a delete decision, natord-sorted, e.g. for a
+    /// review screen.
+    pub fn touched_flat(&self) -> Directory {
+        let entries = self
+            .flat
+            .entries
+            .iter()
+            .filter(|node| self.get(node.path()).is_some_and(FileInfo::touched))
+            .cloned()
+            .collect::<Vec<_>>();
+        let lossy = self
+            .flat
+            .lossy
+            .iter()
+            .filter(|path| entries.iter().any(|node| node.path() == path.as_path()))
+            .cloned()
+            .collect();
+        Directory {
+            this: self.flat.this.clone(),
+            entries,
+            lossy,
+        }
+        .sorted()
+    }
+
+    /// Tracked infos whose path isn't a file in the tree, e.g. stale entries after
+    /// [`State::reroot`] or an [`State::add`] from elsewhere. Sorted by path.
+    pub fn orphans(&self) -> Vec<&FileInfo> {
+        self.infos
+            .iter()
+            .filter(|f| !matches!(self.root.get(&f.path), Some(FsNode::File(_))))
+            .sorted_by(|a, b| a.path.cmp(&b.path))
+            .collect()
     }
 
-    pub fn add(&mut self, f: FileInfo) -> anyhow::Result<()> {
-        // let tag = caseless::default_case_fold_str("s");
-        // let mut f = std::fs::File::open("/tmp/test.txt")?;
-        self.infos.insert(f);
+    /// Tracked files grouped by their parent directory, each group sorted by path. Directories
+    /// without tracked files don't appear.
+    pub fn by_directory(&self) -> BTreeMap<Utf8PathBuf, Vec<&FileInfo>> {
+        let mut groups: BTreeMap<Utf8PathBuf, Vec<&FileInfo>> = BTreeMap::new();
+        for info in &self.infos {
+            let parent = info.path.parent().unwrap_or_else(|| Utf8Path::new(""));
+            groups.entry(parent.to_owned()).or_default().push(info);
+        }
+        for files in groups.values_mut() {
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        groups
+    }
+
+    /// Files in `root` that aren't tracked in `infos` at all.
+    pub fn untouched_files<'a>(
+        &'a self,
+        root: &'a Directory,
+    ) -> impl Iterator<Item = &'a Utf8Path> + 'a {
+        let tracked: HashSet<&Utf8Path> = self.infos.iter().map(|f| f.path.as_path()).collect();
+        root.files().filter(move |path| !tracked.contains(path))
+    }
+
+    /// All questionable states across the tracked files, sorted by path.
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.infos
+            .iter()
+            .filter(|f| f.questionable_state())
+            .map(|f| f.path.clone())
+            .sorted()
+            .map(Warning::DeleteWithTags)
+            .collect()
+    }
+
+    /// Track `f`, replacing whatever was tracked for its path. Fails without changing anything
+    /// if any of its tags is rejected by the [`TagPolicy`] or vocabulary.
+    pub fn add(&mut self, mut f: FileInfo) -> Result<(), FilepersonError> {
+        for tag in &f.tags {
+            self.check_tag(tag)?;
+        }
+        self.interner.intern_info(&mut f);
+        self.infos.replace(f);
         Ok(())
     }
 }
 
+/// [`State::empty`] rooted at the current directory, or `.` if that isn't available as UTF-8.
+impl Default for State {
+    fn default() -> Self {
+        let cwd = std::env::current_dir()
+            .ok()
+            .and_then(|dir| Utf8PathBuf::from_path_buf(dir).ok())
+            .unwrap_or_else(|| ".".into());
+        Self::empty(cwd)
+    }
+}
+
+/// Bucket `tags` according to `casing`, see [`State::tag_counts`].
+fn count_tags<'a>(tags: impl Iterator<Item = &'a Tag>, casing: TagCasing) -> Vec<(Tag, usize)> {
+    let mut all: Vec<&Tag> = tags.collect();
+    all.sort_by(|a, b| casing.compare(a, b));
+
+    let mut counts = vec![];
+    let mut rest = &all[..];
+    while let Some(first) = rest.first() {
+        let len = rest.iter().take_while(|t| casing.matches(first, t)).count();
+        let (bucket, tail) = rest.split_at(len);
+        counts.push((common_casing(bucket), len));
+        rest = tail;
+    }
+    counts
+}
+
+/// The most common spelling among caselessly-equal tags.
+fn common_casing(bucket: &[&Tag]) -> Tag {
+    let mut casings: HashMap<&str, usize> = HashMap::new();
+    for tag in bucket {
+        *casings.entry(&*tag.value).or_default() += 1;
+    }
+    let (value, _) = casings
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+        .expect("buckets are never empty");
+    let spelled = bucket
+        .iter()
+        .find(|tag| &*tag.value == value)
+        .expect("spellings come from the bucket");
+    Tag {
+        color: bucket[0].color,
+        description: bucket[0].description.clone(),
+        ..(*spelled).clone()
+    }
+}
+
+/// [`State::add`]s each info; those with a rejected tag are logged and skipped.
 impl Extend<FileInfo> for State {
     fn extend<T: IntoIterator<Item = FileInfo>>(&mut self, iter: T) {
-        self.infos.extend(iter);
+        for info in iter {
+            let path = info.path.clone();
+            if let Err(e) = self.add(info) {
+                log::warn!("not tracking {path}: {e}");
+            }
+        }
     }
 }
 #[cfg(test)]
 mod tests {
 
+    use std::collections::hash_map::DefaultHasher;
+
     use anyhow::anyhow;
     use directories::UserDirs;
     use lipsum::{MarkovChain, LIBER_PRIMUS, LOREM_IPSUM};
-    use rand::{prelude::SliceRandom, Rng};
+    use rand::Rng;
 
     use super::*;
-    #[test]
-    fn test_rc() {}
 
-    #[test]
-    fn test_tags() -> anyhow::Result<()> {
-        let mut rng = rand::thread_rng();
-        let mut chain = MarkovChain::new();
+    /// A `State` with an empty tree rooted at `/music`, for tests that don't need the filesystem.
+    pub(crate) fn state_fixture() -> State {
+        State::empty("/music".into())
+    }
+
+    /// `/music` with `a.wav`, `b.wav` and `sub/c.wav`.
+    pub(crate) fn tree_fixture() -> Directory {
+        Directory {
+            this: "/music".into(),
+            entries: vec![
+                FsNode::File("/music/a.wav".into()),
+                FsNode::File("/music/b.wav".into()),
+                FsNode::Directory(Directory {
+                    this: "/music/sub".into(),
+                    entries: vec![FsNode::File("/music/sub/c.wav".into())],
+                    lossy: BTreeSet::new(),
+                }),
+            ],
+            lossy: BTreeSet::new(),
+        }
+    }
+
+    /// Everything a user decided about the tracked files, with exact tag casing, sorted by path.
+    pub(crate) fn snapshot(state: &State) -> Vec<(Utf8PathBuf, Vec<String>, Option<bool>)> {
+        state
+            .infos
+            .iter()
+            .map(|f| {
+                (
+                    f.path.clone(),
+                    f.tags.iter().map(|t| t.value.to_string()).collect(),
+                    f.delete,
+                )
+            })
+            .sorted()
+            .collect()
+    }
+
+    fn hash_of(value: &impl Hash) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Counts allocations per thread, so tests running in parallel don't disturb each other.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    fn allocations_during(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    #[test]
+    fn test_rc() {}
+
+    #[test]
+    fn test_tag_fold_cache() {
+        let spellings = [
+            "rock", "Rock", "ROCK", "Straße", "STRASSE", "jazz", "Ǆ", "ǆ",
+        ];
+        let tags: Vec<Tag> = spellings.iter().map(|s| Tag::from(*s)).collect();
+        for (a, ta) in spellings.iter().zip(&tags) {
+            for (b, tb) in spellings.iter().zip(&tags) {
+                let same = caseless::default_caseless_match_str(a, b);
+                assert_eq!(ta == tb, same, "{a} vs {b}");
+                assert_eq!(hash_of(ta) == hash_of(tb), same, "{a} vs {b}");
+                let folded_order =
+                    caseless::default_case_fold_str(a).cmp(&caseless::default_case_fold_str(b));
+                assert_eq!(ta.cmp(tb), folded_order, "{a} vs {b}");
+            }
+        }
+        let json = serde_json::to_string(&tags[3]).unwrap();
+        let back: Tag = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, tags[4]);
+        assert_eq!(hash_of(&back), hash_of(&tags[4]));
+
+        // Rough benchmark: 64 comparisons and hashes, cached versus folding on every call.
+        let cached = allocations_during(|| {
+            for a in &tags {
+                for b in &tags {
+                    std::hint::black_box((a == b, a.cmp(b), hash_of(a)));
+                }
+            }
+        });
+        let refolded = allocations_during(|| {
+            for a in &spellings {
+                for b in &spellings {
+                    std::hint::black_box(
+                        caseless::default_case_fold_str(a) == caseless::default_case_fold_str(b),
+                    );
+                }
+            }
+        });
+        assert_eq!(cached, 0);
+        assert!(
+            refolded >= 2 * spellings.len() * spellings.len(),
+            "{}",
+            refolded
+        );
+    }
+
+    #[test]
+    fn test_file_info_eq_ignores_metadata() {
+        let mut a = FileInfo::from("/music/a.wav");
+        a.add_tag("rock");
+        let mut b = a.clone();
+        a.size = Some(10);
+        a.modified = Some(SystemTime::UNIX_EPOCH);
+        b.size = Some(20);
+        b.modified = Some(SystemTime::now());
+
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+    }
+
+    #[test]
+    fn test_file_info_set_is_keyed_by_path() {
+        let path = Utf8Path::new("/music/a.wav");
+        let mut infos = HashSet::new();
+        infos.insert(FileInfo::from(path));
+
+        let mut info = infos.take(path).unwrap();
+        info.add_tag("rock");
+        info.set_delete(Some(true));
+        infos.insert(info);
+        assert_eq!(infos.get(path).map(|f| f.tags().len()), Some(1));
+        assert!(!infos.insert(FileInfo::from(path)));
+        assert_eq!(infos.len(), 1);
+    }
+
+    #[test]
+    fn test_directory_get() {
+        let tree = tree_fixture();
+        assert!(matches!(
+            tree.get("/music/sub/c.wav".into()),
+            Some(FsNode::File(p)) if p == "/music/sub/c.wav"
+        ));
+        assert!(matches!(
+            tree.get("/music/sub".into()),
+            Some(FsNode::Directory(_))
+        ));
+        assert!(tree.get("/music/sub/missing.wav".into()).is_none());
+        assert!(tree.get("/elsewhere/a.wav".into()).is_none());
+    }
+
+    #[test]
+    fn test_orphans() -> anyhow::Result<()> {
+        let mut state = State::from_tree(tree_fixture());
+        state.add_tag("/music/sub/c.wav", "rock")?;
+        state.add(FileInfo::from("/elsewhere/x.wav"))?;
+        state.add_tag("/music/sub", "folder")?;
+
+        let orphans: Vec<&Utf8Path> = state.orphans().into_iter().map(FileInfo::path).collect();
+        assert_eq!(orphans, vec!["/elsewhere/x.wav", "/music/sub"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_touched_flat() {
+        let mut state = State::from_tree(tree_fixture());
+        state.add_tag("/music/sub/c.wav", "rock").unwrap();
+        state.set_delete("/music/a.wav", Some(true));
+        state.add(FileInfo::from("/music/b.wav")).unwrap();
+
+        let touched = state.touched_flat();
+        assert_eq!(touched.this, "/music");
+        assert_eq!(
+            touched.files().collect::<Vec<_>>(),
+            vec!["/music/a.wav", "/music/sub/c.wav"]
+        );
+    }
+
+    #[test]
+    fn test_cooccurrences() {
+        let mut state = state_fixture();
+        for (path, tags) in [
+            ("/music/a.wav", &["rock", "live", "demo"][..]),
+            ("/music/b.wav", &["Rock", "live"]),
+            ("/music/c.wav", &["rock", "live", "stems"]),
+            ("/music/d.wav", &["rock", "demo"]),
+            ("/music/e.wav", &["jazz", "live", "stems"]),
+        ] {
+            for tag in tags {
+                state.add_tag(path, *tag).unwrap();
+            }
+        }
+        assert_eq!(
+            state.cooccurrences(&"ROCK".into()),
+            vec![("live".into(), 3), ("demo".into(), 2), ("stems".into(), 1)]
+        );
+        assert_eq!(
+            state.cooccurrences(&"live".into())[0].0.value.as_ref(),
+            "rock"
+        );
+    }
+
+    #[test]
+    fn test_tree_checksum() -> anyhow::Result<()> {
+        let dir = tempdir::TempDir::new("fileperson")?;
+        let root = Utf8Path::from_path(dir.path()).ok_or(anyhow!("temp dir is not utf-8"))?;
+        std::fs::create_dir(root.join("sub"))?;
+        std::fs::write(root.join("a.wav"), "")?;
+        std::fs::write(root.join("sub/b.wav"), "")?;
+
+        let first = State::new(root, HashSet::from(["wav"]))?;
+        let second = State::new(root, HashSet::from(["wav"]))?;
+        assert_eq!(first.tree_checksum(), second.tree_checksum());
+
+        std::fs::write(root.join("sub/c.wav"), "")?;
+        let changed = State::new(root, HashSet::from(["wav"]))?;
+        assert_ne!(first.tree_checksum(), changed.tree_checksum());
+
+        // pinned, so a checksum kept from an earlier scan still compares
+        assert_eq!(
+            State::from_tree(tree_fixture()).tree_checksum(),
+            0xae80_9218_558c_1844
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_counts() {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.add_tag("/music/a.wav", "live").unwrap();
+        state.add_tag("/music/b.wav", "Rock").unwrap();
+        state.add_tag("/music/b.wav", "jazz").unwrap();
+        state.add_tag("/music/c.wav", "Rock").unwrap();
+        state.add_tag("/music/c.wav", "live").unwrap();
+
+        let counts: Vec<(String, usize)> = state
+            .tag_counts()
+            .into_iter()
+            .map(|(tag, count)| (tag.to_string(), count))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("jazz".to_string(), 1),
+                ("live".to_string(), 2),
+                ("Rock".to_string(), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tags_with_prefix() {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "Rock").unwrap();
+        state.add_tag("/music/a.wav", "Jazz").unwrap();
+        state.add_tag("/music/b.wav", "rock").unwrap();
+        state.add_tag("/music/b.wav", "robot").unwrap();
+
+        let suggestions: Vec<String> = state
+            .tags_with_prefix("RO")
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(suggestions.len(), 2);
+        assert_eq!(suggestions[0], "robot");
+        assert!(suggestions[1].eq_ignore_ascii_case("rock"));
+        assert_eq!(state.tags_with_prefix("").count(), 3);
+    }
+
+    #[test]
+    fn test_tag_histogram() {
+        let mut state = state_fixture();
+        for file in ["a", "b", "c"] {
+            state.add_tag(format!("/music/{file}.wav"), "rock").unwrap();
+        }
+        state.add_tag("/music/a.wav", "live").unwrap();
+        state.add_tag("/music/b.wav", "jazz").unwrap();
+        state.add_tag("/music/c.wav", "ambient").unwrap();
+
+        let histogram: Vec<(String, usize)> = state
+            .tag_histogram()
+            .into_iter()
+            .map(|(t, c)| (t.to_string(), c))
+            .collect();
+        assert_eq!(
+            histogram,
+            vec![
+                ("rock".into(), 3),
+                ("ambient".into(), 1),
+                ("jazz".into(), 1),
+                ("live".into(), 1)
+            ]
+        );
+        assert_eq!(state.top_n(2).len(), 2);
+        assert_eq!(state.top_n(2)[1].0.to_string(), "ambient");
+    }
+
+    #[test]
+    fn test_tag_casing() {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "Live").unwrap();
+        state.add_tag("/music/b.wav", "live").unwrap();
+        assert_eq!(state.tags().count(), 1);
+        assert_eq!(state.tag_counts().len(), 1);
+        assert_eq!(state.files_with_tag(&Tag::from("LIVE")).count(), 2);
+
+        state.set_tag_casing(TagCasing::Sensitive);
+        let tags: Vec<String> = state.tags().map(|t| t.to_string()).collect();
+        assert_eq!(tags, vec!["Live", "live"]);
+        let counts: Vec<(String, usize)> = state
+            .tag_counts()
+            .into_iter()
+            .map(|(t, c)| (t.to_string(), c))
+            .collect();
+        assert_eq!(counts, vec![("Live".into(), 1), ("live".into(), 1)]);
+        assert_eq!(state.files_with_tag(&Tag::from("live")).count(), 1);
+
+        assert!(state.add_tag("/music/a.wav", "live").unwrap());
+        assert_eq!(state.files_with_tag(&Tag::from("live")).count(), 2);
+    }
+
+    #[test]
+    fn test_tag_ansi() {
+        let red = "#ff0010".parse::<Color>().unwrap();
+        assert_eq!(
+            red,
+            Color {
+                r: 255,
+                g: 0,
+                b: 16
+            }
+        );
+        assert_eq!(red.to_string(), "#ff0010");
+        assert!("#ff001".parse::<Color>().is_err());
+
+        assert_eq!(
+            Tag::from("rock").with_color(red).ansi(),
+            "\x1b[38;2;255;0;16mrock\x1b[0m"
+        );
+        assert_eq!(Tag::from("jazz").ansi(), "jazz");
+    }
+
+    #[test]
+    fn test_tag_trims_whitespace() {
+        let tag = Tag::from("  Rock \t\n");
+        assert_eq!(&*tag.value, "Rock");
+        assert_eq!(tag.to_string(), "Rock");
+        assert_eq!(tag, Tag::from("rock"));
+    }
+
+    #[test]
+    fn test_tag_collapses_whitespace() {
+        let tag: Tag = "drum  \t and\n\nbass".parse().unwrap();
+        assert_eq!(tag.to_string(), "drum and bass");
+        assert_eq!(Tag::from("a b").to_string(), "a b");
+    }
+
+    #[test]
+    fn test_tag_description_ignored_by_eq() {
+        let stems = Tag::from("stems").with_description("isolated instrument tracks");
+        let other = Tag::from("Stems").with_description("something else");
+        assert_eq!(stems.description(), Some("isolated instrument tracks"));
+        assert_eq!(Tag::from("stems").description(), None);
+        assert_eq!(stems, other);
+        assert_eq!(stems.cmp(&other), std::cmp::Ordering::Equal);
+        assert_eq!(hash_of(&stems), hash_of(&other));
+        assert_eq!(hash_of(&stems), hash_of(&Tag::from("stems")));
+    }
+
+    #[test]
+    fn test_tag_description_serde() -> anyhow::Result<()> {
+        let tag: Tag = serde_json::from_str(r#"{"color":null,"value":"stems"}"#)?;
+        assert_eq!(tag.description(), None);
+        let tag = tag.with_description("isolated instrument tracks");
+        let round_trip: Tag = serde_json::from_str(&serde_json::to_string(&tag)?)?;
+        assert_eq!(round_trip.description(), Some("isolated instrument tracks"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hierarchical_tags() {
+        let tag = Tag::from("Genre:Techno/Minimal");
+        assert_eq!(
+            tag.segments().collect::<Vec<_>>(),
+            vec!["Genre", "Techno", "Minimal"]
+        );
+        assert_eq!(tag.parent(), Some(Tag::from("genre:techno/")));
+        assert_eq!(tag.parent().unwrap().parent(), Some(Tag::from("genre:")));
+        assert_eq!(Tag::from("genre:").parent(), None);
+        assert_eq!(
+            Tag::from("genre:").segments().collect::<Vec<_>>(),
+            vec!["genre"]
+        );
+        assert!(!tag.is_namespace());
+
+        let genre = Tag::from("genre:");
+        assert!(genre.is_namespace());
+        assert!(genre.covers(&tag));
+        assert!(genre.covers(&"GENRE:house".into()));
+        assert!(!genre.covers(&"genre".into()));
+        assert!(!genre.covers(&"genres:house".into()));
+        assert!(!Tag::from("genre").covers(&tag));
+        assert!(!TagCasing::Sensitive.covers(&genre, &tag));
+
+        let mut state = tests::state_fixture();
+        state.add_tag("/music/a.wav", "genre:techno").unwrap();
+        state.add_tag("/music/b.wav", "mood/dark").unwrap();
+        state.add_tag("/music/c.wav", "genre").unwrap();
+        let genre_files: Vec<_> = state.files_with_tag(&genre).map(FileInfo::path).collect();
+        assert_eq!(genre_files, vec![Utf8Path::new("/music/a.wav")]);
+    }
+
+    #[test]
+    fn test_tag_color_serde() -> anyhow::Result<()> {
+        let tag = Tag::from("rock").with_color("#ff0010".parse()?);
+        let json = serde_json::to_value(&tag)?;
+        assert_eq!(json["color"], "#ff0010");
+        let round_trip: Tag = serde_json::from_value(json)?;
+        assert_eq!(round_trip.color(), tag.color());
+
+        let legacy: Tag = serde_json::from_str(r#"{"color":"00FF00","value":"jazz"}"#)?;
+        assert_eq!(legacy.color(), Some(Color { r: 0, g: 255, b: 0 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_rejects_malformed_color() {
+        let err = serde_json::from_str::<Tag>(r#"{"color":"red","value":"rock"}"#).unwrap_err();
+        assert!(
+            err.to_string().contains(r#"invalid hex color "red""#),
+            "{}",
+            err
+        );
+        let state = r##"{
+            "version": 1,
+            "root": {"this": "/music", "entries": []},
+            "flat": {"this": "/music", "entries": []},
+            "infos": [{"path": "/music/a.wav", "delete": null,
+                       "tags": [{"color": "#12345", "value": "rock"}]}]
+        }"##;
+        assert!(State::load_from(state.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_render_tree() -> anyhow::Result<()> {
+        let mut tree = tree_fixture();
+        if let FsNode::Directory(sub) = &mut tree.entries[2] {
+            sub.entries.push(FsNode::Directory(Directory {
+                this: "/music/sub/deep".into(),
+                entries: vec![FsNode::File("/music/sub/deep/d.wav".into())],
+                lossy: BTreeSet::new(),
+            }));
+        }
+        tree.entries.push(FsNode::File("/music/z.wav".into()));
+
+        let mut out = vec![];
+        tree.render_tree(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "/music\n\
+             ├── a.wav\n\
+             ├── b.wav\n\
+             ├── sub\n\
+             │   ├── c.wav\n\
+             │   └── deep\n\
+             │       └── d.wav\n\
+             └── z.wav\n"
+        );
+
+        let mut state = State::from_tree(tree.clone());
+        state.add_tag("/music/a.wav", "rock")?;
+        state.add_tag("/music/a.wav", "live")?;
+        state.add_tag("/music/sub/deep/d.wav", "jazz")?;
+        let mut out = vec![];
+        tree.render_tree_with_tags(&mut out, &state)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "/music\n\
+             ├── a.wav [live, rock]\n\
+             ├── b.wav\n\
+             ├── sub\n\
+             │   ├── c.wav\n\
+             │   └── deep\n\
+             │       └── d.wav [jazz]\n\
+             └── z.wav\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_assign_colors() {
+        const PALETTE: &[(u8, u8, u8)] = &[(255, 0, 0), (0, 255, 0)];
+        let colors = |state: &State| -> Vec<(String, Option<String>)> {
+            state
+                .infos
+                .iter()
+                .flat_map(|f| &f.tags)
+                .map(|t| (t.value.to_string(), t.color().map(|c| c.to_string())))
+                .sorted()
+                .collect()
+        };
+
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.add_tag("/music/a.wav", "jazz").unwrap();
+        state.add_tag("/music/b.wav", "Rock").unwrap();
+        state
+            .add_tag(
+                "/music/b.wav",
+                Tag::from("live").with_color("#0000ff".parse().unwrap()),
+            )
+            .unwrap();
+        state.add_tag("/music/c.wav", "live").unwrap();
+        state.assign_colors(PALETTE);
+
+        let expected = vec![
+            ("Rock".to_string(), Some("#00ff00".to_string())),
+            ("jazz".to_string(), Some("#ff0000".to_string())),
+            ("live".to_string(), Some("#0000ff".to_string())),
+            ("live".to_string(), Some("#0000ff".to_string())),
+            ("rock".to_string(), Some("#00ff00".to_string())),
+        ];
+        assert_eq!(colors(&state), expected);
+
+        state.assign_colors(PALETTE);
+        assert_eq!(colors(&state), expected);
+    }
+
+    #[test]
+    fn test_print_tags_colored() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        state.add_tag(
+            "/music/a.wav",
+            Tag::from("rock").with_color("00ff00".parse()?),
+        )?;
+        state.add_tag("/music/a.wav", "jazz")?;
+        let mut out = vec![];
+        state.print_tags_colored(&mut out)?;
+        assert_eq!(out, b"jazz \x1b[38;2;0;255;0mrock\x1b[0m\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_names() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a/song.mp3", "b/Song.mp3", "b/other.mp3", "song.wav"])?;
+        let (tree, _flat) = load(&root, HashSet::from(["mp3"]))?;
+        let duplicates = tree.duplicate_names();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(
+            duplicates["song.mp3"],
+            vec![root.join("a/song.mp3"), root.join("b/Song.mp3")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_tree() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[])?;
+        std::fs::create_dir_all(root.join("sub/empty"))?;
+        std::fs::write(root.join("a.wav"), [0; 10])?;
+        std::fs::write(root.join("sub/b.wav"), [0; 100])?;
+        std::fs::write(root.join("sub/c.wav"), [0; 1000])?;
+        let (tree, _flat) = load(&root, HashSet::from(["wav"]))?;
+
+        assert_eq!(tree.total_size()?, 1110);
+        let sizes = tree.size_tree(MetadataErrorPolicy::Fail)?;
+        assert_eq!(sizes.children.len(), 1);
+        assert_eq!(sizes.children[0].size, 1100);
+        assert_eq!(sizes.children[0].children[0].size, 0);
+
+        std::fs::remove_file(root.join("sub/c.wav"))?;
+        assert!(tree.total_size().is_err());
+        assert_eq!(tree.total_size_with(MetadataErrorPolicy::Skip)?, 110);
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_relative_files() {
+        let tree = tree_fixture();
+        let expected: Vec<Utf8PathBuf> = vec!["a.wav".into(), "b.wav".into(), "sub/c.wav".into()];
+        assert_eq!(tree.relative_files("/music".into()).unwrap(), expected);
+        assert!(tree.relative_files("/music/sub".into()).is_err());
+    }
+
+    #[test]
+    fn test_directory_walk_depths() {
+        let mut tree = tree_fixture();
+        tree.entries.push(FsNode::Directory(Directory {
+            this: "/music/zz".into(),
+            entries: vec![FsNode::Directory(Directory {
+                this: "/music/zz/deeper".into(),
+                entries: vec![FsNode::File("/music/zz/deeper/d.wav".into())],
+                lossy: BTreeSet::new(),
+            })],
+            lossy: BTreeSet::new(),
+        }));
+        let walked: Vec<(usize, &str)> = tree
+            .walk()
+            .map(|(depth, node)| (depth, node.path().as_str()))
+            .collect();
+        assert_eq!(
+            walked,
+            vec![
+                (0, "/music/a.wav"),
+                (0, "/music/b.wav"),
+                (0, "/music/sub"),
+                (1, "/music/sub/c.wav"),
+                (0, "/music/zz"),
+                (1, "/music/zz/deeper"),
+                (2, "/music/zz/deeper/d.wav"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directory_count() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav", "sub/deep/c.wav"])?;
+        std::fs::create_dir_all(root.join("empty/nested"))?;
+        let (tree, _) = load(&root, HashSet::from(["wav"]))?;
+        assert_eq!(
+            tree.count(),
+            NodeCounts {
+                files: 3,
+                directories: 4
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_empty() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[
+            "a.wav",
+            "drums/kick.wav",
+            "docs/readme.txt",
+            "docs/more/notes.txt",
+        ])?;
+        std::fs::create_dir_all(root.join("drums/empty"))?;
+        let (mut tree, _) = load_with_globs(&root, &["**/*.wav"])?;
+        assert_eq!(tree.count().directories, 4);
+
+        tree.prune_empty();
+        let paths: Vec<String> = tree
+            .paths()
+            .map(|p| p.strip_prefix(&root).unwrap().to_string())
+            .collect();
+        assert_eq!(paths, vec!["a.wav", "drums", "drums/kick.wav"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_sorted() {
+        let file = |path: &str| FsNode::File(path.into());
+        let flat = Directory {
+            this: "/music".into(),
+            entries: vec![
+                file("/music/take10.wav"),
+                file("/music/sub/B.wav"),
+                file("/music/take2.wav"),
+                file("/music/a.wav"),
+                file("/music/sub/a.wav"),
+            ],
+            lossy: BTreeSet::new(),
+        };
+        let mut expected: Vec<&Utf8Path> = flat.files().collect();
+        expected.sort_by(|a, b| natord::compare_ignore_case(a.as_str(), b.as_str()));
+
+        let sorted = flat.sorted();
+        assert_eq!(sorted.files().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            expected,
+            vec![
+                "/music/a.wav",
+                "/music/sub/a.wav",
+                "/music/sub/B.wav",
+                "/music/take2.wav",
+                "/music/take10.wav"
+            ]
+        );
+
+        let mut in_place = flat.clone();
+        in_place.sort_in_place();
+        assert!(in_place.files().eq(sorted.files()));
+    }
+
+    #[test]
+    fn test_file_rows() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav", "sub/c.wav"])?;
+        std::fs::write(root.join("sub/c.wav"), "12345")?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("a.wav"), "rock")?;
+        state.set_delete(root.join("sub/b.wav"), Some(true));
+        state.add_tag("/elsewhere/x.wav", "live")?;
+
+        let rows: Vec<FileRow> = state.file_rows().collect();
+        let paths: Vec<&Utf8Path> = rows.iter().map(|row| row.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                root.join("a.wav").as_path(),
+                &root.join("sub/b.wav"),
+                &root.join("sub/c.wav"),
+                Utf8Path::new("/elsewhere/x.wav"),
+            ]
+        );
+        assert_eq!(rows[0].tags, &[Tag::from("rock")]);
+        assert_eq!(rows[1].delete, Some(true));
+        assert!(rows[2].tags.is_empty());
+        assert_eq!(rows[2].size(), Some(5));
+        assert_eq!(rows[3].size(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_state() -> anyhow::Result<()> {
+        let mut state = State::empty("/library".into());
+        assert_eq!(state.root.count(), NodeCounts::default());
+        assert_eq!(state.stats(), Stats::default());
+
+        state.add(
+            FileInfo::builder()
+                .path("/library/a.wav")
+                .tag("rock")
+                .build()?,
+        )?;
+        assert_eq!(
+            snapshot(&state),
+            vec![("/library/a.wav".into(), vec!["rock".to_string()], None)]
+        );
+
+        let default = State::default();
+        assert!(!default.root.this.as_str().is_empty());
+        assert!(default.infos.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_canonicalizes_root() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        for given in [format!("{root}/."), format!("{root}/sub/..")] {
+            let (tree, flat) = load(&given, HashSet::from(["wav"]))?;
+            assert_eq!(tree.this, root);
+            assert_eq!(
+                flat.files().collect::<Vec<_>>(),
+                vec![root.join("a.wav"), root.join("sub/b.wav")]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_single_file() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "b.wav"])?;
+        let file = root.join("b.wav");
+        for (tree, flat) in [
+            load(&file, HashSet::from(["wav"]))?,
+            load_with_globs(&file, &["*.mp3"])?,
+        ] {
+            assert_eq!(tree.this, root);
+            assert_eq!(tree.files().collect::<Vec<_>>(), vec![&file]);
+            assert_eq!(tree.count().directories, 0);
+            assert_eq!(flat.files().collect::<Vec<_>>(), vec![&file]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_collects_skipped() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.mp3", "notes.txt", "sub/B.MP3", "sub/cover.jpg"])?;
+        let options = LoadOptions {
+            collect_skipped: true,
+            ..LoadOptions::default()
+        };
+        let loaded = load_with_options(&root, HashSet::from(["mp3"]), &options)?;
+        assert_eq!(
+            loaded.flat.files().collect::<Vec<_>>(),
+            vec![root.join("a.mp3"), root.join("sub/B.MP3")]
+        );
+        assert_eq!(
+            loaded.skipped,
+            vec![root.join("notes.txt"), root.join("sub/cover.jpg")]
+        );
+        assert!(loaded.errors.is_empty());
+
+        let quiet = load_with_options(&root, HashSet::from(["mp3"]), &LoadOptions::default())?;
+        assert!(quiet.skipped.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_sort_mode() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["file10.wav", "file2.wav", "File3.wav"])?;
+        let names = |sort| -> anyhow::Result<Vec<String>> {
+            let options = LoadOptions {
+                sort,
+                ..LoadOptions::default()
+            };
+            let loaded = load_with_options(&root, HashSet::from(["wav"]), &options)?;
+            Ok(relative_files(&loaded.flat, &root))
+        };
+        assert_eq!(
+            names(SortMode::NaturalIgnoreCase)?,
+            vec!["file2.wav", "File3.wav", "file10.wav"]
+        );
+        assert_eq!(
+            names(SortMode::Natural)?,
+            vec!["File3.wav", "file2.wav", "file10.wav"]
+        );
+        assert_eq!(
+            names(SortMode::Lexical)?,
+            vec!["File3.wav", "file10.wav", "file2.wav"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("a.wav"), "rock")?;
+        assert_eq!(
+            state.validate(),
+            ValidationReport {
+                missing: vec![],
+                untracked: vec![root.join("sub/b.wav")],
+            }
+        );
+
+        std::fs::remove_file(root.join("a.wav"))?;
+        let report = state.validate();
+        assert_eq!(report.missing, vec![root.join("a.wav")]);
+        assert_eq!(state.infos.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_respects_extension_filter() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "notes.txt", "sub/B.WAV"])?;
+        let state = State::new(&root, HashSet::from(["WAV"]))?;
+        let state: State = serde_json::from_str(&serde_json::to_string(&state)?)?;
+        assert_eq!(
+            state.validate().untracked,
+            vec![root.join("a.wav"), root.join("sub/B.WAV")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_relative() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        let mut state = State::new_relative(&root, HashSet::from(["wav"]))?;
+        state.add_tag("sub/b.wav", "rock")?;
+
+        let stored: Vec<&Utf8Path> = state.root.paths().chain(state.flat.files()).collect();
+        assert_eq!(
+            stored,
+            vec!["a.wav", "sub", "sub/b.wav", "a.wav", "sub/b.wav"]
+        );
+        assert!(stored.iter().all(|p| p.is_relative()));
+        assert_eq!(state.base(), Some(root.as_path()));
+        assert_eq!(
+            state.absolute_path("sub/b.wav".into()),
+            root.join("sub/b.wav")
+        );
+        assert_eq!(
+            state.validate(),
+            ValidationReport {
+                missing: vec![],
+                untracked: vec!["a.wav".into()],
+            }
+        );
+
+        let mut json = vec![];
+        state.save_to(&mut json)?;
+        let mut moved = State::load_from(json.as_slice())?;
+        moved.set_base("/mnt/music");
+        let tagged = moved.files_with_tag(&"rock".into()).next().unwrap().path();
+        assert_eq!(
+            moved.absolute_path(tagged),
+            Utf8Path::new("/mnt/music/sub/b.wav")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reroot() {
+        let mut state = State::from_tree(tree_fixture());
+        state.add_tag("/music/sub/c.wav", "rock").unwrap();
+        state.add_tag("/elsewhere/x.wav", "live").unwrap();
+
+        // /music, a.wav, b.wav, sub and sub/c.wav, but not /elsewhere/x.wav
+        assert_eq!(
+            state.reroot("/music".into(), "/mnt/usb/music".into()),
+            (5, 1)
+        );
+        assert_eq!(
+            state.flat.files().collect::<Vec<_>>(),
+            vec![
+                "/mnt/usb/music/a.wav",
+                "/mnt/usb/music/b.wav",
+                "/mnt/usb/music/sub/c.wav"
+            ]
+        );
+        assert_eq!(state.root.this, "/mnt/usb/music");
+        assert_eq!(
+            snapshot(&state),
+            vec![
+                ("/elsewhere/x.wav".into(), vec!["live".to_string()], None),
+                (
+                    "/mnt/usb/music/sub/c.wav".into(),
+                    vec!["rock".to_string()],
+                    None
+                ),
+            ]
+        );
+        let rock = state.files_with_tag(&"rock".into()).next().unwrap();
+        assert!(state.root.files().any(|p| p == rock.path()));
+
+        // the history moved along, so undo changes the files where they are now
+        assert!(state.undo() && state.undo());
+        assert!(state
+            .get("/mnt/usb/music/sub/c.wav".into())
+            .unwrap()
+            .tags()
+            .is_empty());
+        assert!(state.get("/music/sub/c.wav".into()).is_none());
+    }
+
+    #[test]
+    fn test_stat_all() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav", "gone.wav"])?;
+        std::fs::write(root.join("a.wav"), "123")?;
+        std::fs::write(root.join("sub/b.wav"), "12345")?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        for file in ["a.wav", "sub/b.wav", "gone.wav"] {
+            state.add_tag(root.join(file), "rock")?;
+        }
+        std::fs::remove_file(root.join("gone.wav"))?;
+
+        let failed = state.stat_all();
+        assert_eq!(
+            failed.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            vec![&root.join("gone.wav")]
+        );
+        assert_eq!(failed[0].1.kind(), std::io::ErrorKind::NotFound);
+        let size = |path: &str| state.get(&root.join(path)).and_then(FileInfo::size);
+        assert_eq!(size("a.wav"), Some(3));
+        assert_eq!(size("sub/b.wav"), Some(5));
+        assert_eq!(size("gone.wav"), None);
+        assert!(state
+            .get(&root.join("a.wav"))
+            .and_then(FileInfo::modified)
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reclaimable_by_tag() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[])?;
+        let mut state = State::empty(root.clone());
+        for (name, size, tags) in [
+            ("a.wav", 100, vec!["scratch", "drums"]),
+            ("b.wav", 20, vec!["Scratch"]),
+            ("c.wav", 3, vec!["drums"]),
+        ] {
+            std::fs::write(root.join(name), vec![0; size])?;
+            state.set_tags(root.join(name), tags)?;
+        }
+        state.stat_all();
+        // Tracked after statting, so its size stays unknown.
+        std::fs::write(root.join("unsized.wav"), "12345")?;
+        state.add_tag(root.join("unsized.wav"), "scratch")?;
+
+        let totals: Vec<(String, u64)> = state
+            .reclaimable_by_tag()
+            .into_iter()
+            .map(|(tag, bytes)| (tag.folded.to_string(), bytes))
+            .collect();
+        assert_eq!(
+            totals,
+            vec![("drums".to_string(), 103), ("scratch".to_string(), 120)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_file() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav", "sub/d.wav", "untracked.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("a.wav"), "rock")?;
+
+        state.move_file(&root.join("a.wav"), root.join("sub/c.wav"))?;
+        assert!(root.join("sub/c.wav").exists());
+        let moved: Vec<&FileInfo> = state.files_with_tag(&Tag::from("rock")).collect();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].path(), root.join("sub/c.wav"));
+        assert_eq!(
+            relative_files(&state.root, &root),
+            vec!["sub/b.wav", "sub/c.wav", "sub/d.wav", "untracked.wav"]
+        );
+        assert_eq!(
+            relative_files(&state.flat, &root),
+            vec!["sub/c.wav", "sub/b.wav", "sub/d.wav", "untracked.wav"]
+        );
+
+        state.move_file(&root.join("untracked.wav"), root.join("new.wav"))?;
+        assert!(state.untagged().any(|f| f.path() == root.join("new.wav")));
+        assert_eq!(state.infos.len(), 2);
+
+        let replayed = State::replay(state.root.clone(), state.op_log())?;
+        assert_eq!(snapshot(&replayed), snapshot(&state));
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_file_carries_history() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav"])?;
+        let (a, c) = (root.join("a.wav"), root.join("c.wav"));
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.open_journal(root.join("edits.jsonl"))?;
+        state.add_tag(&a, "rock")?;
+        state.move_file(&a, c.clone())?;
+
+        // read the history back from the journal, which has to agree
+        state.open_journal(root.join("edits.jsonl"))?;
+        assert!(state.undo());
+        assert!(state.get(&c).unwrap().tags().is_empty());
+        assert!(state.get(&a).is_none());
+        assert!(state.redo());
+        assert_eq!(state.get(&c).unwrap().tags(), &[Tag::from("rock")]);
+        assert!(state.get(&a).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_file_missing_source() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        match state.move_file(&root.join("gone.wav"), root.join("b.wav")) {
+            Err(FilepersonError::File { path, source }) => {
+                assert_eq!(path, root.join("gone.wav"));
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("expected a file error, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_file_refuses_to_replace() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "b.wav", "c.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("a.wav"), "rock")?;
+        state.add_tag(root.join("b.wav"), "jazz")?;
+        state.add_tag(root.join("gone.wav"), "live")?;
+
+        for to in ["b.wav", "gone.wav"] {
+            match state.move_file(&root.join("a.wav"), root.join(to)) {
+                Err(FilepersonError::File { source, .. }) => {
+                    assert_eq!(source.kind(), std::io::ErrorKind::AlreadyExists)
+                }
+                other => panic!("expected a refusal, got {:?}", other),
+            }
+        }
+        assert!(state
+            .move_file(&root.join("c.wav"), root.join("a.wav"))
+            .is_err());
+        assert!(root.join("a.wav").exists() && root.join("c.wav").exists());
+        assert_eq!(state.files_with_tag(&Tag::from("jazz")).count(), 1);
+        assert_eq!(state.flat.entries.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_file_relative() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        let mut state = State::new_relative(&root, HashSet::from(["wav"]))?;
+        state.add_tag("a.wav", "rock")?;
+        state.move_file("a.wav".into(), "sub/a.wav".into())?;
+        assert!(root.join("sub/a.wav").exists());
+        assert_eq!(
+            state
+                .files_with_tag(&Tag::from("rock"))
+                .next()
+                .unwrap()
+                .path(),
+            "sub/a.wav"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_by_directory() {
+        let mut state = state_fixture();
+        state.add_tag("/music/sub/b.wav", "rock").unwrap();
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.set_delete("/music/sub/a.wav", Some(true));
+        state.add_tag("/music/c.wav", "jazz").unwrap();
+
+        let groups = state.by_directory();
+        let paths: Vec<(&str, Vec<&str>)> = groups
+            .iter()
+            .map(|(dir, files)| {
+                (
+                    dir.as_str(),
+                    files.iter().map(|f| f.path.as_str()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("/music", vec!["/music/a.wav", "/music/c.wav"]),
+                ("/music/sub", vec!["/music/sub/a.wav", "/music/sub/b.wav"]),
+            ]
+        );
+        assert_eq!(groups[Utf8Path::new("/music")].len(), 2);
+        assert_eq!(groups[Utf8Path::new("/music/sub")].len(), 2);
+    }
+
+    #[test]
+    fn test_untagged() {
+        let mut state = State::from_tree(tree_fixture());
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.set_delete("/music/b.wav", Some(true));
+
+        let untagged: Vec<&Utf8Path> = state.untagged().map(|f| f.path()).collect();
+        assert_eq!(untagged, vec![Utf8Path::new("/music/b.wav")]);
+    }
+
+    #[test]
+    fn test_preview() -> anyhow::Result<()> {
+        let mut state = State::from_tree(tree_fixture());
+        state.add_tag("/music/a.wav", "rock")?;
+        state.add_tag("/music/b.wav", "jazz")?;
+        state.add(FileInfo::from("/music/sub/c.wav"))?;
+
+        let mut a = state.take_info("/music/a.wav".into()).unwrap();
+        a.set_preview(Some("/cache/a.png".into()));
+        assert_eq!(a.preview(), Some(Utf8Path::new("/cache/a.png")));
+        state.infos.insert(a);
+
+        let missing: Vec<&Utf8Path> = state.previews_missing().map(FileInfo::path).collect();
+        assert_eq!(missing, vec![Utf8Path::new("/music/b.wav")]);
+
+        let mut json = vec![];
+        state.save_to(&mut json)?;
+        let loaded = State::load_from(json.as_slice())?;
+        let previews: Vec<(&Utf8Path, Option<&Utf8Path>)> = loaded
+            .infos
+            .iter()
+            .map(|f| (f.path(), f.preview()))
+            .sorted()
+            .collect();
+        assert_eq!(
+            previews,
+            vec![
+                (
+                    Utf8Path::new("/music/a.wav"),
+                    Some(Utf8Path::new("/cache/a.png"))
+                ),
+                (Utf8Path::new("/music/b.wav"), None),
+                (Utf8Path::new("/music/sub/c.wav"), None),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "rock")?;
+        state.add_tag("/music/a.wav", "live")?;
+        state.add_tag("/music/b.wav", "Rock")?;
+        state.set_delete("/music/b.wav", Some(true));
+        state.set_delete("/music/c.wav", Some(true));
+        state.set_delete("/music/d.wav", Some(false));
+        state.add(FileInfo::from("/music/e.wav"))?;
+
+        let stats = state.stats();
+        assert_eq!(
+            stats,
+            Stats {
+                files: 5,
+                tagged: 2,
+                untagged: 3,
+                marked_for_deletion: 2,
+                distinct_tags: 2,
+                warnings: 1,
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "5 files (2 tagged, 3 untagged), 2 marked for deletion, 2 distinct tags, 1 warnings"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_info_builder() -> anyhow::Result<()> {
+        let info = FileInfo::builder()
+            .path("/music/a.wav")
+            .tag("rock")
+            .tag("Live")
+            .tag("ROCK")
+            .delete(true)
+            .build()?;
+        let mut state = state_fixture();
+        state.add(info)?;
+        assert_eq!(
+            snapshot(&state),
+            vec![(
+                "/music/a.wav".into(),
+                vec!["Live".to_string(), "rock".to_string()],
+                Some(true)
+            )]
+        );
+
+        assert!(FileInfo::builder().tag("rock").build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_untouched_files() {
+        let mut state = State::from_tree(tree_fixture());
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.set_delete("/music/sub/c.wav", Some(true));
+
+        let untouched: Vec<&Utf8Path> = state.untouched_files(&state.root).collect();
+        assert_eq!(untouched, vec![Utf8Path::new("/music/b.wav")]);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let mut state = State::from_tree(tree_fixture());
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.set_delete("/music/a.wav", Some(true));
+        state.set_delete("/music/b.wav", Some(true));
+        state.add_tag("/music/sub/c.wav", "jazz").unwrap();
+
+        assert_eq!(
+            state.warnings(),
+            vec![Warning::DeleteWithTags("/music/a.wav".into())]
+        );
+    }
+
+    /// Creates `files` (relative paths, parent directories included) under a fresh temp dir.
+    pub(crate) fn fs_fixture(files: &[&str]) -> anyhow::Result<(tempdir::TempDir, Utf8PathBuf)> {
+        let dir = tempdir::TempDir::new("fileperson")?;
+        let root = Utf8Path::from_path(dir.path())
+            .ok_or(anyhow!("temp dir is not utf-8"))?
+            .to_owned();
+        for file in files {
+            let path = root.join(file);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, "")?;
+        }
+        Ok((dir, root))
+    }
+
+    pub(crate) fn relative_files(dir: &Directory, root: &Utf8Path) -> Vec<String> {
+        dir.files()
+            .map(|p| p.strip_prefix(root).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_load_missing_root() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[])?;
+        let missing = root.join("nope");
+        match State::new(&missing, HashSet::from(["wav"])) {
+            Err(FilepersonError::Root { path, source }) => {
+                assert_eq!(path, missing);
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("loaded a missing root"),
+        }
+        assert!(matches!(
+            load_with_globs(&missing, &["*"]),
+            Err(FilepersonError::Root { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_with_recursive_glob() -> anyhow::Result<()> {
+        let (_dir, root) =
+            fs_fixture(&["a.mp3", "b.wav", "drums/kick.mp3", "drums/deep/snare.mp3"])?;
+        let (tree, flat) = load_with_globs(&root, &["**/*.mp3"])?;
+        let expected = vec!["a.mp3", "drums/deep/snare.mp3", "drums/kick.mp3"];
+        assert_eq!(relative_files(&flat, &root), expected);
+        assert_eq!(relative_files(&tree, &root), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_with_scoped_glob() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[
+            "kick.wav",
+            "drums/kick.wav",
+            "drums/kick.mp3",
+            "drums/deep/snare.wav",
+            "keys/organ.wav",
+        ])?;
+        let (_tree, flat) = load_with_globs(&root, &["drums/*.wav", "keys/*"])?;
+        assert_eq!(
+            relative_files(&flat, &root),
+            vec!["drums/kick.wav", "keys/organ.wav"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_with_name_regex() -> anyhow::Result<()> {
+        let (_dir, root) =
+            fs_fixture(&["take1.wav", "final.wav", "take2/final.wav", "x/take3.wav"])?;
+        let (tree, flat) = load_with_name_regex(&root, Regex::new(r"^take\d+")?)?;
+        let expected = vec!["take1.wav", "x/take3.wav"];
+        assert_eq!(relative_files(&flat, &root), expected);
+        assert_eq!(relative_files(&tree, &root), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_multi() -> anyhow::Result<()> {
+        let (_dir_a, root_a) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        let (_dir_b, root_b) = fs_fixture(&["c.wav"])?;
+        let mut state = State::new_multi(&[&root_a, &root_b], HashSet::from(["wav"]))?;
+
+        let roots: Vec<&Utf8Path> = state.root.entries().iter().map(FsNode::path).collect();
+        assert_eq!(roots, vec![root_a.as_path(), root_b.as_path()]);
+        let flat: Vec<&Utf8Path> = state.flat.files().collect();
+        assert_eq!(
+            flat,
+            vec![
+                root_a.join("a.wav").as_path(),
+                root_a.join("sub/b.wav").as_path(),
+                root_b.join("c.wav").as_path(),
+            ]
+        );
+
+        state.add_tag(root_a.join("a.wav"), "rock")?;
+        state.add_tag(root_b.join("c.wav"), "rock")?;
+        let tagged: Vec<&Utf8Path> = state
+            .files_with_tag(&Tag::from("rock"))
+            .map(FileInfo::path)
+            .sorted()
+            .collect();
+        let mut expected = vec![root_a.join("a.wav"), root_b.join("c.wav")];
+        expected.sort();
+        assert_eq!(tagged, expected);
+
+        let report = state.validate();
+        assert!(report.missing.is_empty());
+        assert_eq!(report.untracked, vec![root_a.join("sub/b.wav")]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_skips_symlink_cycle() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        std::os::unix::fs::symlink(&root, root.join("sub/loop"))?;
+        let (tree, flat) = load(&root, HashSet::from(["wav"]))?;
+        let expected = vec!["a.wav", "sub/b.wav"];
+        assert_eq!(relative_files(&flat, &root), expected);
+        assert_eq!(relative_files(&tree, &root), expected);
+        let sub = match &tree.entries()[1] {
+            FsNode::Directory(sub) => sub,
+            other => panic!("expected sub/, got {:?}", other),
+        };
+        assert_eq!(sub.entries().len(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_non_utf8_lossy() -> anyhow::Result<()> {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let (_dir, root) = fs_fixture(&["a.wav"])?;
+        let raw = root.as_std_path().join(OsStr::from_bytes(b"caf\xe9.wav"));
+        std::fs::write(raw, "")?;
+
+        let skipped = load_with_options(&root, HashSet::from(["wav"]), &LoadOptions::default())?;
+        assert_eq!(relative_files(&skipped.flat, &root), vec!["a.wav"]);
+        assert!(matches!(
+            skipped.errors.as_slice(),
+            [LoadError::NonUtf8Path(path)] if path.ends_with(OsStr::from_bytes(b"caf\xe9.wav"))
+        ));
+
+        let options = LoadOptions {
+            non_utf8: NonUtf8Policy::Lossy,
+            ..LoadOptions::default()
+        };
+        let Loaded {
+            root: tree, flat, ..
+        } = load_with_options(&root, HashSet::from(["wav"]), &options)?;
+        let expected = vec!["a.wav", "caf\u{fffd}.wav"];
+        assert_eq!(relative_files(&flat, &root), expected);
+        assert_eq!(relative_files(&tree, &root), expected);
+        let lossy = root.join("caf\u{fffd}.wav");
+        assert!(tree.is_lossy(&lossy));
+        assert!(flat.is_lossy(&lossy));
+        assert!(!tree.is_lossy(&root.join("a.wav")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_cancel() -> anyhow::Result<()> {
+        let files = [
+            "a.wav",
+            "b.wav",
+            "c/d.wav",
+            "c/e.wav",
+            "c/f/g.wav",
+            "h.wav",
+            "i/j.wav",
+            "k.wav",
+        ];
+        let (_dir, root) = fs_fixture(&files)?;
+        let (full, _) = load(&root, HashSet::from(["wav"]))?;
+        let all = relative_files(&full, &root);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let options = LoadOptions {
+            cancel: Some(cancel),
+            progress: Some(Arc::new(move |seen| {
+                if seen == 5 {
+                    flag.store(true, Ordering::Relaxed);
+                }
+            })),
+            ..LoadOptions::default()
+        };
+        let loaded = load_with_options(&root, HashSet::from(["wav"]), &options)?;
+        assert!(loaded.cancelled);
+        // a.wav, b.wav, c/, c/d.wav and c/e.wav are the first five entries walked
+        let partial = relative_files(&loaded.flat, &root);
+        assert_eq!(partial, vec!["a.wav", "b.wav", "c/d.wav", "c/e.wav"]);
+        assert_eq!(partial, all[..4]);
+        assert_eq!(relative_files(&loaded.root, &root), partial);
+        assert_eq!(loaded.root.entries().len(), 3);
+
+        let uncancelled =
+            load_with_options(&root, HashSet::from(["wav"]), &LoadOptions::default())?;
+        assert!(!uncancelled.cancelled);
+        assert_eq!(relative_files(&uncancelled.flat, &root), all);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_collects_errors() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_dir, root) = fs_fixture(&["a.wav", "locked/b.wav", "z.wav"])?;
+        let locked = root.join("locked");
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))?;
+        if std::fs::read_dir(&locked).is_ok() {
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+            eprintln!("permissions are not enforced for this user, skipping");
+            return Ok(());
+        }
+
+        let loaded = load_with_options(&root, HashSet::from(["wav"]), &LoadOptions::default());
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+        let loaded = loaded?;
+        assert_eq!(relative_files(&loaded.flat, &root), vec!["a.wav", "z.wav"]);
+        assert!(matches!(loaded.errors.as_slice(), [LoadError::Walk(_)]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_with_size() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["small.wav", "sub/large.wav"])?;
+        std::fs::write(root.join("small.wav"), [0u8; 10])?;
+        std::fs::write(root.join("sub/large.wav"), vec![0u8; 10 * 1024])?;
+
+        let filter = SizeFilter {
+            min: Some(1024),
+            max: None,
+        };
+        let (tree, flat) = load_with_size(&root, HashSet::from(["wav"]), filter)?;
+        assert_eq!(relative_files(&flat, &root), vec!["sub/large.wav"]);
+        assert_eq!(relative_files(&tree, &root), vec!["sub/large.wav"]);
+
+        let filter = SizeFilter {
+            min: Some(10),
+            max: Some(10),
+        };
+        let (tree, flat) = load_with_size(&root, HashSet::from(["wav"]), filter)?;
+        assert_eq!(relative_files(&flat, &root), vec!["small.wav"]);
+        // `sub` is kept even though none of its files are
+        assert_eq!(tree.paths().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_with_modified() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["fresh.wav", "old/stale.wav"])?;
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        let long_ago = SystemTime::now() - 10 * week;
+        std::fs::File::options()
+            .write(true)
+            .open(root.join("old/stale.wav"))?
+            .set_modified(long_ago)?;
+
+        let filter = ModifiedFilter {
+            after: Some(SystemTime::now() - week),
+            before: None,
+        };
+        let (tree, flat) = load_with_modified(&root, HashSet::from(["wav"]), filter)?;
+        assert_eq!(relative_files(&flat, &root), vec!["fresh.wav"]);
+        assert_eq!(relative_files(&tree, &root), vec!["fresh.wav"]);
+
+        let filter = ModifiedFilter {
+            after: None,
+            before: Some(SystemTime::now() - week),
+        };
+        let (_tree, flat) = load_with_modified(&root, HashSet::from(["wav"]), filter)?;
+        assert_eq!(relative_files(&flat, &root), vec!["old/stale.wav"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_extensions() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[
+            "a.WAV",
+            "notes.txt",
+            "docs/readme.md",
+            "drums/kick.mp3",
+            "drums/velocity/info.txt",
+            "README",
+        ])?;
+        let (tree, _flat) = load(&root, HashSet::<&str>::new())?;
+        let filtered = tree.filter_extensions(&HashSet::from(["wav".to_string(), "mp3".into()]));
+
+        assert_eq!(
+            relative_files(&filtered, &root),
+            vec!["a.WAV", "drums/kick.mp3"]
+        );
+        let paths: Vec<String> = filtered
+            .paths()
+            .map(|p| p.strip_prefix(&root).unwrap().to_string())
+            .collect();
+        assert_eq!(paths, vec!["a.WAV", "drums", "drums/kick.mp3"]);
+        assert_eq!(tree.files().count(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tags() -> anyhow::Result<()> {
+        let mut rng = rand::thread_rng();
+        let mut chain = MarkovChain::new();
         chain.learn(LOREM_IPSUM);
         chain.learn(LIBER_PRIMUS);
         let chain = &mut chain.iter();
@@ -363,4 +3827,36 @@ mod tests {
         println!("{:?}", state.tags().join(" "));
         Ok(())
     }
+
+    #[test]
+    fn test_par_tag_counts_matches_sequential() {
+        let mut rng = rand::thread_rng();
+        let vocabulary = [
+            "rock", "Rock", "ROCK", "jazz", "Jazz", "live", "demo", "stems",
+        ];
+        for casing in [TagCasing::Insensitive, TagCasing::Sensitive] {
+            let mut state = state_fixture();
+            state.set_tag_casing(casing);
+            state.extend((0..10_000).map(|i| {
+                let mut info = FileInfo::from(format!("/music/{i}.wav").as_str());
+                for _ in 0..rng.gen_range(0..4) {
+                    info.add_tag(vocabulary[rng.gen_range(0..vocabulary.len())]);
+                }
+                info
+            }));
+
+            let spelled = |counts: Vec<(Tag, usize)>| {
+                counts
+                    .into_iter()
+                    .map(|(tag, count)| (tag.value.to_string(), count))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                spelled(state.par_tag_counts()),
+                spelled(state.tag_counts()),
+                "{:?}",
+                casing
+            );
+        }
+    }
 }
@@ -1,67 +1,461 @@
-use std::{
-    collections::HashSet,
-    io::BufReader,
-    sync::{
-        atomic::{AtomicU32, Ordering},
-        mpsc,
-    },
-    thread::{self, sleep},
-    time::Duration,
-};
+use std::{collections::HashSet, io::Write};
 
-use directories::UserDirs;
-use fileperson::{load, FsNode, State};
-use rayon::prelude::*;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, Subcommand};
+use fileperson::{ApplyOptions, M3uOptions, State, Tag, TagQuery};
+use itertools::Itertools;
 
-fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init();
+#[cfg(feature = "tui")]
+mod tui;
 
-    let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
-    //let sink = rodio::Sink::try_new(&handle).unwrap();
+#[derive(Parser)]
+#[command(version, about = "Tag files and decide which ones to keep")]
+struct Cli {
+    /// State file read and written by every subcommand.
+    #[arg(long, global = true, default_value = "fileperson.json")]
+    state: Utf8PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    let ud = UserDirs::new().unwrap();
-    let desktop = ud.desktop_dir().unwrap().to_string_lossy().to_string();
-    let arg = std::env::args().nth(1);
-    let root = arg.unwrap_or(desktop);
-    let include = HashSet::from(["mp3", "wav", "caf", "aif", "aiff"]);
+#[derive(Subcommand)]
+enum Command {
+    /// Load the files under <root> into a fresh state, replacing the state file.
+    Scan {
+        root: Utf8PathBuf,
+        /// Only load files with this extension; repeat for several.
+        #[arg(long = "ext", default_values = ["mp3", "wav", "caf", "aif", "aiff"])]
+        extensions: Vec<String>,
+        /// Also try decoding every loaded file and report how many are playable audio.
+        #[cfg(feature = "audio")]
+        #[arg(long)]
+        audio: bool,
+        /// Also read audio properties and embedded tags into the state.
+        #[cfg(feature = "metadata")]
+        #[arg(long)]
+        metadata: bool,
+    },
+    /// Print the files in the state with their tags.
+    List {
+        /// Only print files carrying this tag, or any tag beneath a namespace like "genre:".
+        #[arg(long)]
+        tag: Option<String>,
+    },
+    /// Print the files whose tags match a boolean query, e.g. "kick AND (808 OR analog)".
+    Query { query: TagQuery },
+    /// Write the files matching a query to an M3U playlist.
+    Playlist {
+        query: TagQuery,
+        /// The playlist to write, e.g. dark.m3u8.
+        #[arg(long, short)]
+        output: Utf8PathBuf,
+        /// Write paths relative to the playlist's directory.
+        #[arg(long)]
+        relative: bool,
+        /// Write #EXTM3U and #EXTINF lines with durations and titles.
+        #[arg(long)]
+        extended: bool,
+    },
+    /// Write the files matching a query as a Rekordbox XML collection, a playlist per tag.
+    Rekordbox {
+        query: TagQuery,
+        /// The collection to write, e.g. rekordbox.xml.
+        #[arg(long, short)]
+        output: Utf8PathBuf,
+    },
+    /// Add tags to a file.
+    Tag {
+        path: Utf8PathBuf,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Rename a tag on every file carrying it.
+    Rename { from: String, to: String },
+    /// Replace several tags with one on every file carrying any of them.
+    Merge {
+        #[arg(required = true)]
+        sources: Vec<String>,
+        /// The tag to replace them with.
+        #[arg(long)]
+        into: String,
+    },
+    /// Check the state against the disk: report tracked files that are gone.
+    Verify {
+        /// Also report files under the root that carry no tags or decisions.
+        #[arg(long)]
+        untracked: bool,
+    },
+    /// Write each file's tags into its own ID3 or Vorbis comment metadata.
+    #[cfg(feature = "metadata")]
+    Embed,
+    /// Sync tags with macOS Finder both ways.
+    #[cfg(feature = "finder")]
+    Finder {
+        /// Which side wins where the two disagree.
+        #[arg(long, value_enum, default_value_t = Prefer::Union)]
+        prefer: Prefer,
+    },
+    /// Mirror each tagged file's tags into its user.fileperson.tags extended attribute.
+    #[cfg(feature = "xattr")]
+    Xattrs {
+        /// Read the attributes back into the state instead, e.g. into a fresh scan.
+        #[arg(long)]
+        import: bool,
+    },
+    /// Print sets of files with identical content, one set per paragraph.
+    #[cfg(feature = "blake3")]
+    Dedupe {
+        /// Mark all but one file of each set for deletion.
+        #[arg(long)]
+        mark: bool,
+        /// Which file of a set to keep when marking.
+        #[arg(long, value_enum, default_value_t = Keep::Tagged)]
+        keep: Keep,
+    },
+    /// Print sets of audio files that sound alike, one set per paragraph.
+    #[cfg(feature = "fingerprint")]
+    Similar {
+        /// How much of the shorter file has to sound like the other, from 0 to 1.
+        #[arg(long, default_value_t = fileperson::NEAR_DUPLICATE_THRESHOLD)]
+        threshold: f32,
+    },
+    /// Tag files with the ratings, genres and playlists of an iTunes / Music.app library export.
+    #[cfg(feature = "itunes")]
+    Itunes {
+        /// The exported Library.xml.
+        library: Utf8PathBuf,
+    },
+    /// Browse the tree interactively, audition files and edit their tags.
+    #[cfg(feature = "tui")]
+    Tui,
+    /// Carry out pending decisions, i.e. move the files marked for deletion to the trash.
+    Apply {
+        /// Delete files for good instead.
+        #[arg(long)]
+        permanent: bool,
+        /// Only print what would be done.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
 
-    let (_root, files) = load(root, include)?;
-    let count = AtomicU32::new(0);
-    files.entries().par_iter().for_each(|path| {
-        if let FsNode::File(path) = path {
-            if let Ok(file) = std::fs::File::open(path) {
-                let quick = true;
-                if quick {
-                    if rodio::Decoder::new(BufReader::new(file)).is_ok() {
-                        // log::info!("found one: {:?}", path);
-                        // sink.append(decoder);
-                    } else {
-                    }
-                    let val = count.fetch_add(1, Ordering::Relaxed);
-                    if val % 50 == 0 {
-                        log::info!("{val}");
-                    }
-                } else {
-                    match handle.play_once(BufReader::new(file)) {
-                        Ok(h) => {
-                            log::debug!("2> {:?}", path);
-                            let (tx, rx) = mpsc::channel();
+/// The [`fileperson::FinderConflict`] policies, for the command line.
+#[cfg(feature = "finder")]
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Prefer {
+    /// Give both sides the tags of either.
+    Union,
+    /// Make Finder show what fileperson has.
+    Fileperson,
+    /// Make fileperson take what Finder shows.
+    Finder,
+}
+
+/// The [`fileperson::KeepDuplicate`] choices, for the command line.
+#[cfg(feature = "blake3")]
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Keep {
+    /// A file already decided to keep, else the one with the most tags.
+    Tagged,
+    /// The first path in sort order.
+    First,
+    /// The path with the fewest components.
+    Shallowest,
+}
+
+fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+    let cli = Cli::parse();
 
-                            h.set_volume(0.2);
-                            thread::spawn(move || {
-                                sleep(Duration::from_millis(500));
-                                tx.send(());
-                            });
-                            rx.recv().ok();
-                            println!("! {:?}", path);
-                            h.stop();
-                        }
-                        Err(e) => println!("X_X {:?}", e),
+    match cli.command {
+        Command::Scan {
+            root,
+            extensions,
+            #[cfg(feature = "audio")]
+            audio,
+            #[cfg(feature = "metadata")]
+            metadata,
+        } => {
+            #[allow(unused_mut)]
+            let mut state = State::new(&root, extensions.into_iter().collect::<HashSet<_>>())?;
+            #[cfg(feature = "metadata")]
+            if metadata {
+                for (path, e) in state.probe_metadata() {
+                    log::debug!("no metadata for {}: {}", path, e);
+                }
+            }
+            #[cfg(feature = "audio")]
+            if audio {
+                probe_audio(&state);
+            }
+            state.save(&cli.state)?;
+            println!(
+                "scanned {} files into {}",
+                state.file_rows().count(),
+                cli.state
+            );
+            Ok(())
+        }
+        Command::List { tag } => {
+            let state = State::load(&cli.state)?;
+            let tag = tag.as_deref().map(Tag::from);
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            for row in state.file_rows() {
+                if let Some(tag) = &tag {
+                    if !row.tags.iter().any(|t| tag.covers(t)) {
+                        continue;
                     }
                 }
+                writeln!(out, "{}\t{}", row.path, row.tags.iter().join(", "))?;
+            }
+            Ok(())
+        }
+        Command::Query { query } => {
+            let state = State::load(&cli.state)?;
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            for info in state.query(&query).sorted_by_key(|info| info.path()) {
+                writeln!(out, "{}\t{}", info.path(), info.tags().iter().join(", "))?;
+            }
+            Ok(())
+        }
+        Command::Playlist {
+            query,
+            output,
+            relative,
+            extended,
+        } => {
+            let state = State::load(&cli.state)?;
+            let options = M3uOptions { relative, extended };
+            let count = state.export_m3u_with(&query, &output, &options)?;
+            println!("wrote {} files to {}", count, output);
+            Ok(())
+        }
+        Command::Rekordbox { query, output } => {
+            let state = State::load(&cli.state)?;
+            let file = std::io::BufWriter::new(std::fs::File::create(&output)?);
+            let count = state.export_rekordbox(&query, file)?;
+            println!("wrote {} tracks to {}", count, output);
+            Ok(())
+        }
+        Command::Tag { path, tags } => {
+            let mut state = State::load(&cli.state)?;
+            let path = canonical(&path);
+            for tag in tags {
+                state.add_tag(&path, tag.as_str())?;
+            }
+            Ok(state.save(&cli.state)?)
+        }
+        Command::Rename { from, to } => {
+            let mut state = State::load(&cli.state)?;
+            let changed = state.rename_tag(&Tag::from(from.as_str()), to.as_str())?;
+            state.save(&cli.state)?;
+            println!("renamed {} to {} on {} files", from, to, changed);
+            Ok(())
+        }
+        Command::Merge { sources, into } => {
+            let mut state = State::load(&cli.state)?;
+            let sources: Vec<Tag> = sources.iter().map(|tag| Tag::from(tag.as_str())).collect();
+            let changed = state.merge_tags(&sources, into.as_str())?;
+            state.save(&cli.state)?;
+            println!("merged into {} on {} files", into, changed);
+            Ok(())
+        }
+        Command::Verify { untracked } => {
+            let state = State::load(&cli.state)?;
+            let report = state.validate();
+            for path in &report.missing {
+                println!("missing\t{}", path);
+            }
+            if untracked {
+                for path in &report.untracked {
+                    println!("untracked\t{}", path);
+                }
+            }
+            if !report.missing.is_empty() || (untracked && !report.untracked.is_empty()) {
+                anyhow::bail!("{} does not match the disk", cli.state);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "metadata")]
+        Command::Embed => {
+            let state = State::load(&cli.state)?;
+            let report = state.embed_tags();
+            for path in &report.written {
+                println!("{}", path);
+            }
+            for (path, e) in &report.failed {
+                eprintln!("could not embed tags in {}: {}", path, e);
+            }
+            if !report.is_success() {
+                anyhow::bail!("{} files failed", report.failed.len());
+            }
+            Ok(())
+        }
+        #[cfg(feature = "finder")]
+        Command::Finder { prefer } => {
+            use fileperson::FinderConflict;
+            let mut state = State::load(&cli.state)?;
+            let report = state.sync_finder_tags(match prefer {
+                Prefer::Union => FinderConflict::Union,
+                Prefer::Fileperson => FinderConflict::Fileperson,
+                Prefer::Finder => FinderConflict::Finder,
+            });
+            for path in &report.imported {
+                println!("imported\t{}", path);
+            }
+            for path in &report.exported {
+                println!("exported\t{}", path);
+            }
+            for (path, e) in &report.failed {
+                eprintln!("could not sync {}: {}", path, e);
+            }
+            state.save(&cli.state)?;
+            if !report.is_success() {
+                anyhow::bail!("{} files failed", report.failed.len());
+            }
+            Ok(())
+        }
+        #[cfg(feature = "xattr")]
+        Command::Xattrs { import } => {
+            let mut state = State::load(&cli.state)?;
+            let failed = if import {
+                let failed = state.import_xattrs();
+                state.save(&cli.state)?;
+                failed
+            } else {
+                state.sync_xattrs()
+            };
+            for (path, e) in &failed {
+                eprintln!("could not sync {}: {}", path, e);
+            }
+            if !failed.is_empty() {
+                anyhow::bail!("{} files failed", failed.len());
+            }
+            Ok(())
+        }
+        #[cfg(feature = "blake3")]
+        Command::Dedupe { mark, keep } => {
+            use fileperson::KeepDuplicate;
+            let mut state = State::load(&cli.state)?;
+            let report = state.find_duplicates();
+            for (path, e) in &report.failed {
+                eprintln!("could not read {}: {}", path, e);
+            }
+            let duplicates = report.groups;
+            for (i, group) in duplicates.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                for path in group {
+                    println!("{}", path);
+                }
             }
+            if mark {
+                let marked = state.mark_duplicates(
+                    &duplicates,
+                    match keep {
+                        Keep::Tagged => KeepDuplicate::Tagged,
+                        Keep::First => KeepDuplicate::First,
+                        Keep::Shallowest => KeepDuplicate::ShallowestPath,
+                    },
+                );
+                state.save(&cli.state)?;
+                eprintln!("marked {} files for deletion", marked);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "fingerprint")]
+        Command::Similar { threshold } => {
+            let state = State::load(&cli.state)?;
+            for (i, group) in state.find_near_duplicates(threshold).iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                for path in group {
+                    println!("{}", path);
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "itunes")]
+        Command::Itunes { library } => {
+            let mut state = State::load(&cli.state)?;
+            let report = state.import_itunes(&library)?;
+            for location in &report.unmatched {
+                eprintln!("no file for {}", location);
+            }
+            for (path, e) in &report.rejected {
+                eprintln!("not tagging {}: {}", path, e);
+            }
+            state.save(&cli.state)?;
+            println!(
+                "matched {} tracks, tagged {} files",
+                report.matched, report.tagged
+            );
+            Ok(())
+        }
+        #[cfg(feature = "tui")]
+        Command::Tui => {
+            let mut state = State::load(&cli.state)?;
+            tui::run(&mut state, &cli.state)
+        }
+        Command::Apply { permanent, dry_run } => {
+            let mut state = State::load(&cli.state)?;
+            if dry_run {
+                print!("{}", state.plan());
+                return Ok(());
+            }
+            let report = state.apply_with(&ApplyOptions { permanent });
+            for action in &report.done {
+                println!("{}", action);
+            }
+            for (action, e) in &report.failed {
+                eprintln!("could not {}: {}", action, e);
+            }
+            state.save(&cli.state)?;
+            if !report.is_success() {
+                anyhow::bail!("{} actions failed", report.failed.len());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// States store canonical paths, so resolve what the user typed the same way. Paths that don't
+/// exist (anymore) are taken as given.
+fn canonical(path: &Utf8Path) -> Utf8PathBuf {
+    path.canonicalize()
+        .ok()
+        .and_then(|path| Utf8PathBuf::from_path_buf(path).ok())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+#[cfg(feature = "audio")]
+fn probe_audio(state: &State) {
+    use rayon::prelude::*;
+    use std::{
+        fs::File,
+        io::BufReader,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    let files: Vec<&Utf8Path> = state.file_rows().map(|row| row.path).collect();
+    let playable = AtomicU32::new(0);
+    files.par_iter().for_each(|path| {
+        let decodes = File::open(path)
+            .map(|file| rodio::Decoder::new(BufReader::new(file)).is_ok())
+            .unwrap_or(false);
+        if decodes {
+            playable.fetch_add(1, Ordering::Relaxed);
+        } else {
+            log::debug!("not playable: {path}");
         }
     });
-    // sink.sleep_until_end();
-    Ok(())
+    println!(
+        "{} of {} files are playable audio",
+        playable.into_inner(),
+        files.len()
+    );
 }
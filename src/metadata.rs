@@ -0,0 +1,241 @@
+//! Audio properties and embedded tags read from the files themselves, so filters like "44.1kHz
+//! stereo over 10 seconds" don't need to decode anything.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::FileInfo;
+
+/// What a file's container says about its audio, see [`FileInfo::read_metadata`] (`metadata`
+/// feature). Every field is optional because formats differ in what they record.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AudioMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bit_depth: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u8>,
+    /// Text items of the embedded ID3, Vorbis, RIFF INFO, ... tags, keyed by a format-neutral
+    /// name such as `TrackTitle` or `Genre`; items without one (pictures, ratings, anything
+    /// format-specific) are skipped. When a file has several tags, the first one to mention a
+    /// key wins.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl FileInfo {
+    /// Metadata stored by [`FileInfo::probe_metadata`] or [`State::probe_metadata`](crate::State::probe_metadata).
+    pub fn audio(&self) -> Option<&AudioMetadata> {
+        self.audio.as_ref()
+    }
+}
+
+#[cfg(feature = "metadata")]
+mod read {
+    use camino::{Utf8Path, Utf8PathBuf};
+    use lofty::prelude::*;
+    use rayon::prelude::*;
+
+    use super::AudioMetadata;
+    use crate::{FileInfo, FilepersonError, State};
+
+    /// Read `path`'s properties and tags without decoding any audio.
+    fn read(path: &Utf8Path) -> Result<AudioMetadata, FilepersonError> {
+        let file = lofty::read_from_path(path).map_err(|source| FilepersonError::Metadata {
+            path: path.to_owned(),
+            source,
+        })?;
+        let properties = file.properties();
+        let mut metadata = AudioMetadata {
+            duration: Some(properties.duration()).filter(|d| !d.is_zero()),
+            sample_rate: properties.sample_rate(),
+            bit_depth: properties.bit_depth(),
+            channels: properties.channels(),
+            ..AudioMetadata::default()
+        };
+        for tag in file.tags() {
+            for item in tag.items() {
+                if let (Some(key), Some(text)) = (key_name(item.key()), item.value().text()) {
+                    metadata
+                        .tags
+                        .entry(key.to_string())
+                        .or_insert_with(|| text.to_string());
+                }
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// The name an item is stored under in [`AudioMetadata::tags`]. Spelled out rather than
+    /// taken from `ItemKey`'s `Debug` output, so that saved states don't change with lofty.
+    fn key_name(key: ItemKey) -> Option<&'static str> {
+        Some(match key {
+            ItemKey::TrackTitle => "TrackTitle",
+            ItemKey::TrackSubtitle => "TrackSubtitle",
+            ItemKey::TrackArtist => "TrackArtist",
+            ItemKey::AlbumTitle => "AlbumTitle",
+            ItemKey::AlbumArtist => "AlbumArtist",
+            ItemKey::Composer => "Composer",
+            ItemKey::Conductor => "Conductor",
+            ItemKey::Lyricist => "Lyricist",
+            ItemKey::Remixer => "Remixer",
+            ItemKey::Label => "Label",
+            ItemKey::Publisher => "Publisher",
+            ItemKey::Genre => "Genre",
+            ItemKey::Mood => "Mood",
+            ItemKey::Bpm => "Bpm",
+            ItemKey::IntegerBpm => "IntegerBpm",
+            ItemKey::InitialKey => "InitialKey",
+            ItemKey::TrackNumber => "TrackNumber",
+            ItemKey::TrackTotal => "TrackTotal",
+            ItemKey::DiscNumber => "DiscNumber",
+            ItemKey::RecordingDate => "RecordingDate",
+            ItemKey::Year => "Year",
+            ItemKey::Isrc => "Isrc",
+            ItemKey::Comment => "Comment",
+            ItemKey::Description => "Description",
+            ItemKey::EncodedBy => "EncodedBy",
+            ItemKey::EncoderSoftware => "EncoderSoftware",
+            _ => return None,
+        })
+    }
+
+    impl FileInfo {
+        /// Read the audio properties and embedded tags of the file.
+        pub fn read_metadata(&self) -> Result<AudioMetadata, FilepersonError> {
+            read(&self.path)
+        }
+
+        /// Like [`FileInfo::read_metadata`], but also stores the result in the info, filling in
+        /// [`FileInfo::duration`] too if it isn't known yet.
+        pub fn probe_metadata(&mut self) -> Result<&AudioMetadata, FilepersonError> {
+            let on_disk = self.path.clone();
+            self.probe_metadata_at(&on_disk)
+        }
+
+        fn probe_metadata_at(
+            &mut self,
+            on_disk: &Utf8Path,
+        ) -> Result<&AudioMetadata, FilepersonError> {
+            let metadata = read(on_disk)?;
+            if self.duration.is_none() {
+                self.duration = metadata.duration;
+            }
+            Ok(self.audio.insert(metadata))
+        }
+    }
+
+    impl State {
+        /// [`FileInfo::probe_metadata`] every file in the tree on the rayon thread pool, tracking
+        /// the ones that aren't yet. Returns the files that couldn't be read, e.g. because
+        /// they aren't audio; they're tracked but keep whatever metadata they had.
+        pub fn probe_metadata(&mut self) -> Vec<(Utf8PathBuf, FilepersonError)> {
+            let mut infos: Vec<FileInfo> = std::mem::take(&mut self.infos).into_iter().collect();
+            let tracked: std::collections::HashSet<Utf8PathBuf> =
+                infos.iter().map(|info| info.path.clone()).collect();
+            infos.extend(
+                self.root
+                    .files()
+                    .filter(|path| !tracked.contains(*path))
+                    .map(FileInfo::from),
+            );
+            let this = &*self;
+            let mut failed: Vec<(Utf8PathBuf, FilepersonError)> = infos
+                .par_iter_mut()
+                .filter_map(|info| {
+                    let on_disk = this.absolute_path(&info.path);
+                    info.probe_metadata_at(&on_disk)
+                        .err()
+                        .map(|e| (info.path.clone(), e))
+                })
+                .collect();
+            self.infos = infos.into_iter().collect();
+            failed.sort_by(|a, b| a.0.cmp(&b.0));
+            failed
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metadata"))]
+mod tests {
+    use std::collections::HashSet;
+
+    use camino::Utf8Path;
+
+    use crate::{tests::fs_fixture, State};
+
+    use super::*;
+
+    /// `testdata/beep.wav` (8kHz mono 16-bit, 0.25s) with a RIFF INFO title appended.
+    fn titled_beep(path: &Utf8Path, title: &str) -> anyhow::Result<()> {
+        let mut wav =
+            std::fs::read(Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/beep.wav"))?;
+        let mut value = title.as_bytes().to_vec();
+        value.push(0);
+        if value.len() % 2 == 1 {
+            value.push(0);
+        }
+        let mut list = b"INFO".to_vec();
+        list.extend(b"INAM");
+        list.extend((value.len() as u32).to_le_bytes());
+        list.extend(value);
+        wav.extend(b"LIST");
+        wav.extend((list.len() as u32).to_le_bytes());
+        wav.extend(list);
+        let riff_len = (wav.len() - 8) as u32;
+        wav[4..8].copy_from_slice(&riff_len.to_le_bytes());
+        std::fs::write(path, wav)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_metadata() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["notes.txt"])?;
+        titled_beep(&root.join("beep.wav"), "Beep")?;
+        let mut state = State::new(&root, HashSet::from(["wav", "txt"]))?;
+        state.add_tag(root.join("beep.wav"), "test")?;
+
+        let failed = state.probe_metadata();
+        assert_eq!(
+            failed
+                .iter()
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>(),
+            vec![root.join("notes.txt")]
+        );
+
+        let info = state.get(&root.join("beep.wav")).unwrap();
+        let audio = info.audio().expect("probed");
+        assert_eq!(audio.sample_rate, Some(8000));
+        assert_eq!(audio.channels, Some(1));
+        assert_eq!(audio.bit_depth, Some(16));
+        assert_eq!(audio.duration, Some(Duration::from_millis(250)));
+        assert_eq!(
+            audio.tags.get("TrackTitle").map(String::as_str),
+            Some("Beep")
+        );
+        assert_eq!(audio.tags.keys().collect::<Vec<_>>(), vec!["TrackTitle"]);
+        assert_eq!(info.duration(), audio.duration);
+        assert_eq!(state.get(&root.join("notes.txt")).unwrap().audio(), None);
+
+        // a filter over the stored metadata
+        let mono: Vec<&Utf8Path> = state
+            .files_where(|f| f.audio().is_some_and(|a| a.channels == Some(1)))
+            .map(FileInfo::path)
+            .collect();
+        assert_eq!(mono, vec![root.join("beep.wav")]);
+
+        let mut saved = vec![];
+        state.save_to(&mut saved)?;
+        let loaded = State::load_from(&saved[..])?;
+        assert_eq!(
+            loaded.get(&root.join("beep.wav")).unwrap().audio(),
+            Some(audio)
+        );
+        Ok(())
+    }
+}
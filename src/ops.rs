@@ -0,0 +1,720 @@
+//! Tag mutations as serializable operations, recorded in an [`OpLog`] so a state can be audited
+//! and rebuilt from its tree plus the log.
+
+use std::collections::VecDeque;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use itertools::Itertools;
+
+use crate::{journal::JournalEntry, Directory, FileInfo, FilepersonError, State, Tag, TagCasing};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Op {
+    AddTag {
+        path: Utf8PathBuf,
+        tag: Tag,
+    },
+    RemoveTag {
+        path: Utf8PathBuf,
+        tag: Tag,
+    },
+    SetDelete {
+        path: Utf8PathBuf,
+        delete: Option<bool>,
+    },
+    RenameTag {
+        from: Tag,
+        to: Tag,
+    },
+    MergeTags {
+        sources: Vec<Tag>,
+        target: Tag,
+    },
+    /// Start tracking a file without any tags or decisions, e.g. in [`State::import_csv`].
+    Track {
+        path: Utf8PathBuf,
+    },
+    /// Forget everything about a file, e.g. in [`State::apply_patch`].
+    Untrack {
+        path: Utf8PathBuf,
+    },
+    /// A file renamed on disk by [`State::move_file`].
+    MoveFile {
+        from: Utf8PathBuf,
+        to: Utf8PathBuf,
+    },
+}
+
+/// Append-only record of every effective mutation made through the `State` mutation methods.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OpLog {
+    ops: Vec<Op>,
+}
+
+impl OpLog {
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+fn same_spelling(a: &[Tag], b: &[Tag]) -> bool {
+    a.iter().map(|t| &t.value).eq(b.iter().map(|t| &t.value))
+}
+
+/// What an edit can change about one file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Snapshot {
+    tags: Vec<Tag>,
+    delete: Option<bool>,
+}
+
+impl Snapshot {
+    fn same(&self, other: &Snapshot) -> bool {
+        same_spelling(&self.tags, &other.tags) && self.delete == other.delete
+    }
+}
+
+/// One file before and after an edit.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FileEdit {
+    path: Utf8PathBuf,
+    before: Snapshot,
+    after: Snapshot,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Edit {
+    files: Vec<FileEdit>,
+}
+
+/// Bounded undo/redo history of tag and delete flag edits made through the `State` mutation
+/// methods.
+#[derive(Clone, Debug)]
+pub(crate) struct EditLog {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    limit: usize,
+}
+
+impl Default for EditLog {
+    fn default() -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            limit: 100,
+        }
+    }
+}
+
+impl EditLog {
+    /// A new edit invalidates everything that could have been redone.
+    pub(crate) fn push(&mut self, edit: Edit) {
+        self.redo.clear();
+        self.undo.push_back(edit);
+        self.truncate();
+    }
+
+    /// Move the latest edit from the undo to the redo stack, returning it.
+    pub(crate) fn step_back(&mut self) -> Option<&Edit> {
+        let edit = self.undo.pop_back()?;
+        self.redo.push(edit);
+        self.redo.last()
+    }
+
+    /// Move the latest undone edit back onto the undo stack, returning it.
+    pub(crate) fn step_forward(&mut self) -> Option<&Edit> {
+        let edit = self.redo.pop()?;
+        self.undo.push_back(edit);
+        self.undo.back()
+    }
+
+    /// Drop all history, keeping the limit.
+    pub(crate) fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Point the history of `from` and everything beneath it at the same files under `to`.
+    pub(crate) fn move_paths(&mut self, from: &Utf8Path, to: &Utf8Path) {
+        for edit in self.undo.iter_mut().chain(&mut self.redo) {
+            for file in &mut edit.files {
+                if let Ok(rest) = file.path.strip_prefix(from) {
+                    file.path = if rest.as_str().is_empty() {
+                        to.to_owned()
+                    } else {
+                        to.join(rest)
+                    };
+                }
+            }
+        }
+    }
+
+    /// Drop the history of `path` and everything beneath it, and the edits left empty by that.
+    pub(crate) fn forget(&mut self, path: &Utf8Path) {
+        for edit in self.undo.iter_mut().chain(&mut self.redo) {
+            edit.files.retain(|file| !file.path.starts_with(path));
+        }
+        self.undo.retain(|edit| !edit.files.is_empty());
+        self.redo.retain(|edit| !edit.files.is_empty());
+    }
+
+    fn truncate(&mut self) {
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+}
+
+impl State {
+    pub fn op_log(&self) -> &OpLog {
+        &self.ops
+    }
+
+    /// Add `tag` to the file at `path`, tracking the file if it isn't yet.
+    /// Returns `false` if the file already carried the tag, and fails if the tag violates the
+    /// state's [`TagPolicy`](crate::TagPolicy) or isn't in its vocabulary, see
+    /// [`State::with_vocabulary`].
+    pub fn add_tag(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        tag: impl Into<Tag>,
+    ) -> Result<bool, FilepersonError> {
+        let path = path.as_ref().to_owned();
+        let op = Op::AddTag {
+            path: path.clone(),
+            tag: tag.into(),
+        };
+        self.check_op(&op)?;
+        Ok(self.track_edit(vec![path], |state| state.record(op) > 0))
+    }
+
+    /// Replace the tags of the file at `path` with `tags`, tracking the file if it isn't yet.
+    /// Fails without changing anything if any of the tags is rejected, like [`State::add_tag`].
+    /// Returns `false` if the file already carried exactly these tags.
+    pub fn set_tags(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        tags: impl IntoIterator<Item = impl Into<Tag>>,
+    ) -> Result<bool, FilepersonError> {
+        let path = path.as_ref().to_owned();
+        let tags: Vec<Tag> = tags.into_iter().map(Into::into).collect();
+        for tag in &tags {
+            self.check_tag(tag)?;
+        }
+        Ok(self.track_edit(vec![path.clone()], |state| {
+            let before = state.ops.len();
+            state.restore_tags(&path, &tags);
+            state.ops.len() > before
+        }))
+    }
+
+    /// Remove `tag` from the file at `path`. Returns `false` if the file didn't carry it.
+    pub fn remove_tag(&mut self, path: impl AsRef<Utf8Path>, tag: &Tag) -> bool {
+        let path = path.as_ref().to_owned();
+        let tag = tag.clone();
+        self.track_edit(vec![path.clone()], |state| {
+            state.record(Op::RemoveTag { path, tag }) > 0
+        })
+    }
+
+    /// Set the deletion flag of the file at `path`, tracking the file if it isn't yet.
+    pub fn set_delete(&mut self, path: impl AsRef<Utf8Path>, delete: Option<bool>) -> bool {
+        let path = path.as_ref().to_owned();
+        self.track_edit(vec![path.clone()], |state| {
+            state.record(Op::SetDelete { path, delete }) > 0
+        })
+    }
+
+    /// Replace `from` with `to` on every file carrying it. Returns the number of files changed,
+    /// and fails without changing anything if `to` is rejected, like [`State::add_tag`].
+    pub fn rename_tag(&mut self, from: &Tag, to: impl Into<Tag>) -> Result<usize, FilepersonError> {
+        let op = Op::RenameTag {
+            from: from.clone(),
+            to: to.into(),
+        };
+        self.check_op(&op)?;
+        let affected = self.paths_with_any(std::slice::from_ref(from));
+        Ok(self.track_edit(affected, |state| state.record(op)))
+    }
+
+    /// Replace every tag in `sources` with `target`. Returns the number of files changed, and
+    /// fails without changing anything if `target` is rejected, like [`State::add_tag`].
+    pub fn merge_tags(
+        &mut self,
+        sources: &[Tag],
+        target: impl Into<Tag>,
+    ) -> Result<usize, FilepersonError> {
+        let op = Op::MergeTags {
+            sources: sources.to_vec(),
+            target: target.into(),
+        };
+        self.check_op(&op)?;
+        let affected = self.paths_with_any(sources);
+        Ok(self.track_edit(affected, |state| state.record(op)))
+    }
+
+    /// Add `tag` to every tracked file matching `pred`, as a single undoable edit.
+    /// Returns the number of files changed; files already carrying the tag don't count.
+    /// Fails without changing anything if the tag is rejected, like [`State::add_tag`].
+    pub fn tag_where(
+        &mut self,
+        mut pred: impl FnMut(&FileInfo) -> bool,
+        tag: impl Into<Tag>,
+    ) -> Result<usize, FilepersonError> {
+        let tag = tag.into();
+        self.check_tag(&tag)?;
+        let matching: Vec<Utf8PathBuf> = self
+            .infos
+            .iter()
+            .filter(|info| pred(info))
+            .map(|info| info.path.clone())
+            .collect();
+        Ok(self.track_edit(matching.clone(), |state| {
+            matching
+                .into_iter()
+                .filter(|path| {
+                    state.record(Op::AddTag {
+                        path: path.clone(),
+                        tag: tag.clone(),
+                    }) > 0
+                })
+                .count()
+        }))
+    }
+
+    /// Remove `tag` from every tracked file matching `pred`, as a single undoable edit.
+    /// Returns the number of files changed; files without the tag don't count.
+    pub fn untag_where(&mut self, mut pred: impl FnMut(&FileInfo) -> bool, tag: &Tag) -> usize {
+        let matching: Vec<Utf8PathBuf> = self
+            .infos
+            .iter()
+            .filter(|info| pred(info))
+            .map(|info| info.path.clone())
+            .collect();
+        self.track_edit(matching.clone(), |state| {
+            matching
+                .into_iter()
+                .filter(|path| {
+                    state.record(Op::RemoveTag {
+                        path: path.clone(),
+                        tag: tag.clone(),
+                    }) > 0
+                })
+                .count()
+        })
+    }
+
+    /// Remove all tags from every tracked file matching `pred`, as a single undoable edit.
+    /// Returns the number of files that had any.
+    pub fn clear_tags_where(&mut self, mut pred: impl FnMut(&FileInfo) -> bool) -> usize {
+        let matching: Vec<(Utf8PathBuf, Vec<Tag>)> = self
+            .infos
+            .iter()
+            .filter(|info| !info.tags.is_empty() && pred(info))
+            .map(|info| (info.path.clone(), info.tags.clone()))
+            .collect();
+        let paths = matching.iter().map(|(path, _)| path.clone()).collect();
+        self.track_edit(paths, |state| {
+            for (path, tags) in &matching {
+                for tag in tags {
+                    state.record(Op::RemoveTag {
+                        path: path.clone(),
+                        tag: tag.clone(),
+                    });
+                }
+            }
+            matching.len()
+        })
+    }
+
+    /// Limit how many edits [`State::undo`] can go back. Older edits are dropped first.
+    pub fn set_undo_limit(&mut self, limit: usize) {
+        self.edits.limit = limit;
+        self.edits.truncate();
+    }
+
+    /// Revert the most recent edit of tags or delete flags. Returns `false` if there was
+    /// nothing to undo.
+    ///
+    /// Edits are the mutation methods in this module and the bulk ones built on them (imports,
+    /// rules, patches, dedupe, Finder sync, xattr import). Not undoable, and left out of the
+    /// history: replacing whole infos through [`State::add`] or `extend` (which is also how a
+    /// sqlite index exports into a state), tag colors from [`State::assign_colors`], file metadata from
+    /// [`State::stat_all`], and moves and removals of files ([`State::move_file`],
+    /// [`State::reroot`], [`State::apply`], [`State::watch`]). The history of moved files follows
+    /// them, and that of removed files is dropped.
+    pub fn undo(&mut self) -> bool {
+        let edit = match self.edits.step_back() {
+            Some(edit) => edit.clone(),
+            None => return false,
+        };
+        for file in &edit.files {
+            self.restore(&file.path, &file.before);
+        }
+        self.journal(JournalEntry::Undo);
+        true
+    }
+
+    /// Reapply the most recently undone edit. Returns `false` if there was nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        let edit = match self.edits.step_forward() {
+            Some(edit) => edit.clone(),
+            None => return false,
+        };
+        for file in &edit.files {
+            self.restore(&file.path, &file.after);
+        }
+        self.journal(JournalEntry::Redo);
+        true
+    }
+
+    /// Rebuild a state by applying `ops` in order onto a fresh [`State::from_tree`].
+    pub fn replay(tree: Directory, ops: &OpLog) -> Result<State, FilepersonError> {
+        let mut state = State::from_tree(tree);
+        for op in ops.ops() {
+            state.record(op.clone());
+        }
+        Ok(state)
+    }
+
+    /// A superset of the files carrying any of `tags`, taken from the tag index.
+    fn paths_with_any(&self, tags: &[Tag]) -> Vec<Utf8PathBuf> {
+        tags.iter()
+            .flat_map(|tag| self.infos.paths_covered_by(tag, TagCasing::Insensitive))
+            .sorted()
+            .dedup()
+            .map(Utf8Path::to_owned)
+            .collect()
+    }
+
+    fn tags_of(&self, path: &Utf8Path) -> Vec<Tag> {
+        self.get(path)
+            .map(|info| info.tags.clone())
+            .unwrap_or_default()
+    }
+
+    fn snapshot_of(&self, path: &Utf8Path) -> Snapshot {
+        self.get(path)
+            .map(|info| Snapshot {
+                tags: info.tags.clone(),
+                delete: info.delete,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Run `f`, recording how it changed the tags and delete flags of `paths` as one undoable
+    /// edit.
+    pub(crate) fn track_edit<R>(
+        &mut self,
+        paths: Vec<Utf8PathBuf>,
+        f: impl FnOnce(&mut State) -> R,
+    ) -> R {
+        let before: Vec<Snapshot> = paths.iter().map(|path| self.snapshot_of(path)).collect();
+        let result = f(self);
+        let files: Vec<FileEdit> = paths
+            .into_iter()
+            .zip(before)
+            .filter_map(|(path, before)| {
+                let after = self.snapshot_of(&path);
+                (!before.same(&after)).then_some(FileEdit {
+                    path,
+                    before,
+                    after,
+                })
+            })
+            .collect();
+        if !files.is_empty() {
+            let edit = Edit { files };
+            self.journal(JournalEntry::Edit(edit.clone()));
+            self.edits.push(edit);
+        }
+        result
+    }
+
+    /// Carry the undo history of `from` over to `to`, after the files there moved.
+    pub(crate) fn move_history(&mut self, from: &Utf8Path, to: &Utf8Path) {
+        self.edits.move_paths(from, to);
+        self.journal(JournalEntry::Move {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+    }
+
+    /// Drop the undo history of `path`, after it stopped being tracked.
+    pub(crate) fn forget_history(&mut self, path: &Utf8Path) {
+        self.edits.forget(path);
+        self.journal(JournalEntry::Forget(path.to_owned()));
+    }
+
+    /// Bring `path` back to exactly `target`, through logged ops.
+    fn restore(&mut self, path: &Utf8Path, target: &Snapshot) {
+        self.restore_tags(path, &target.tags);
+        if self.snapshot_of(path).delete != target.delete {
+            self.record(Op::SetDelete {
+                path: path.to_owned(),
+                delete: target.delete,
+            });
+        }
+    }
+
+    /// Bring the tags of `path` back to exactly `target`, through logged ops.
+    fn restore_tags(&mut self, path: &Utf8Path, target: &[Tag]) {
+        let current = self.tags_of(path);
+        for tag in &current {
+            if !target.iter().any(|t| t.value == tag.value) {
+                self.record(Op::RemoveTag {
+                    path: path.to_owned(),
+                    tag: tag.clone(),
+                });
+            }
+        }
+        for tag in target {
+            if !current.iter().any(|t| t.value == tag.value) {
+                self.record(Op::AddTag {
+                    path: path.to_owned(),
+                    tag: tag.clone(),
+                });
+            }
+        }
+    }
+
+    /// Apply `op` and append it to the log if it changed anything.
+    pub(crate) fn record(&mut self, op: Op) -> usize {
+        let changed = self.apply_op(&op);
+        if changed > 0 {
+            self.ops.ops.push(op);
+        }
+        changed
+    }
+
+    /// Apply `op` without logging it. Returns the number of files changed.
+    fn apply_op(&mut self, op: &Op) -> usize {
+        match op {
+            Op::AddTag { path, tag } => {
+                let mut info = self.take_info(path).unwrap_or_else(|| FileInfo::from(path));
+                let changed = info.add_tag_with(self.interner.intern(tag.clone()), self.casing);
+                self.infos.insert(info);
+                changed as usize
+            }
+            Op::RemoveTag { path, tag } => match self.take_info(path) {
+                Some(mut info) => {
+                    let changed = info.remove_tag_with(tag, self.casing);
+                    self.infos.insert(info);
+                    changed as usize
+                }
+                None => 0,
+            },
+            Op::SetDelete { path, delete } => {
+                let mut info = self.take_info(path).unwrap_or_else(|| FileInfo::from(path));
+                let changed = info.delete != *delete;
+                info.delete = *delete;
+                self.infos.insert(info);
+                changed as usize
+            }
+            Op::RenameTag { from, to } => self.replace_tags(std::slice::from_ref(from), to),
+            Op::MergeTags { sources, target } => self.replace_tags(sources, target),
+            Op::Track { path } => match self.get(path) {
+                Some(_) => 0,
+                None => {
+                    self.infos.insert(FileInfo::from(path));
+                    1
+                }
+            },
+            Op::Untrack { path } => self.infos.remove(path.as_path()) as usize,
+            Op::MoveFile { from, to } => {
+                let mut info = self.take_info(from).unwrap_or_else(|| FileInfo::from(to));
+                info.path = to.clone();
+                self.infos.insert(info);
+                1
+            }
+        }
+    }
+
+    fn replace_tags(&mut self, sources: &[Tag], target: &Tag) -> usize {
+        let target = self.interner.intern(target.clone());
+        let mut changed = 0;
+        let casing = self.casing;
+        let infos = std::mem::take(&mut self.infos);
+        self.infos = infos
+            .into_iter()
+            .map(|mut info| {
+                let before = info.tags.clone();
+                let mut hit = false;
+                for source in sources {
+                    hit |= info.remove_tag_with(source, casing);
+                }
+                if hit {
+                    info.add_tag_with(target.clone(), casing);
+                    if before
+                        .iter()
+                        .map(|t| &t.value)
+                        .ne(info.tags.iter().map(|t| &t.value))
+                    {
+                        changed += 1;
+                    }
+                }
+                info
+            })
+            .collect();
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{fs_fixture, snapshot, state_fixture};
+
+    use super::*;
+
+    #[test]
+    fn test_undo_redo() {
+        let mut state = state_fixture();
+        let a = Utf8Path::new("/music/a.wav");
+        state.add_tag(a, "rock").unwrap();
+        state.add_tag(a, "live").unwrap();
+        let two_edits = snapshot(&state);
+        assert!(state.rename_tag(&Tag::from("rock"), "Rock").unwrap() > 0);
+        state.add_tag(a, "jazz").unwrap();
+
+        assert!(state.undo());
+        assert!(state.undo());
+        assert_eq!(snapshot(&state), two_edits);
+        assert!(state.redo());
+        assert_eq!(state.tags_of(a), vec![Tag::from("live"), Tag::from("Rock")]);
+        assert_eq!(&*state.tags_of(a)[1].value, "Rock");
+
+        // a new edit drops the remaining redo step
+        state.remove_tag(a, &Tag::from("live"));
+        assert!(!state.redo());
+
+        // undo/redo go through the op log, so replaying still reproduces the state
+        let replayed = State::replay(state.root.clone(), state.op_log()).unwrap();
+        assert_eq!(snapshot(&replayed), snapshot(&state));
+    }
+
+    #[test]
+    fn test_undo_set_delete() {
+        let mut state = state_fixture();
+        let a = Utf8Path::new("/music/a.wav");
+        state.add_tag(a, "rock").unwrap();
+        assert!(state.set_delete(a, Some(true)));
+        assert!(!state.set_delete(a, Some(true)));
+        state.add_tag(a, "live").unwrap();
+
+        assert!(state.undo());
+        assert!(state.undo());
+        assert_eq!(
+            snapshot(&state),
+            vec![(a.to_owned(), vec!["rock".to_string()], None)]
+        );
+        assert!(state.redo());
+        assert_eq!(state.get(a).unwrap().delete(), Some(true));
+        assert!(state.undo());
+        assert!(state.undo());
+        assert!(!state.undo());
+    }
+
+    #[test]
+    fn test_undo_limit() {
+        let mut state = state_fixture();
+        state.set_undo_limit(2);
+        for tag in ["a", "b", "c"] {
+            state.add_tag("/music/a.wav", tag).unwrap();
+        }
+        assert!(state.undo());
+        assert!(state.undo());
+        assert!(!state.undo());
+        assert_eq!(state.tags_of("/music/a.wav".into()), vec![Tag::from("a")]);
+    }
+
+    #[test]
+    fn test_tag_where() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&[])?;
+        let mut state = state_fixture();
+        for (name, size) in [("small.wav", 10), ("big.wav", 5000), ("huge.wav", 9000)] {
+            let path = root.join(name);
+            std::fs::write(&path, vec![0; size])?;
+            let mut info = FileInfo::from(path);
+            info.stat()?;
+            state.add(info)?;
+        }
+        state.add_tag(root.join("huge.wav"), "loud")?;
+
+        let loud = |f: &FileInfo| f.size().unwrap_or(0) > 1000;
+        assert_eq!(state.tag_where(loud, "loud")?, 1);
+        assert_eq!(state.tag_where(loud, "LOUD")?, 0);
+        let mut tagged: Vec<&Utf8Path> = state
+            .files_with_tag(&Tag::from("loud"))
+            .map(|f| f.path())
+            .collect();
+        tagged.sort();
+        assert_eq!(tagged, vec![root.join("big.wav"), root.join("huge.wav")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_untag_where() {
+        let mut state = state_fixture();
+        for name in ["a.wav", "b.wav", "c.wav", "d.wav"] {
+            let path = format!("/music/{}", name);
+            state.add_tag(&path, "wip").unwrap();
+            state.add_tag(&path, "drums").unwrap();
+        }
+        state.add_tag("/music/a.wav", "zzz").unwrap();
+
+        let done = |f: &FileInfo| f.path() != "/music/d.wav";
+        assert_eq!(state.untag_where(done, &"WIP".into()), 3);
+        assert_eq!(state.untag_where(done, &"wip".into()), 0);
+        assert_eq!(
+            snapshot(&state)
+                .into_iter()
+                .map(|(path, tags, _)| (path.to_string(), tags))
+                .collect::<Vec<_>>(),
+            vec![
+                ("/music/a.wav".into(), vec!["drums".into(), "zzz".into()]),
+                ("/music/b.wav".into(), vec!["drums".into()]),
+                ("/music/c.wav".into(), vec!["drums".into()]),
+                ("/music/d.wav".into(), vec!["drums".into(), "wip".into()]),
+            ]
+        );
+
+        assert_eq!(state.clear_tags_where(|f| f.path() < "/music/c.wav"), 2);
+        assert!(state.get("/music/a.wav".into()).unwrap().tags().is_empty());
+        assert_eq!(state.files_with_tag(&"drums".into()).count(), 2);
+
+        assert!(state.undo());
+        assert_eq!(state.files_with_tag(&"drums".into()).count(), 4);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut state = state_fixture();
+        let a = Utf8Path::new("/music/a.wav");
+        let b = Utf8Path::new("/music/b.wav");
+        state.add_tag(a, "rock").unwrap();
+        state.add_tag(a, "live").unwrap();
+        state.add_tag(b, "Rokc").unwrap();
+        state.add_tag(b, "jazz").unwrap();
+        state.remove_tag(a, &Tag::from("live"));
+        state.set_delete(b, Some(true));
+        assert_eq!(state.merge_tags(&[Tag::from("rokc")], "Rock").unwrap(), 1);
+        assert_eq!(state.rename_tag(&Tag::from("jazz"), "bebop").unwrap(), 1);
+        // no-ops aren't recorded
+        assert!(!state.add_tag(a, "ROCK").unwrap());
+        assert_eq!(state.op_log().len(), 8);
+
+        let replayed = State::replay(state.root.clone(), state.op_log()).unwrap();
+        assert_eq!(snapshot(&replayed), snapshot(&state));
+        assert_eq!(replayed.op_log().ops(), state.op_log().ops());
+    }
+}
@@ -0,0 +1,307 @@
+//! Reading and writing a [`State`] as JSON, upgrading files written by older versions.
+
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+use camino::Utf8Path;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{FileInfo, FilepersonError, State};
+
+/// Version written by [`State::save_to`]. Bump it, and add a step to [`migrate`], whenever the
+/// serialized shape changes in a way serde defaults can't paper over.
+///
+/// - v0: no `version` field; predates `size`/`modified` on files and `ops`/`casing` on the state.
+/// - v1: adds `version`.
+pub const STATE_VERSION: u32 = 1;
+
+impl State {
+    pub fn save_to(&self, w: impl Write) -> Result<(), FilepersonError> {
+        serde_json::to_writer(w, self)?;
+        Ok(())
+    }
+
+    /// Write the state to the file at `path`, see [`State::save_to`]. It goes to a temporary
+    /// file next to `path` first and is then renamed over it, so a crash midway leaves the
+    /// previous save intact.
+    pub fn save(&self, path: impl AsRef<Utf8Path>) -> Result<(), FilepersonError> {
+        let path = path.as_ref();
+        let file_error = |source| FilepersonError::File {
+            path: path.to_owned(),
+            source,
+        };
+        let tmp = path.with_file_name(format!(".{}.tmp", path.file_name().unwrap_or("state")));
+        let write = || -> Result<(), FilepersonError> {
+            let mut w = BufWriter::new(File::create(&tmp).map_err(file_error)?);
+            self.save_to(&mut w)?;
+            let file = w.into_inner().map_err(|e| file_error(e.into_error()))?;
+            file.sync_all().map_err(file_error)?;
+            std::fs::rename(&tmp, path).map_err(file_error)
+        };
+        write().inspect_err(|_| {
+            std::fs::remove_file(&tmp).ok();
+        })
+    }
+
+    /// Read a state from the file at `path`, see [`State::load_from`].
+    pub fn load(path: impl AsRef<Utf8Path>) -> Result<State, FilepersonError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|source| FilepersonError::File {
+            path: path.to_owned(),
+            source,
+        })?;
+        State::load_from(BufReader::new(file))
+    }
+
+    /// Like [`State::save_to`], but leaves out infos that aren't [`FileInfo::touched`]: they
+    /// carry nothing the tree doesn't, so [`State::load_from`] gets the same files back.
+    pub fn save_touched(&self, w: impl Write) -> Result<(), FilepersonError> {
+        let mut value = serde_json::to_value(self)?;
+        let touched: HashSet<&FileInfo> = self.infos.iter().filter(|f| f.touched()).collect();
+        value["infos"] = sorted_infos(&touched, serde_json::value::Serializer)?;
+        serde_json::to_writer(w, &value)?;
+        Ok(())
+    }
+
+    /// Read a state written by [`State::save_to`] of this or any earlier version, then
+    /// [`State::normalize`] it in case the file was edited by hand.
+    /// Fails on files from a newer version instead of guessing at their meaning.
+    pub fn load_from(r: impl Read) -> Result<State, FilepersonError> {
+        let mut value: Value = serde_json::from_reader(r)?;
+        let version = match value.get("version") {
+            None => 0,
+            Some(version) => version
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| {
+                    FilepersonError::MalformedState(format!("invalid format version {}", version))
+                })?,
+        };
+        if version > STATE_VERSION {
+            return Err(FilepersonError::UnsupportedVersion {
+                found: version,
+                supported: STATE_VERSION,
+            });
+        }
+        migrate(&mut value, version)?;
+        let mut state: State = serde_json::from_value(value)?;
+        state.normalize();
+        Ok(state)
+    }
+}
+
+/// Writes `infos` as an array sorted by path, so equal states serialize to identical bytes.
+pub(crate) fn sorted_infos<I, S>(infos: &HashSet<I>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    I: std::borrow::Borrow<FileInfo> + Eq + std::hash::Hash,
+    S: Serializer,
+{
+    let mut sorted: Vec<&FileInfo> = infos.iter().map(|f| f.borrow()).collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+    sorted.serialize(serializer)
+}
+
+/// Upgrade a serialized state from `version` to [`STATE_VERSION`], one version at a time.
+fn migrate(value: &mut Value, mut version: u32) -> Result<(), FilepersonError> {
+    let state = value
+        .as_object_mut()
+        .ok_or_else(|| FilepersonError::MalformedState("not a JSON object".to_string()))?;
+    while version < STATE_VERSION {
+        match version {
+            // The fields added since v0 all have serde defaults, so only the stamp is missing.
+            0 => {}
+            _ => unreachable!("no migration from version {}", version),
+        }
+        version += 1;
+    }
+    state.insert("version".to_string(), version.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{fs_fixture, snapshot, state_fixture};
+
+    use super::*;
+
+    #[test]
+    fn test_load_v0() -> anyhow::Result<()> {
+        let v0 = r##"{
+            "root": {"this": "/music", "entries": [
+                {"File": "/music/a.wav"},
+                {"Directory": {"this": "/music/sub", "entries": [{"File": "/music/sub/b.wav"}]}}
+            ]},
+            "flat": {"this": "/music", "entries": [
+                {"File": "/music/a.wav"}, {"File": "/music/sub/b.wav"}
+            ]},
+            "infos": [
+                {"path": "/music/a.wav", "delete": null,
+                 "tags": [{"color": null, "value": "live"}, {"color": "#ff0000", "value": "Rock"}]},
+                {"path": "/music/sub/b.wav", "delete": true, "tags": []}
+            ]
+        }"##;
+        let state = State::load_from(v0.as_bytes())?;
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(
+            snapshot(&state),
+            vec![
+                (
+                    "/music/a.wav".into(),
+                    vec!["live".to_string(), "Rock".to_string()],
+                    None
+                ),
+                ("/music/sub/b.wav".into(), vec![], Some(true)),
+            ]
+        );
+        let rock = state.tags().find(|t| &*t.value == "Rock").unwrap();
+        assert_eq!(
+            rock.color().map(|c| c.to_string()).as_deref(),
+            Some("#ff0000")
+        );
+        assert_eq!(state.flat.files().count(), 2);
+        assert_eq!(state.root.paths().count(), 3);
+        assert!(state.op_log().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_dedups_tags() -> anyhow::Result<()> {
+        let json = r#"{
+            "version": 1,
+            "root": {"this": "/music", "entries": []},
+            "flat": {"this": "/music", "entries": []},
+            "infos": [{"path": "/music/a.wav", "delete": null, "tags": [
+                {"color": null, "value": "rock"},
+                {"color": null, "value": "jazz"},
+                {"color": null, "value": "Rock"},
+                {"color": null, "value": "ROCK"}
+            ]}]
+        }"#;
+        let state = State::load_from(json.as_bytes())?;
+        assert_eq!(
+            snapshot(&state),
+            vec![(
+                "/music/a.wav".into(),
+                vec!["jazz".to_string(), "rock".to_string()],
+                None
+            )]
+        );
+        assert_eq!(
+            state.tag_counts(),
+            vec![("jazz".into(), 1), ("rock".into(), 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_load_round_trip() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "rock")?;
+        state.set_delete("/music/b.wav", Some(false));
+
+        let mut json = vec![];
+        state.save_to(&mut json)?;
+        let loaded = State::load_from(json.as_slice())?;
+        assert_eq!(snapshot(&loaded), snapshot(&state));
+        assert_eq!(loaded.op_log().len(), state.op_log().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_load_file() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("sub/b.wav"), "rock")?;
+        let path = root.join("state.json");
+        state.save(&path)?;
+        state.add_tag(root.join("a.wav"), "jazz")?;
+        state.save(&path)?;
+        assert_eq!(
+            std::fs::read_dir(&root)?.count(),
+            3,
+            "no temporary file is left behind"
+        );
+
+        let loaded = State::load(&path)?;
+        assert_eq!(snapshot(&loaded), snapshot(&state));
+        assert_eq!(
+            loaded.root.files().collect::<Vec<_>>(),
+            state.root.files().collect::<Vec<_>>()
+        );
+        assert_eq!(loaded.flat.files().count(), 2);
+
+        match State::load(root.join("missing.json")) {
+            Err(FilepersonError::File { path, .. }) => assert_eq!(path, root.join("missing.json")),
+            other => panic!("expected a file error, got {:?}", other.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_touched() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        state.extend(["/music/a.wav", "/music/b.wav", "/music/c.wav"].map(FileInfo::from));
+        state.add_tag("/music/b.wav", "rock")?;
+
+        let mut json = vec![];
+        state.save_touched(&mut json)?;
+        let value: Value = serde_json::from_slice(&json)?;
+        let paths: Vec<&str> = value["infos"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|info| info["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, vec!["/music/b.wav"]);
+
+        let loaded = State::load_from(json.as_slice())?;
+        assert_eq!(
+            snapshot(&loaded),
+            vec![("/music/b.wav".into(), vec!["rock".to_string()], None)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_is_stable() -> anyhow::Result<()> {
+        let infos = || {
+            ["/music/c.wav", "/music/a.wav", "/music/sub/b.wav"].map(|path| {
+                let mut info = FileInfo::from(path);
+                info.add_tag("rock");
+                info
+            })
+        };
+        let mut first = state_fixture();
+        first.extend(infos());
+        let mut second = state_fixture();
+        second.extend(Vec::from(infos()).into_iter().rev());
+
+        let (mut a, mut b) = (vec![], vec![]);
+        first.save_to(&mut a)?;
+        second.save_to(&mut b)?;
+        assert_eq!(String::from_utf8(a)?, String::from_utf8(b)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_future_version() {
+        let json = format!(
+            r#"{{"version": {}, "root": {{"this": "/", "entries": []}}}}"#,
+            STATE_VERSION + 1
+        );
+        let err = match State::load_from(json.as_bytes()) {
+            Ok(_) => panic!("loaded a state from the future"),
+            Err(e) => e,
+        };
+        assert!(
+            err.to_string().contains("newer than the supported"),
+            "{}",
+            err
+        );
+    }
+}
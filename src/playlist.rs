@@ -0,0 +1,190 @@
+//! M3U playlists of the files matching a tag query, for any player to open.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use itertools::Itertools;
+
+use crate::{FileInfo, FilepersonError, LoadError, State, TagQuery};
+
+/// Knobs for [`State::export_m3u_with`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct M3uOptions {
+    /// Write paths relative to the playlist's directory, so the playlist can move along with the
+    /// files, e.g. onto a USB stick. Paths that share no root with it stay absolute.
+    pub relative: bool,
+    /// Write an extended M3U: an `#EXTM3U` header, and before each path an `#EXTINF` line with
+    /// the duration in seconds (`-1` if unknown) and the title from the file's metadata, or its
+    /// name.
+    pub extended: bool,
+}
+
+/// `path` relative to the directory `base`, both absolute. `None` if they share no root, like
+/// paths on different Windows drives.
+fn relative_to(path: &Utf8Path, base: &Utf8Path) -> Option<Utf8PathBuf> {
+    let mut path_parts = path.components().peekable();
+    let mut base_parts = base.components().peekable();
+    match (path_parts.peek(), base_parts.peek()) {
+        (Some(Utf8Component::Prefix(a)), Some(Utf8Component::Prefix(b))) if a != b => return None,
+        _ => {}
+    }
+    while let (Some(a), Some(b)) = (path_parts.peek(), base_parts.peek()) {
+        if a != b {
+            break;
+        }
+        path_parts.next();
+        base_parts.next();
+    }
+    let mut relative: Utf8PathBuf = base_parts.map(|_| "..").collect();
+    relative.extend(path_parts);
+    Some(relative)
+}
+
+fn extinf(info: &FileInfo, on_disk: &Utf8Path) -> String {
+    let seconds = info
+        .duration
+        .map_or(-1, |duration| duration.as_secs_f64().round() as i64);
+    let title = info
+        .audio
+        .as_ref()
+        .and_then(|audio| audio.tags.get("TrackTitle"))
+        .map(String::as_str)
+        .or_else(|| on_disk.file_stem())
+        .unwrap_or_default();
+    // a newline would end the entry early
+    format!("#EXTINF:{},{}", seconds, title.split_whitespace().join(" "))
+}
+
+impl State {
+    /// Write the tracked files matching `query` to the playlist at `path`, sorted by path, and
+    /// return how many there are. The playlist is UTF-8, i.e. M3U8, whatever the extension says.
+    pub fn export_m3u(
+        &self,
+        query: &TagQuery,
+        path: impl AsRef<Utf8Path>,
+    ) -> Result<usize, FilepersonError> {
+        self.export_m3u_with(query, path, &M3uOptions::default())
+    }
+
+    /// Like [`State::export_m3u`], with non-default [`M3uOptions`].
+    pub fn export_m3u_with(
+        &self,
+        query: &TagQuery,
+        path: impl AsRef<Utf8Path>,
+        options: &M3uOptions,
+    ) -> Result<usize, FilepersonError> {
+        let path = path.as_ref();
+        let file_error = |source| FilepersonError::File {
+            path: path.to_owned(),
+            source,
+        };
+        let mut w = BufWriter::new(File::create(path).map_err(file_error)?);
+        // Now that the playlist exists it can be canonicalized, resolving `..` and symlinks in
+        // its path the way a player opening it will.
+        let base = if options.relative {
+            let canonical = std::fs::canonicalize(path).map_err(file_error)?;
+            let canonical =
+                Utf8PathBuf::from_path_buf(canonical).map_err(LoadError::NonUtf8Path)?;
+            canonical.parent().map(Utf8Path::to_owned)
+        } else {
+            None
+        };
+        let mut write = || -> std::io::Result<usize> {
+            if options.extended {
+                writeln!(w, "#EXTM3U")?;
+            }
+            let mut count = 0;
+            for info in self.query(query).sorted_by_key(|info| info.path()) {
+                let on_disk = self.absolute_path(&info.path);
+                if options.extended {
+                    writeln!(w, "{}", extinf(info, &on_disk))?;
+                }
+                let entry = base
+                    .as_deref()
+                    .and_then(|base| relative_to(&on_disk, base))
+                    .unwrap_or(on_disk);
+                writeln!(w, "{}", entry)?;
+                count += 1;
+            }
+            w.flush()?;
+            Ok(count)
+        };
+        write().map_err(file_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::tests::fs_fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_relative_to() {
+        let rel = |path: &str, base: &str| relative_to(path.into(), base.into()).unwrap();
+        assert_eq!(rel("/music/dark/a.wav", "/music/lists"), "../dark/a.wav");
+        assert_eq!(rel("/music/a.wav", "/music"), "a.wav");
+        assert_eq!(rel("/a.wav", "/music/lists/gig"), "../../../a.wav");
+    }
+
+    #[test]
+    fn test_export_m3u() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["drone.wav", "dark/pad one.wav", "bright.wav", "lists/x"])?;
+        let mut state = State::new(&root, ["wav"].into())?;
+        state.add_tag(root.join("drone.wav"), "mood:dark")?;
+        state.add_tag(root.join("dark/pad one.wav"), "mood:Dark")?;
+        state.add_tag(root.join("bright.wav"), "mood:bright")?;
+        let mut info = state.take_info(&root.join("drone.wav")).unwrap();
+        info.duration = Some(Duration::from_millis(61_600));
+        state.infos.insert(info);
+        let query: TagQuery = "mood:dark".parse()?;
+
+        let plain = root.join("lists/dark.m3u");
+        assert_eq!(state.export_m3u(&query, &plain)?, 2);
+        assert_eq!(
+            std::fs::read_to_string(&plain)?,
+            format!(
+                "{}\n{}\n",
+                root.join("dark/pad one.wav"),
+                root.join("drone.wav")
+            )
+        );
+
+        let extended = root.join("lists/dark.m3u8");
+        let options = M3uOptions {
+            relative: true,
+            extended: true,
+        };
+        assert_eq!(state.export_m3u_with(&query, &extended, &options)?, 2);
+        assert_eq!(
+            std::fs::read_to_string(&extended)?,
+            "#EXTM3U\n\
+             #EXTINF:-1,pad one\n../dark/pad one.wav\n\
+             #EXTINF:62,drone\n../drone.wav\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_m3u_relative_through_parent_dir() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["dark/a.wav", "sub/x", "lists/x"])?;
+        let mut state = State::new(&root, ["wav"].into())?;
+        state.add_tag(root.join("dark/a.wav"), "dark")?;
+        let options = M3uOptions {
+            relative: true,
+            ..M3uOptions::default()
+        };
+        let playlist = root.join("sub/../lists/d.m3u");
+        assert_eq!(
+            state.export_m3u_with(&"dark".parse()?, &playlist, &options)?,
+            1
+        );
+        assert_eq!(std::fs::read_to_string(&playlist)?, "../dark/a.wav\n");
+        Ok(())
+    }
+}
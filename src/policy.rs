@@ -0,0 +1,322 @@
+//! Limits on what a tag value may look like, for interop with systems that are pickier than us.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{FilepersonError, Op, State, Tag};
+
+/// Checked by every mutation that adds tags, e.g. [`State::add_tag`], [`State::rename_tag`] or
+/// [`State::tag_where`], and by [`State::add`] for whole infos. Loading a saved state doesn't
+/// re-check. The default only forbids control characters.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagPolicy {
+    /// Longest allowed value in characters, or `None` for no limit.
+    pub max_len: Option<usize>,
+    /// Reject values containing control characters such as newlines or tabs.
+    pub forbid_control: bool,
+}
+
+impl Default for TagPolicy {
+    fn default() -> Self {
+        Self {
+            max_len: None,
+            forbid_control: true,
+        }
+    }
+}
+
+/// Why a tag doesn't satisfy a [`TagPolicy`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TagViolation {
+    #[error("{len} characters long, at most {max} allowed")]
+    TooLong { len: usize, max: usize },
+    #[error("contains control character {0:?}")]
+    ControlChar(char),
+    #[error("not in the vocabulary")]
+    NotInVocabulary,
+}
+
+impl TagPolicy {
+    pub fn check(&self, tag: &Tag) -> Result<(), TagViolation> {
+        let len = tag.value.chars().count();
+        if let Some(max) = self.max_len.filter(|max| len > *max) {
+            return Err(TagViolation::TooLong { len, max });
+        }
+        if self.forbid_control {
+            if let Some(c) = tag.value.chars().find(|c| c.is_control()) {
+                return Err(TagViolation::ControlChar(c));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    pub fn tag_policy(&self) -> TagPolicy {
+        self.policy
+    }
+
+    /// Takes effect for tags added from now on; existing tags are not re-checked.
+    pub fn set_tag_policy(&mut self, policy: TagPolicy) {
+        self.policy = policy;
+    }
+
+    /// Only allow adding tags from `vocabulary`, matched caselessly. It's checked wherever the
+    /// [`TagPolicy`] is, including [`State::add`], and like it doesn't affect tags already in the
+    /// state. The vocabulary is saved with the state.
+    pub fn with_vocabulary(mut self, vocabulary: HashSet<Tag>) -> Self {
+        self.set_vocabulary(Some(vocabulary));
+        self
+    }
+
+    /// Replace the vocabulary; `None` allows any tag again.
+    pub fn set_vocabulary(&mut self, vocabulary: Option<HashSet<Tag>>) {
+        self.vocabulary = vocabulary.map(|tags| tags.into_iter().collect());
+    }
+
+    pub fn vocabulary(&self) -> Option<impl Iterator<Item = &Tag>> {
+        self.vocabulary.as_ref().map(|tags| tags.iter())
+    }
+
+    /// The vocabulary tags closest to `near` by (caseless) edit distance, for suggesting a fix
+    /// when a tag is rejected. Empty if there is no vocabulary or nothing comes close.
+    pub fn suggest(&self, near: &str) -> Vec<&Tag> {
+        let near = Tag::from(near);
+        let vocabulary = match &self.vocabulary {
+            Some(vocabulary) => vocabulary,
+            None => return vec![],
+        };
+        let distances: Vec<(usize, &Tag)> = vocabulary
+            .iter()
+            .map(|tag| (edit_distance(&near.folded, &tag.folded), tag))
+            .collect();
+        // A distance as large as the word itself means rewriting all of it.
+        match distances.iter().map(|(distance, _)| *distance).min() {
+            Some(best) if best < near.folded.chars().count() => distances
+                .into_iter()
+                .filter(|(distance, _)| *distance == best)
+                .map(|(_, tag)| tag)
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Check `tag` against the policy and vocabulary before it's added.
+    pub(crate) fn check_tag(&self, tag: &Tag) -> Result<(), FilepersonError> {
+        let reject = |reason| FilepersonError::TagRejected {
+            tag: tag.value.to_string(),
+            reason,
+        };
+        self.policy.check(tag).map_err(reject)?;
+        match &self.vocabulary {
+            Some(vocabulary) if !vocabulary.contains(tag) => {
+                Err(reject(TagViolation::NotInVocabulary))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the tag `op` would add, if any. Every mutation that adds tags checks its ops with
+    /// this before recording any of them; undo and replay don't, they only bring back what was
+    /// accepted before.
+    pub(crate) fn check_op(&self, op: &Op) -> Result<(), FilepersonError> {
+        match op {
+            Op::AddTag { tag, .. }
+            | Op::RenameTag { to: tag, .. }
+            | Op::MergeTags { target: tag, .. } => self.check_tag(tag),
+            Op::RemoveTag { .. }
+            | Op::SetDelete { .. }
+            | Op::Track { .. }
+            | Op::Untrack { .. }
+            | Op::MoveFile { .. } => Ok(()),
+        }
+    }
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(row[j]).min(above)
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::state_fixture, FileInfo, FilepersonError};
+
+    use super::*;
+
+    #[test]
+    fn test_policy_rejects_long_tag() {
+        let mut state = state_fixture();
+        state.set_tag_policy(TagPolicy {
+            max_len: Some(128),
+            ..TagPolicy::default()
+        });
+        let long = "x".repeat(300);
+        match state.add_tag("/music/a.wav", long.as_str()) {
+            Err(FilepersonError::TagRejected { reason, .. }) => {
+                assert_eq!(reason, TagViolation::TooLong { len: 300, max: 128 })
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(state
+            .add_tag("/music/a.wav", "x".repeat(128).as_str())
+            .unwrap());
+        assert_eq!(state.infos.len(), 1);
+    }
+
+    #[test]
+    fn test_policy_checked_by_bulk_edits() {
+        let mut state = state_fixture();
+        state.add_tag("/music/a.wav", "rock").unwrap();
+        state.set_tag_policy(TagPolicy {
+            max_len: Some(5),
+            ..TagPolicy::default()
+        });
+        let too_long = |result: Result<usize, FilepersonError>| {
+            matches!(
+                result,
+                Err(FilepersonError::TagRejected {
+                    reason: TagViolation::TooLong { len: 11, max: 5 },
+                    ..
+                })
+            )
+        };
+        assert!(too_long(state.rename_tag(&"rock".into(), "rock-n-roll")));
+        assert!(too_long(state.merge_tags(&["rock".into()], "rock-n-roll")));
+        assert!(too_long(state.tag_where(|_| true, "rock-n-roll")));
+        assert_eq!(
+            state.get("/music/a.wav".into()).unwrap().tags(),
+            &vec![Tag::from("rock")]
+        );
+    }
+
+    #[test]
+    fn test_default_policy_rejects_newline() {
+        let mut state = state_fixture();
+        // `Tag::from` would collapse the newline into a space.
+        let tag = Tag::from_value("rock\nroll".into());
+        let err = state.add_tag("/music/a.wav", tag.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"tag "rock\nroll" rejected: contains control character '\n'"#
+        );
+        let mut info = FileInfo::from("/music/a.wav");
+        info.add_tag(tag);
+        assert!(state.add(info).is_err(), "whole infos are checked too");
+        assert!(state.infos.is_empty());
+    }
+
+    fn vocabulary_fixture() -> State {
+        let vocabulary = ["Drums", "Bass", "Pad", "Pads"];
+        state_fixture().with_vocabulary(vocabulary.iter().map(|&t| t.into()).collect())
+    }
+
+    #[test]
+    fn test_vocabulary_accepts_caselessly() -> anyhow::Result<()> {
+        let mut state = vocabulary_fixture();
+        assert!(state.add_tag("/music/a.wav", "drums")?);
+        assert!(state.set_tags("/music/b.wav", ["BASS", "pad"])?);
+        assert!(!state.set_tags("/music/b.wav", ["BASS", "pad"])?);
+        assert_eq!(state.files_with_tag(&"Drums".into()).count(), 1);
+        assert_eq!(state.files_with_tag(&"pad".into()).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vocabulary_rejects() {
+        let mut state = vocabulary_fixture();
+        state.add_tag("/music/a.wav", "bass").unwrap();
+        let err = state
+            .set_tags("/music/a.wav", ["pad", "kazoo"])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"tag "kazoo" rejected: not in the vocabulary"#
+        );
+        assert_eq!(
+            state.get("/music/a.wav".into()).unwrap().tags(),
+            &vec![Tag::from("bass")],
+            "a rejected set_tags changes nothing"
+        );
+
+        state.set_vocabulary(None);
+        assert!(state.add_tag("/music/a.wav", "kazoo").unwrap());
+    }
+
+    #[test]
+    fn test_vocabulary_checked_by_add() {
+        let mut state = vocabulary_fixture();
+        let info = |path: &str, tag: &str| {
+            let mut info = FileInfo::from(path);
+            info.add_tag(tag);
+            info
+        };
+        let err = state.add(info("/music/a.wav", "kazoo")).unwrap_err();
+        assert!(matches!(
+            err,
+            FilepersonError::TagRejected {
+                reason: TagViolation::NotInVocabulary,
+                ..
+            }
+        ));
+        assert!(state.infos.is_empty());
+
+        state.extend([info("/music/a.wav", "kazoo"), info("/music/b.wav", "pad")]);
+        assert_eq!(
+            state.infos.iter().map(|f| f.path()).collect::<Vec<_>>(),
+            vec!["/music/b.wav"]
+        );
+    }
+
+    #[test]
+    fn test_vocabulary_checked_by_bulk_edits() {
+        let mut state = vocabulary_fixture();
+        state.add_tag("/music/a.wav", "drums").unwrap();
+        let rejected = |result: Result<usize, FilepersonError>| {
+            matches!(
+                result,
+                Err(FilepersonError::TagRejected {
+                    reason: TagViolation::NotInVocabulary,
+                    ..
+                })
+            )
+        };
+        assert!(rejected(state.rename_tag(&"drums".into(), "kazoo")));
+        assert!(rejected(state.merge_tags(&["drums".into()], "kazoo")));
+        assert!(rejected(state.tag_where(|_| true, "kazoo")));
+        assert_eq!(
+            state.get("/music/a.wav".into()).unwrap().tags(),
+            &vec![Tag::from("drums")]
+        );
+        assert_eq!(state.rename_tag(&"drums".into(), "pad").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_suggest_near_miss() {
+        let state = vocabulary_fixture();
+        let suggest =
+            |near| -> Vec<String> { state.suggest(near).iter().map(|t| t.to_string()).collect() };
+        assert_eq!(suggest("drmus"), vec!["Drums"]);
+        assert_eq!(suggest("PAD"), vec!["Pad"]);
+        assert_eq!(suggest("pda"), vec!["Pad", "Pads"]);
+        assert!(suggest("xyz").is_empty());
+        assert!(state_fixture().suggest("drums").is_empty());
+    }
+}
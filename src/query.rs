@@ -0,0 +1,200 @@
+//! Boolean tag queries like `rock AND NOT (live OR demo)`.
+
+use std::{iter::Peekable, str::FromStr};
+
+use thiserror::Error;
+
+use crate::{FileInfo, State, Tag, TagCasing};
+
+/// A boolean expression over tags. `NOT` binds tighter than `AND`, which binds tighter than `OR`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagQuery {
+    Tag(Tag),
+    And(Box<TagQuery>, Box<TagQuery>),
+    Or(Box<TagQuery>, Box<TagQuery>),
+    Not(Box<TagQuery>),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum QueryError {
+    #[error("unexpected end of query")]
+    UnexpectedEnd,
+    #[error("unexpected {0:?} in query")]
+    UnexpectedToken(String),
+}
+
+impl TagQuery {
+    /// Parse whitespace-delimited tags, the keywords `AND`, `OR` and `NOT` (upper case only,
+    /// so lower-case `and` is still a tag) and parentheses.
+    pub fn parse(s: &str) -> Result<Self, QueryError> {
+        let spaced = s.replace('(', " ( ").replace(')', " ) ");
+        let mut tokens = spaced.split_whitespace().peekable();
+        let query = parse_or(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(query),
+            Some(token) => Err(QueryError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    /// Whether a file with `tags` satisfies the query, comparing tags according to `casing`.
+    /// A namespace like `genre:` stands for any tag beneath it.
+    pub fn matches(&self, tags: &[Tag], casing: TagCasing) -> bool {
+        match self {
+            Self::Tag(tag) => tags.iter().any(|t| casing.covers(tag, t)),
+            Self::And(a, b) => a.matches(tags, casing) && b.matches(tags, casing),
+            Self::Or(a, b) => a.matches(tags, casing) || b.matches(tags, casing),
+            Self::Not(q) => !q.matches(tags, casing),
+        }
+    }
+}
+
+impl FromStr for TagQuery {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn parse_or<'a, I: Iterator<Item = &'a str>>(
+    tokens: &mut Peekable<I>,
+) -> Result<TagQuery, QueryError> {
+    let mut query = parse_and(tokens)?;
+    while tokens.next_if_eq(&"OR").is_some() {
+        query = TagQuery::Or(Box::new(query), Box::new(parse_and(tokens)?));
+    }
+    Ok(query)
+}
+
+fn parse_and<'a, I: Iterator<Item = &'a str>>(
+    tokens: &mut Peekable<I>,
+) -> Result<TagQuery, QueryError> {
+    let mut query = parse_not(tokens)?;
+    while tokens.next_if_eq(&"AND").is_some() {
+        query = TagQuery::And(Box::new(query), Box::new(parse_not(tokens)?));
+    }
+    Ok(query)
+}
+
+fn parse_not<'a, I: Iterator<Item = &'a str>>(
+    tokens: &mut Peekable<I>,
+) -> Result<TagQuery, QueryError> {
+    match tokens.next().ok_or(QueryError::UnexpectedEnd)? {
+        "NOT" => Ok(TagQuery::Not(Box::new(parse_not(tokens)?))),
+        "(" => {
+            let query = parse_or(tokens)?;
+            match tokens.next() {
+                Some(")") => Ok(query),
+                Some(token) => Err(QueryError::UnexpectedToken(token.to_string())),
+                None => Err(QueryError::UnexpectedEnd),
+            }
+        }
+        token @ (")" | "AND" | "OR") => Err(QueryError::UnexpectedToken(token.to_string())),
+        tag => Ok(TagQuery::Tag(Tag::from(tag))),
+    }
+}
+
+impl State {
+    /// Tracked files matching `query`, compared according to the state's [`TagCasing`].
+    pub fn query<'a: 'q, 'q>(
+        &'a self,
+        query: &'q TagQuery,
+    ) -> impl Iterator<Item = &'a FileInfo> + 'q {
+        self.infos
+            .iter()
+            .filter(move |f| query.matches(&f.tags, self.casing))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+    use itertools::Itertools;
+
+    use crate::tests::state_fixture;
+
+    use super::*;
+
+    fn tagged_state() -> State {
+        let mut state = state_fixture();
+        for (path, tags) in [
+            ("/music/a.wav", &["genre:rock", "live"][..]),
+            ("/music/b.wav", &["Genre:Rock"]),
+            ("/music/c.wav", &["jazz", "live"]),
+            ("/music/d.wav", &["demo"]),
+        ] {
+            for tag in tags {
+                state.add_tag(path, *tag).unwrap();
+            }
+        }
+        state
+    }
+
+    fn query_paths<'a>(state: &'a State, query: &str) -> Vec<&'a Utf8Path> {
+        let query = TagQuery::parse(query).unwrap();
+        state.query(&query).map(FileInfo::path).sorted().collect()
+    }
+
+    #[test]
+    fn test_query_and_not() {
+        let state = tagged_state();
+        assert_eq!(
+            TagQuery::parse("genre:rock AND NOT live"),
+            Ok(TagQuery::And(
+                Box::new(TagQuery::Tag("genre:rock".into())),
+                Box::new(TagQuery::Not(Box::new(TagQuery::Tag("live".into())))),
+            ))
+        );
+        assert_eq!(
+            query_paths(&state, "GENRE:ROCK AND NOT live"),
+            vec![Utf8Path::new("/music/b.wav")]
+        );
+    }
+
+    #[test]
+    fn test_query_parenthesized_or() {
+        let state = tagged_state();
+        assert_eq!(
+            query_paths(&state, "live AND (jazz OR genre:rock)"),
+            vec![Utf8Path::new("/music/a.wav"), Utf8Path::new("/music/c.wav")]
+        );
+        assert_eq!(
+            query_paths(&state, "NOT (live OR demo)"),
+            vec![Utf8Path::new("/music/b.wav")]
+        );
+    }
+
+    #[test]
+    fn test_query_namespace() {
+        let state = tagged_state();
+        assert_eq!(
+            query_paths(&state, "genre: AND NOT live"),
+            vec![Utf8Path::new("/music/b.wav")]
+        );
+        assert_eq!(
+            query_paths(&state, "genre: OR jazz"),
+            vec![
+                Utf8Path::new("/music/a.wav"),
+                Utf8Path::new("/music/b.wav"),
+                Utf8Path::new("/music/c.wav")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_parse_errors() {
+        assert_eq!(TagQuery::parse("rock AND"), Err(QueryError::UnexpectedEnd));
+        assert_eq!(
+            TagQuery::parse("(rock OR jazz"),
+            Err(QueryError::UnexpectedEnd)
+        );
+        assert_eq!(
+            TagQuery::parse("rock jazz"),
+            Err(QueryError::UnexpectedToken("jazz".into()))
+        );
+        assert_eq!(
+            TagQuery::parse("OR rock"),
+            Err(QueryError::UnexpectedToken("OR".into()))
+        );
+    }
+}
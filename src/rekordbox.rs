@@ -0,0 +1,314 @@
+//! Rekordbox XML collections of the files matching a tag query, with a playlist per tag, so a
+//! selection can be imported into Rekordbox and exported to USB from there.
+
+use std::{collections::BTreeMap, io::Write};
+
+use camino::Utf8Path;
+use itertools::Itertools;
+
+use crate::{FileInfo, FilepersonError, State, Tag, TagCasing, TagQuery};
+
+/// Rekordbox's ratings for zero to five stars.
+const STARS: [u8; 6] = [0, 51, 102, 153, 204, 255];
+
+/// A playlist folder or playlist, named by one tag segment.
+#[derive(Default)]
+struct Node {
+    name: String,
+    /// Track IDs, for the files carrying exactly this tag.
+    tracks: Vec<usize>,
+    /// By the segment's compare key under the state's casing.
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn insert<'a>(
+        &mut self,
+        mut segments: impl Iterator<Item = &'a str>,
+        casing: TagCasing,
+        id: usize,
+    ) {
+        match segments.next() {
+            Some(segment) => {
+                let key = match casing {
+                    TagCasing::Insensitive => caseless::default_case_fold_str(segment),
+                    TagCasing::Sensitive => segment.to_string(),
+                };
+                self.children
+                    .entry(key)
+                    .or_insert_with(|| Node {
+                        name: segment.to_string(),
+                        ..Node::default()
+                    })
+                    .insert(segments, casing, id)
+            }
+            None if self.tracks.last() != Some(&id) => self.tracks.push(id),
+            None => {}
+        }
+    }
+
+    /// Write this node's children. A tag that is also a namespace, like `techno` next to
+    /// `techno/minimal`, becomes a folder holding a playlist of the same name first.
+    fn write_children(&self, w: &mut impl Write, depth: usize) -> std::io::Result<()> {
+        for child in self.children.values() {
+            let indent = "  ".repeat(depth);
+            if child.children.is_empty() {
+                child.write_playlist(w, depth)?;
+                continue;
+            }
+            let count = child.children.len() + usize::from(!child.tracks.is_empty());
+            writeln!(
+                w,
+                r#"{}<NODE Type="0" Name="{}" Count="{}">"#,
+                indent,
+                escape(&child.name),
+                count
+            )?;
+            if !child.tracks.is_empty() {
+                child.write_playlist(w, depth + 1)?;
+            }
+            child.write_children(w, depth + 1)?;
+            writeln!(w, "{}</NODE>", indent)?;
+        }
+        Ok(())
+    }
+
+    fn write_playlist(&self, w: &mut impl Write, depth: usize) -> std::io::Result<()> {
+        let indent = "  ".repeat(depth);
+        writeln!(
+            w,
+            r#"{}<NODE Type="1" Name="{}" KeyType="0" Entries="{}">"#,
+            indent,
+            escape(&self.name),
+            self.tracks.len()
+        )?;
+        for id in &self.tracks {
+            writeln!(w, r#"{}  <TRACK Key="{}"/>"#, indent, id)?;
+        }
+        writeln!(w, "{}</NODE>", indent)
+    }
+}
+
+/// `s` as an XML attribute value.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\r' | '\t' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The `file://localhost/...` URL Rekordbox locates a file by.
+fn location(path: &Utf8Path) -> String {
+    let mut url = String::from("file://localhost");
+    if !path.as_str().starts_with('/') {
+        url.push('/');
+    }
+    for b in path.as_str().replace('\\', "/").bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                url.push(char::from(b))
+            }
+            b => url.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    url
+}
+
+/// The star count of a `rating:N` tag, like the iTunes import writes.
+fn stars(tags: &[Tag]) -> Option<u8> {
+    tags.iter().find_map(|tag| {
+        let (namespace, value) = tag.value.split_once(':')?;
+        if !namespace.eq_ignore_ascii_case("rating") {
+            return None;
+        }
+        value.parse().ok().filter(|stars| *stars <= 5)
+    })
+}
+
+fn write_track(
+    w: &mut impl Write,
+    id: usize,
+    info: &FileInfo,
+    on_disk: &Utf8Path,
+) -> std::io::Result<()> {
+    let audio = info.audio.as_ref();
+    let text = |key: &str| {
+        audio
+            .and_then(|audio| audio.tags.get(key))
+            .map(String::as_str)
+    };
+    let name = text("TrackTitle")
+        .or_else(|| on_disk.file_stem())
+        .unwrap_or_default();
+    write!(
+        w,
+        r#"    <TRACK TrackID="{}" Name="{}" Artist="{}" Genre="{}""#,
+        id,
+        escape(name),
+        escape(text("TrackArtist").unwrap_or_default()),
+        escape(text("Genre").unwrap_or_default()),
+    )?;
+    if let Some(extension) = on_disk.extension() {
+        write!(w, r#" Kind="{} File""#, escape(&extension.to_uppercase()))?;
+    }
+    if let Some(size) = info.size {
+        write!(w, r#" Size="{}""#, size)?;
+    }
+    if let Some(duration) = info.duration {
+        write!(
+            w,
+            r#" TotalTime="{}""#,
+            duration.as_secs_f64().round() as u64
+        )?;
+    }
+    if let Some(sample_rate) = audio.and_then(|audio| audio.sample_rate) {
+        write!(w, r#" SampleRate="{}""#, sample_rate)?;
+    }
+    let rating = stars(&info.tags).map_or(0, |stars| STARS[usize::from(stars)]);
+    writeln!(
+        w,
+        r#" Rating="{}" Location="{}"/>"#,
+        rating,
+        escape(&location(on_disk))
+    )
+}
+
+impl State {
+    /// Write the tracked files matching `query` as a Rekordbox XML collection, for Rekordbox's
+    /// "Imported Library". Every tag of those files becomes a playlist of the matching files
+    /// that carry it; namespaces become folders, so `genre:techno` is the playlist `techno` in
+    /// the folder `genre`. Tags are grouped according to the state's [`TagCasing`].
+    ///
+    /// Titles, artists, genres and sample rates come from probed metadata where there is any,
+    /// and `rating:N` tags become star ratings. Returns the number of tracks written.
+    pub fn export_rekordbox(
+        &self,
+        query: &TagQuery,
+        mut w: impl Write,
+    ) -> Result<usize, FilepersonError> {
+        let infos: Vec<&FileInfo> = self
+            .query(query)
+            .sorted_by_key(|info| info.path())
+            .collect();
+        let mut root = Node::default();
+        for (id, info) in (1..).zip(&infos) {
+            for tag in &info.tags {
+                root.insert(tag.segments(), self.casing, id);
+            }
+        }
+
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(w, r#"<DJ_PLAYLISTS Version="1.0.0">"#)?;
+        writeln!(
+            w,
+            r#"  <PRODUCT Name="fileperson" Version="{}" Company=""/>"#,
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(w, r#"  <COLLECTION Entries="{}">"#, infos.len())?;
+        for (id, info) in (1..).zip(&infos) {
+            write_track(&mut w, id, info, &self.absolute_path(&info.path))?;
+        }
+        writeln!(w, "  </COLLECTION>")?;
+        writeln!(w, "  <PLAYLISTS>")?;
+        writeln!(
+            w,
+            r#"    <NODE Type="0" Name="ROOT" Count="{}">"#,
+            root.children.len()
+        )?;
+        root.write_children(&mut w, 3)?;
+        writeln!(w, "    </NODE>")?;
+        writeln!(w, "  </PLAYLISTS>")?;
+        writeln!(w, "</DJ_PLAYLISTS>")?;
+        w.flush()?;
+        Ok(infos.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::tests::state_fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        assert_eq!(
+            location("/Users/dj/Music/pad & drone #2.wav".into()),
+            "file://localhost/Users/dj/Music/pad%20%26%20drone%20%232.wav"
+        );
+        assert_eq!(
+            location(r"C:\Music\a.wav".into()),
+            "file://localhost/C:/Music/a.wav"
+        );
+    }
+
+    #[test]
+    fn test_export_rekordbox() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        state.add_tag("/music/kick.wav", "gig")?;
+        state.add_tag("/music/kick.wav", "genre:techno")?;
+        state.add_tag("/music/kick.wav", "rating:4")?;
+        state.add_tag("/music/hat.wav", "gig")?;
+        state.add_tag("/music/hat.wav", "genre:Techno/minimal")?;
+        state.add_tag("/music/pad.wav", "genre:ambient")?;
+        let mut kick = state.take_info("/music/kick.wav".into()).unwrap();
+        kick.duration = Some(Duration::from_secs(3));
+        kick.size = Some(1234);
+        state.infos.insert(kick);
+
+        let mut out = vec![];
+        let written = state.export_rekordbox(&"gig".parse()?, &mut out)?;
+        assert_eq!(written, 2);
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            String::from_utf8(out)?,
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<DJ_PLAYLISTS Version="1.0.0">
+  <PRODUCT Name="fileperson" Version="{version}" Company=""/>
+  <COLLECTION Entries="2">
+    <TRACK TrackID="1" Name="hat" Artist="" Genre="" Kind="WAV File" Rating="0" Location="file://localhost/music/hat.wav"/>
+    <TRACK TrackID="2" Name="kick" Artist="" Genre="" Kind="WAV File" Size="1234" TotalTime="3" Rating="204" Location="file://localhost/music/kick.wav"/>
+  </COLLECTION>
+  <PLAYLISTS>
+    <NODE Type="0" Name="ROOT" Count="3">
+      <NODE Type="0" Name="genre" Count="1">
+        <NODE Type="0" Name="Techno" Count="2">
+          <NODE Type="1" Name="Techno" KeyType="0" Entries="1">
+            <TRACK Key="2"/>
+          </NODE>
+          <NODE Type="1" Name="minimal" KeyType="0" Entries="1">
+            <TRACK Key="1"/>
+          </NODE>
+        </NODE>
+      </NODE>
+      <NODE Type="1" Name="gig" KeyType="0" Entries="2">
+        <TRACK Key="1"/>
+        <TRACK Key="2"/>
+      </NODE>
+      <NODE Type="0" Name="rating" Count="1">
+        <NODE Type="1" Name="4" KeyType="0" Entries="1">
+          <TRACK Key="2"/>
+        </NODE>
+      </NODE>
+    </NODE>
+  </PLAYLISTS>
+</DJ_PLAYLISTS>
+"#,
+                version = version
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Tagging files by where they live, e.g. everything under `drums/` gets `drums`.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+
+use crate::{FilepersonError, Op, State, Tag};
+
+/// Matches a file's path relative to the root it was loaded from.
+#[derive(Clone, Debug)]
+pub enum GlobOrRegex {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl GlobOrRegex {
+    /// A glob with the same syntax as [`crate::load_with_globs`]: `*` does not cross directory
+    /// boundaries, `**` does.
+    pub fn glob(pattern: &str) -> Result<Self, FilepersonError> {
+        let glob = GlobBuilder::new(pattern).literal_separator(true).build()?;
+        Ok(Self::Glob(glob.compile_matcher()))
+    }
+
+    pub fn is_match(&self, relative: &Utf8Path) -> bool {
+        match self {
+            Self::Glob(glob) => glob.is_match(relative),
+            Self::Regex(re) => re.is_match(relative.as_str()),
+        }
+    }
+}
+
+impl From<Regex> for GlobOrRegex {
+    fn from(re: Regex) -> Self {
+        Self::Regex(re)
+    }
+}
+
+/// Add `tags` to every file whose relative path matches `matcher`, see [`State::apply_rules`].
+#[derive(Clone, Debug)]
+pub struct TagRule {
+    pub matcher: GlobOrRegex,
+    pub tags: Vec<Tag>,
+}
+
+impl State {
+    /// Add the tags of every matching rule to each file in the tree, tracking files that aren't
+    /// yet, as a single undoable edit. Paths are matched relative to the root they were loaded
+    /// from. Returns the number of files that gained at least one tag, and fails without
+    /// changing anything if any of the rules' tags is rejected, like [`State::add_tag`].
+    pub fn apply_rules(&mut self, rules: &[TagRule]) -> Result<usize, FilepersonError> {
+        for tag in rules.iter().flat_map(|rule| &rule.tags) {
+            self.check_tag(tag)?;
+        }
+        let roots = self.walked_roots();
+        let matching: Vec<(Utf8PathBuf, Vec<Tag>)> = self
+            .root
+            .files()
+            .filter_map(|path| {
+                let relative = roots.iter().find_map(|root| path.strip_prefix(root).ok())?;
+                let tags: Vec<Tag> = rules
+                    .iter()
+                    .filter(|rule| rule.matcher.is_match(relative))
+                    .flat_map(|rule| rule.tags.iter().cloned())
+                    .collect();
+                (!tags.is_empty()).then(|| (path.to_owned(), tags))
+            })
+            .collect();
+        let paths = matching.iter().map(|(path, _)| path.clone()).collect();
+        Ok(self.track_edit(paths, |state| {
+            matching
+                .into_iter()
+                .filter(|(path, tags)| {
+                    tags.iter().fold(false, |changed, tag| {
+                        let added = state.record(Op::AddTag {
+                            path: path.clone(),
+                            tag: tag.clone(),
+                        }) > 0;
+                        changed || added
+                    })
+                })
+                .count()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::{tests::snapshot, Directory, FsNode};
+
+    use super::*;
+
+    #[test]
+    fn test_apply_rules_directory_scoped() -> anyhow::Result<()> {
+        let mut state = State::from_tree(Directory {
+            this: "/music".into(),
+            entries: vec![
+                FsNode::File("/music/bass.wav".into()),
+                FsNode::Directory(Directory {
+                    this: "/music/drums".into(),
+                    entries: vec![
+                        FsNode::File("/music/drums/kick.wav".into()),
+                        FsNode::Directory(Directory {
+                            this: "/music/drums/loops".into(),
+                            entries: vec![FsNode::File("/music/drums/loops/break.wav".into())],
+                            lossy: BTreeSet::new(),
+                        }),
+                    ],
+                    lossy: BTreeSet::new(),
+                }),
+            ],
+            lossy: BTreeSet::new(),
+        });
+        state.add_tag("/music/drums/kick.wav", "drums")?;
+
+        let rules = [
+            TagRule {
+                matcher: GlobOrRegex::glob("drums/**")?,
+                tags: vec!["drums".into(), "perc".into()],
+            },
+            TagRule {
+                // Relative paths start at the root, so this matches nothing.
+                matcher: Regex::new(r"^loops/")?.into(),
+                tags: vec!["loop".into()],
+            },
+        ];
+        assert_eq!(state.apply_rules(&rules)?, 2);
+        assert_eq!(
+            snapshot(&state),
+            vec![
+                (
+                    "/music/drums/kick.wav".into(),
+                    vec!["drums".to_string(), "perc".to_string()],
+                    None
+                ),
+                (
+                    "/music/drums/loops/break.wav".into(),
+                    vec!["drums".to_string(), "perc".to_string()],
+                    None
+                ),
+            ]
+        );
+
+        assert!(state.undo());
+        assert_eq!(state.files_with_tag(&"perc".into()).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_rules_checks_vocabulary() -> anyhow::Result<()> {
+        let tree = Directory {
+            this: "/music".into(),
+            entries: vec![FsNode::File("/music/kick.wav".into())],
+            lossy: BTreeSet::new(),
+        };
+        let mut state = State::from_tree(tree).with_vocabulary(["drums".into()].into());
+        let rules = [TagRule {
+            matcher: GlobOrRegex::glob("*.wav")?,
+            tags: vec!["drums".into(), "kazoo".into()],
+        }];
+        assert!(matches!(
+            state.apply_rules(&rules),
+            Err(FilepersonError::TagRejected { .. })
+        ));
+        assert!(state.infos.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+//! Tracked files kept in an SQLite database rather than in memory, for very large libraries.
+//!
+//! This is a store next to [`State`], not a backend for it: a `State` always holds its infos in
+//! memory. The store answers tag lookups from its index without loading anything, and loads
+//! the slice of the library you want to work on, e.g. one tag's files, into a `State` with the
+//! full API.
+
+use std::{
+    convert::TryFrom,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use rusqlite::{params, Connection, OptionalExtension, Params, Row, Transaction};
+
+use crate::{FileInfo, FilepersonError, State, Tag};
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS files (
+        path TEXT PRIMARY KEY,
+        delete_flag INTEGER,
+        size INTEGER,
+        modified_ns INTEGER,
+        duration_ns INTEGER,
+        preview TEXT
+    );
+    CREATE TABLE IF NOT EXISTS tags (
+        path TEXT NOT NULL REFERENCES files(path) ON DELETE CASCADE,
+        value TEXT NOT NULL,
+        folded TEXT NOT NULL,
+        color TEXT,
+        description TEXT,
+        PRIMARY KEY (path, folded)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS tags_by_folded ON tags (folded);
+";
+
+/// The infos of a [`State`], stored one row per file plus one row per tag, with an index on
+/// the case-folded tag value. Lookups read only the records they need, so a library of
+/// hundreds of thousands of files doesn't have to fit in memory at once.
+///
+/// Tags are matched caselessly, as with the default [`TagCasing`](crate::TagCasing). The
+/// state's [`TagPolicy`](crate::TagPolicy) and vocabulary aren't enforced here, and changes
+/// made through the store aren't undoable; load files into a [`State`] with
+/// [`SqliteStore::export_into`] or [`SqliteStore::export_tagged_into`] for that.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it and its tables as needed.
+    pub fn open(path: impl AsRef<Utf8Path>) -> Result<Self, FilepersonError> {
+        Self::with_connection(Connection::open(path.as_ref())?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, FilepersonError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self, FilepersonError> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Write every info of `state` to the store in one transaction, replacing the records
+    /// already stored for the same paths. Returns the number of infos written.
+    pub fn import(&mut self, state: &State) -> Result<usize, FilepersonError> {
+        let tx = self.conn.transaction()?;
+        for info in &state.infos {
+            put(&tx, info)?;
+        }
+        tx.commit()?;
+        Ok(state.infos.len())
+    }
+
+    /// Add every stored info to `state`, replacing what it tracked for the same paths. Returns
+    /// the number of infos added.
+    pub fn export_into(&self, state: &mut State) -> Result<usize, FilepersonError> {
+        self.load_into(state, "", [])
+    }
+
+    /// Like [`SqliteStore::export_into`], but only the files carrying `tag`, with all of their
+    /// tags. The rest of the library stays on disk.
+    pub fn export_tagged_into(
+        &self,
+        tag: &Tag,
+        state: &mut State,
+    ) -> Result<usize, FilepersonError> {
+        self.load_into(
+            state,
+            "WHERE f.path IN (SELECT path FROM tags WHERE folded = ?1)",
+            [&*tag.folded],
+        )
+    }
+
+    /// Add the infos selected by `filter`, a `WHERE` clause over `files f`, to `state`. Files
+    /// and their tags come from a single joined query, one row per tag, sorted by path.
+    fn load_into(
+        &self,
+        state: &mut State,
+        filter: &str,
+        params: impl Params,
+    ) -> Result<usize, FilepersonError> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT f.path, f.delete_flag, f.size, f.modified_ns, f.duration_ns, f.preview,
+                    t.value, t.color, t.description
+             FROM files f LEFT JOIN tags t ON t.path = f.path
+             {}
+             ORDER BY f.path, t.folded",
+            filter
+        ))?;
+        let mut rows = stmt.query(params)?;
+        let mut count = 0;
+        let mut current: Option<(FileInfo, Vec<Tag>)> = None;
+        let mut flush = |current: Option<(FileInfo, Vec<Tag>)>| -> Result<(), FilepersonError> {
+            if let Some((mut info, tags)) = current {
+                info.set_tags(tags);
+                state.add(info)?;
+                count += 1;
+            }
+            Ok(())
+        };
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(0)?;
+            if current.as_ref().map(|(info, _)| info.path.as_str()) != Some(path.as_str()) {
+                flush(current.take())?;
+                current = Some((info_from_row(row)?, vec![]));
+            }
+            if row.get::<_, Option<String>>(6)?.is_some() {
+                if let Some((_, tags)) = &mut current {
+                    tags.push(tag_from_row(row, 6)?);
+                }
+            }
+        }
+        flush(current)?;
+        Ok(count)
+    }
+
+    /// Number of stored infos.
+    pub fn len(&self) -> Result<usize, FilepersonError> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, FilepersonError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The info stored for `path`, read from the database on each call.
+    pub fn get(&self, path: &Utf8Path) -> Result<Option<FileInfo>, FilepersonError> {
+        let info = self
+            .conn
+            .query_row(
+                "SELECT path, delete_flag, size, modified_ns, duration_ns, preview
+                 FROM files WHERE path = ?1",
+                [path.as_str()],
+                info_from_row,
+            )
+            .optional()?;
+        let mut info = match info {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT value, color, description FROM tags WHERE path = ?1 ORDER BY folded",
+        )?;
+        let tags = stmt
+            .query_map([path.as_str()], |row| tag_from_row(row, 0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        info.set_tags(tags);
+        Ok(Some(info))
+    }
+
+    /// Paths of the files carrying `tag`, sorted. Served from the tag index.
+    pub fn files_with_tag(&self, tag: &Tag) -> Result<Vec<Utf8PathBuf>, FilepersonError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT path FROM tags WHERE folded = ?1 ORDER BY path")?;
+        let paths = stmt
+            .query_map([&*tag.folded], |row| row.get::<_, String>(0))?
+            .map(|path| path.map(Utf8PathBuf::from))
+            .collect::<Result<_, _>>()?;
+        Ok(paths)
+    }
+
+    /// How many files carry each tag, sorted by folded value. Each tag is represented by its
+    /// lexically smallest spelling.
+    pub fn tag_counts(&self) -> Result<Vec<(Tag, usize)>, FilepersonError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT MIN(value), COUNT(*) FROM tags GROUP BY folded ORDER BY folded")?;
+        let counts = stmt
+            .query_map([], |row| {
+                let value: String = row.get(0)?;
+                let count: i64 = row.get(1)?;
+                Ok((Tag::from_value(value.into()), count as usize))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(counts)
+    }
+
+    /// Add `tag` to the file at `path`, storing the file if it isn't yet. Returns `false` if
+    /// the file already carried the tag.
+    pub fn add_tag(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        tag: impl Into<Tag>,
+    ) -> Result<bool, FilepersonError> {
+        let path = path.as_ref().as_str();
+        let tag = tag.into();
+        let tx = self.conn.transaction()?;
+        tx.execute("INSERT OR IGNORE INTO files (path) VALUES (?1)", [path])?;
+        let added = tx.execute(
+            "INSERT OR IGNORE INTO tags (path, value, folded, color, description)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                path,
+                &*tag.value,
+                &*tag.folded,
+                tag.color.map(|c| c.to_string()),
+                tag.description
+            ],
+        )?;
+        tx.commit()?;
+        Ok(added > 0)
+    }
+
+    /// Remove `tag` from the file at `path`. Returns `false` if the file didn't carry it.
+    pub fn remove_tag(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        tag: &Tag,
+    ) -> Result<bool, FilepersonError> {
+        let removed = self.conn.execute(
+            "DELETE FROM tags WHERE path = ?1 AND folded = ?2",
+            params![path.as_ref().as_str(), &*tag.folded],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Set the delete flag of the file at `path`, storing the file if it isn't yet.
+    pub fn set_delete(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        delete: Option<bool>,
+    ) -> Result<(), FilepersonError> {
+        self.conn.execute(
+            "INSERT INTO files (path, delete_flag) VALUES (?1, ?2)
+             ON CONFLICT (path) DO UPDATE SET delete_flag = excluded.delete_flag",
+            params![path.as_ref().as_str(), delete],
+        )?;
+        Ok(())
+    }
+
+    /// Forget the file at `path` and its tags. Returns `false` if it wasn't stored.
+    pub fn remove(&mut self, path: impl AsRef<Utf8Path>) -> Result<bool, FilepersonError> {
+        let removed = self.conn.execute(
+            "DELETE FROM files WHERE path = ?1",
+            [path.as_ref().as_str()],
+        )?;
+        Ok(removed > 0)
+    }
+}
+
+/// Replace the records of `info` with its current contents.
+fn put(tx: &Transaction, info: &FileInfo) -> Result<(), FilepersonError> {
+    let path = info.path.as_str();
+    tx.execute("DELETE FROM files WHERE path = ?1", [path])?;
+    tx.execute(
+        "INSERT INTO files (path, delete_flag, size, modified_ns, duration_ns, preview)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            path,
+            info.delete,
+            info.size.map(|size| size as i64),
+            info.modified
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(nanos),
+            info.duration.map(nanos),
+            info.preview.as_ref().map(|p| p.as_str()),
+        ],
+    )?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT OR IGNORE INTO tags (path, value, folded, color, description)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for tag in &info.tags {
+        stmt.execute(params![
+            path,
+            &*tag.value,
+            &*tag.folded,
+            tag.color.map(|c| c.to_string()),
+            tag.description
+        ])?;
+    }
+    Ok(())
+}
+
+/// Saturates rather than wrapping for durations past the year 2262.
+fn nanos(duration: Duration) -> i64 {
+    i64::try_from(duration.as_nanos()).unwrap_or(i64::MAX)
+}
+
+fn info_from_row(row: &Row) -> rusqlite::Result<FileInfo> {
+    let path: String = row.get(0)?;
+    let mut info = FileInfo::from(Utf8Path::new(&path));
+    info.delete = row.get(1)?;
+    info.size = row.get::<_, Option<i64>>(2)?.map(|size| size as u64);
+    info.modified = row
+        .get::<_, Option<i64>>(3)?
+        .map(|ns| SystemTime::UNIX_EPOCH + Duration::from_nanos(ns as u64));
+    info.duration = row
+        .get::<_, Option<i64>>(4)?
+        .map(|ns| Duration::from_nanos(ns as u64));
+    info.preview = row.get::<_, Option<String>>(5)?.map(Utf8PathBuf::from);
+    Ok(info)
+}
+
+/// The tag in the `value, color, description` columns starting at column `at`.
+fn tag_from_row(row: &Row, at: usize) -> rusqlite::Result<Tag> {
+    let value: String = row.get(at)?;
+    let mut tag = Tag::from_value(value.into());
+    // Colors were validated on the way in; a hand-edited bad one is dropped.
+    tag.color = row
+        .get::<_, Option<String>>(at + 1)?
+        .and_then(|c| c.parse().ok());
+    tag.description = row.get(at + 2)?;
+    Ok(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{snapshot, state_fixture};
+
+    use super::*;
+
+    #[test]
+    fn test_import_export_round_trip() -> anyhow::Result<()> {
+        let mut state = state_fixture();
+        state.add_tag(
+            "/music/a.wav",
+            Tag::from("Rock").with_color("#ff0000".parse()?),
+        )?;
+        state.add_tag("/music/a.wav", "live")?;
+        state.set_delete("/music/sub/b.wav", Some(true));
+        let mut info = FileInfo::from("/music/c.wav");
+        info.size = Some(1234);
+        info.modified = Some(UNIX_EPOCH + Duration::from_nanos(1_600_000_000_123_456_789));
+        info.set_delete(Some(false));
+        state.add(info)?;
+
+        let mut store = SqliteStore::open_in_memory()?;
+        assert_eq!(store.import(&state)?, 3);
+        assert_eq!(store.len()?, 3);
+
+        let mut loaded = state_fixture();
+        assert_eq!(store.export_into(&mut loaded)?, 3);
+        assert_eq!(snapshot(&loaded), snapshot(&state));
+        let c = loaded.get("/music/c.wav".into()).unwrap();
+        assert_eq!(c.size(), Some(1234));
+        assert_eq!(
+            c.modified(),
+            state.get("/music/c.wav".into()).unwrap().modified()
+        );
+        let rock = loaded.tags().find(|t| &*t.value == "Rock").unwrap();
+        assert_eq!(
+            rock.color().map(|c| c.to_string()).as_deref(),
+            Some("#ff0000")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_lookups() -> anyhow::Result<()> {
+        let mut store = SqliteStore::open_in_memory()?;
+        assert!(store.add_tag("/music/b.wav", "Rock")?);
+        assert!(store.add_tag("/music/a.wav", "rock")?);
+        assert!(!store.add_tag("/music/a.wav", "ROCK")?);
+        assert!(store.add_tag("/music/a.wav", "jazz")?);
+
+        assert_eq!(
+            store.files_with_tag(&"rOcK".into())?,
+            vec![Utf8PathBuf::from("/music/a.wav"), "/music/b.wav".into()]
+        );
+        let counts: Vec<(String, usize)> = store
+            .tag_counts()?
+            .into_iter()
+            .map(|(tag, count)| (tag.to_string(), count))
+            .collect();
+        assert_eq!(counts, vec![("jazz".into(), 1), ("Rock".into(), 2)]);
+
+        let mut rock = state_fixture();
+        assert_eq!(store.export_tagged_into(&"ROCK".into(), &mut rock)?, 2);
+        assert_eq!(
+            snapshot(&rock),
+            vec![
+                (
+                    "/music/a.wav".into(),
+                    vec!["jazz".to_string(), "rock".to_string()],
+                    None
+                ),
+                ("/music/b.wav".into(), vec!["Rock".to_string()], None),
+            ]
+        );
+
+        assert!(store.remove_tag("/music/b.wav", &"rock".into())?);
+        assert_eq!(store.files_with_tag(&"rock".into())?.len(), 1);
+        assert!(store.remove("/music/a.wav")?);
+        assert!(store.files_with_tag(&"jazz".into())?.is_empty());
+        assert_eq!(store.get("/music/b.wav".into())?.unwrap().tags(), &vec![]);
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+//! `fileperson tui`: arrow through the tree, audition files and edit their tags.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use fileperson::{FsNode, State, Tag};
+use itertools::Itertools;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+const HELP: &str =
+    "↑↓ move · space play · type a tag + enter to add/remove it · del mark · ^z/^y undo/redo · esc quit";
+
+/// One line of the tree pane.
+struct Row {
+    depth: usize,
+    path: Utf8PathBuf,
+    is_dir: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Control {
+    Continue,
+    Quit,
+}
+
+struct App {
+    rows: Vec<Row>,
+    list: ListState,
+    /// The tag being typed, if any.
+    input: Option<String>,
+    status: String,
+    dirty: bool,
+    player: Player,
+}
+
+/// Browse `state` until the user quits, then save it to `path` if anything changed.
+pub fn run(state: &mut State, path: &Utf8Path) -> anyhow::Result<()> {
+    let mut app = App::new(state);
+    let mut terminal = ratatui::init();
+    let result = app.event_loop(&mut terminal, state, path);
+    ratatui::restore();
+    result?;
+    if app.dirty {
+        state.save(path)?;
+    }
+    Ok(())
+}
+
+impl App {
+    fn new(state: &State) -> Self {
+        let rows = state
+            .tree()
+            .walk()
+            .map(|(depth, node)| Row {
+                depth,
+                path: node.path().to_owned(),
+                is_dir: matches!(node, FsNode::Directory(_)),
+            })
+            .collect::<Vec<_>>();
+        let mut list = ListState::default();
+        list.select((!rows.is_empty()).then_some(0));
+        Self {
+            rows,
+            list,
+            input: None,
+            status: HELP.to_string(),
+            dirty: false,
+            player: Player::default(),
+        }
+    }
+
+    fn event_loop(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        state: &mut State,
+        path: &Utf8Path,
+    ) -> anyhow::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame, state))?;
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    if key.code == KeyCode::Char('s') && key.modifiers == KeyModifiers::CONTROL {
+                        state.save(path)?;
+                        self.dirty = false;
+                        self.status = format!("saved {}", path);
+                    } else if self.handle_key(key, state) == Control::Quit {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn selected(&self) -> Option<&Row> {
+        self.list.selected().and_then(|i| self.rows.get(i))
+    }
+
+    /// The selected row, if it's a file.
+    fn selected_file(&self) -> Option<Utf8PathBuf> {
+        self.selected()
+            .filter(|row| !row.is_dir)
+            .map(|row| row.path.clone())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, state: &mut State) -> Control {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Esc => self.input = None,
+                KeyCode::Enter => {
+                    let tag = self.input.take().unwrap_or_default();
+                    self.toggle_tag(state, tag.trim());
+                }
+                KeyCode::Tab => {
+                    if let Some(tag) = state.tags_with_prefix(input).next() {
+                        *input = tag.to_string();
+                    }
+                }
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) if !ctrl => input.push(c),
+                _ => {}
+            }
+            return Control::Continue;
+        }
+
+        match key.code {
+            KeyCode::Esc => return Control::Quit,
+            KeyCode::Char('c') if ctrl => return Control::Quit,
+            KeyCode::Char('z') if ctrl => self.step(state, State::undo, "undo"),
+            KeyCode::Char('y') if ctrl => self.step(state, State::redo, "redo"),
+            KeyCode::Up => self.list.select_previous(),
+            KeyCode::Down => self.list.select_next(),
+            KeyCode::PageUp => self.list.scroll_up_by(10),
+            KeyCode::PageDown => self.list.scroll_down_by(10),
+            KeyCode::Home => self.list.select_first(),
+            KeyCode::End => self.list.select_last(),
+            KeyCode::Char(' ') => self.audition(state),
+            KeyCode::Delete => {
+                if let Some(path) = self.selected_file() {
+                    let marked = state.get(&path).and_then(|f| f.delete()) == Some(true);
+                    state.set_delete(&path, (!marked).then_some(true));
+                    self.dirty = true;
+                }
+            }
+            KeyCode::Char(c) if !ctrl && self.selected_file().is_some() => {
+                self.input = Some(c.to_string())
+            }
+            _ => {}
+        }
+        Control::Continue
+    }
+
+    /// Remove `tag` from the selected file if it carries it, add it otherwise.
+    fn toggle_tag(&mut self, state: &mut State, tag: &str) {
+        let path = match self.selected_file() {
+            Some(path) if !tag.is_empty() => path,
+            _ => return,
+        };
+        let tag = Tag::from(tag);
+        let carried = state.get(&path).is_some_and(|f| f.tags().contains(&tag));
+        if carried {
+            state.remove_tag(&path, &tag);
+            self.status = format!("removed {}", tag);
+            self.dirty = true;
+            return;
+        }
+        match state.add_tag(&path, tag.clone()) {
+            Ok(_) => {
+                self.status = format!("added {}", tag);
+                self.dirty = true;
+            }
+            Err(e) => {
+                let suggestions = state.suggest(&tag.to_string()).into_iter().join(", ");
+                self.status = if suggestions.is_empty() {
+                    e.to_string()
+                } else {
+                    format!("{} (did you mean {}?)", e, suggestions)
+                };
+            }
+        }
+    }
+
+    fn step(&mut self, state: &mut State, step: fn(&mut State) -> bool, name: &str) {
+        self.status = if step(state) {
+            self.dirty = true;
+            name.to_string()
+        } else {
+            format!("nothing to {}", name)
+        };
+    }
+
+    fn audition(&mut self, state: &State) {
+        let path = match self.selected_file() {
+            Some(path) => state.absolute_path(&path),
+            None => return,
+        };
+        self.status = match self.player.toggle(&path) {
+            Ok(true) => format!("playing {}", path),
+            Ok(false) => "stopped".to_string(),
+            Err(e) => format!("cannot play {}: {}", path, e),
+        };
+    }
+
+    fn draw(&mut self, frame: &mut Frame, state: &State) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [tree, details] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main);
+
+        let items: Vec<ListItem> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let name = row.path.file_name().unwrap_or(row.path.as_str());
+                let indent = "  ".repeat(row.depth);
+                if row.is_dir {
+                    return ListItem::new(format!("{}{}/", indent, name))
+                        .style(Style::new().add_modifier(Modifier::BOLD));
+                }
+                let info = state.get(&row.path);
+                let style = match info {
+                    Some(info) if info.delete() == Some(true) => Style::new()
+                        .fg(Color::Red)
+                        .add_modifier(Modifier::CROSSED_OUT),
+                    Some(info) if !info.tags().is_empty() => Style::new().fg(Color::Green),
+                    _ => Style::new(),
+                };
+                ListItem::new(format!("{}{}", indent, name)).style(style)
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title("files"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, tree, &mut self.list);
+
+        let mut lines = vec![];
+        if let Some(path) = self.selected_file() {
+            let info = state.get(&path);
+            let tags = info.map(|f| f.tags().as_slice()).unwrap_or_default();
+            if tags.is_empty() {
+                lines.push(Line::from("no tags").style(Style::new().add_modifier(Modifier::DIM)));
+            }
+            for tag in tags {
+                let style = match tag.color() {
+                    Some(c) => Style::new().fg(Color::Rgb(c.r, c.g, c.b)),
+                    None => Style::new(),
+                };
+                lines.push(Line::from(Span::styled(tag.to_string(), style)));
+            }
+            if info.and_then(|f| f.delete()) == Some(true) {
+                lines.push(Line::default());
+                lines.push(Line::from("marked for deletion").style(Style::new().fg(Color::Red)));
+            }
+        }
+        let title = self
+            .selected()
+            .map(|row| row.path.to_string())
+            .unwrap_or_default();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            details,
+        );
+
+        let status_line = match &self.input {
+            Some(input) => Line::from(vec![Span::raw("tag: "), Span::raw(input.as_str())]),
+            None => Line::from(self.status.as_str()),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+}
+
+/// Plays one file at a time.
+#[derive(Default)]
+struct Player {
+    #[cfg(feature = "audio")]
+    output: Option<(rodio::OutputStream, rodio::OutputStreamHandle)>,
+    #[cfg(feature = "audio")]
+    playing: Option<(Utf8PathBuf, rodio::Sink)>,
+}
+
+impl Player {
+    /// Start playing `path`, or stop if it's what is playing. Returns whether it's playing now.
+    #[cfg(feature = "audio")]
+    fn toggle(&mut self, path: &Utf8Path) -> anyhow::Result<bool> {
+        if let Some((playing, sink)) = self.playing.take() {
+            sink.stop();
+            if playing == path {
+                return Ok(false);
+            }
+        }
+        if self.output.is_none() {
+            self.output = Some(rodio::OutputStream::try_default()?);
+        }
+        let (_, handle) = self.output.as_ref().expect("just opened");
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        self.playing = Some((path.to_owned(), handle.play_once(file)?));
+        Ok(true)
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn toggle(&mut self, _path: &Utf8Path) -> anyhow::Result<bool> {
+        anyhow::bail!("built without the audio feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> State {
+        State::load(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/state.json")).unwrap()
+    }
+
+    fn press(app: &mut App, state: &mut State, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                '\t' => KeyCode::Tab,
+                '↓' => KeyCode::Down,
+                c => KeyCode::Char(c),
+            };
+            app.handle_key(KeyEvent::from(code), state);
+        }
+    }
+
+    #[test]
+    fn test_rows_follow_tree() {
+        let state = fixture();
+        let app = App::new(&state);
+        let rows: Vec<(usize, &str, bool)> = app
+            .rows
+            .iter()
+            .map(|row| (row.depth, row.path.as_str(), row.is_dir))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (0, "/music/a.wav", false),
+                (0, "/music/drums", true),
+                (1, "/music/drums/kick.wav", false),
+                (1, "/music/drums/snare.wav", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_to_toggle_tags() {
+        let mut state = fixture();
+        let mut app = App::new(&state);
+        let a = Utf8Path::new("/music/a.wav");
+        press(&mut app, &mut state, "warm\n");
+        assert_eq!(state.get(a).unwrap().tags().len(), 2);
+        press(&mut app, &mut state, "PAD\n");
+        assert_eq!(state.get(a).unwrap().tags(), &vec![Tag::from("warm")]);
+        assert!(app.dirty);
+
+        // tab completes from the tags in the state
+        press(&mut app, &mut state, "↓↓dr\t\n");
+        let kick = state.get("/music/drums/kick.wav".into()).unwrap();
+        assert_eq!(kick.tags(), &vec![Tag::from("Kick")]);
+    }
+
+    #[test]
+    fn test_directories_take_no_tags() {
+        let mut state = fixture();
+        let mut app = App::new(&state);
+        press(&mut app, &mut state, "↓x\n");
+        assert_eq!(app.input, None);
+        assert!(!app.dirty);
+        assert_eq!(
+            app.handle_key(KeyEvent::from(KeyCode::Esc), &mut state),
+            Control::Quit
+        );
+    }
+
+    #[test]
+    fn test_mark_and_undo() {
+        let mut state = fixture();
+        let mut app = App::new(&state);
+        let a = Utf8Path::new("/music/a.wav");
+        app.handle_key(KeyEvent::from(KeyCode::Delete), &mut state);
+        assert_eq!(state.get(a).unwrap().delete(), Some(true));
+        app.handle_key(
+            KeyEvent::new(KeyCode::Char('z'), KeyModifiers::CONTROL),
+            &mut state,
+        );
+        assert_eq!(state.get(a).unwrap().delete(), None);
+    }
+}
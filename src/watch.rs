@@ -0,0 +1,339 @@
+//! Keeping a loaded [`State`] current while files are created, removed and renamed under it.
+//!
+//! New files are held to the extension filter the state was loaded with, so a `wav` scan
+//! doesn't start tracking the `.asd` files a DAW writes next to each sample.
+
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+
+use crate::{FilepersonError, FsNode, State};
+
+/// A change that [`WatchHandle`] applied to the state, see [`WatchHandle::subscribe`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WatchEvent {
+    /// A file or directory was added to the trees.
+    Created(Utf8PathBuf),
+    /// A file or directory was dropped from the trees, along with the tags beneath it.
+    Removed(Utf8PathBuf),
+    /// A file or directory was renamed; its tags moved along.
+    Moved { from: Utf8PathBuf, to: Utf8PathBuf },
+}
+
+/// A live subscription to filesystem changes under a state's root(s), see [`State::watch`].
+///
+/// Events queue up in the background and are applied to the state by [`WatchHandle::pump`].
+/// Dropping the handle unsubscribes; [`WatchHandle::stop`] also applies what's still queued.
+pub struct WatchHandle<'a> {
+    state: &'a mut State,
+    watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    roots: Vec<Utf8PathBuf>,
+    subscribers: Vec<Sender<WatchEvent>>,
+}
+
+impl State {
+    /// Start watching the loaded root(s). New files and directories are added to the trees,
+    /// removed ones are dropped along with their tags, and renamed files keep their tags.
+    /// The extension filter applies to new files; other load-time filters (globs, regexes)
+    /// don't.
+    pub fn watch(&mut self) -> Result<WatchHandle<'_>, FilepersonError> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        let roots: Vec<Utf8PathBuf> = self
+            .walked_roots()
+            .into_iter()
+            .map(Utf8Path::to_owned)
+            .collect();
+        for root in &roots {
+            watcher.watch(root.as_std_path(), RecursiveMode::Recursive)?;
+        }
+        Ok(WatchHandle {
+            state: self,
+            watcher,
+            events,
+            roots,
+            subscribers: vec![],
+        })
+    }
+
+    /// Add `path` (and everything beneath it, for a directory) to the trees, unless it's
+    /// already there. Returns whether it was added.
+    fn track_path(&mut self, path: &Utf8Path) -> bool {
+        if self.root.paths().any(|p| p == path) {
+            return false;
+        }
+        let walk = self.loaded_walk();
+        let (node, files) = if path.is_dir() {
+            let (tree, flat) = walk.run(path);
+            (FsNode::Directory(tree), flat.entries)
+        } else if path.is_file() && walk.included(path) {
+            let node = FsNode::File(path.to_owned());
+            (node.clone(), vec![node])
+        } else {
+            return false;
+        };
+        if self.root.insert_node(node) {
+            self.flat.entries.extend(files);
+            true
+        } else {
+            log::debug!("{path:?} is outside of the loaded tree");
+            false
+        }
+    }
+
+    /// Drop `path` (and everything beneath it) from the trees, and from `infos` if `forget`.
+    /// Returns whether it was in the trees.
+    fn untrack_path(&mut self, path: &Utf8Path, forget: bool) -> bool {
+        let removed = self.root.remove_node(path).is_some();
+        self.flat
+            .entries
+            .retain(|node| !node.path().starts_with(path));
+        if forget {
+            self.infos.retain(|info| !info.path.starts_with(path));
+            self.forget_history(path);
+        }
+        removed
+    }
+
+    /// Move `from` (a file or a directory) to `to` in the trees and carry the infos along.
+    fn relocate(&mut self, from: &Utf8Path, to: &Utf8Path) {
+        self.untrack_path(from, false);
+        self.untrack_path(to, false);
+        self.track_path(to);
+
+        let moved: Vec<_> = self
+            .infos
+            .iter()
+            .filter(|info| info.path.starts_with(from))
+            .map(|info| info.path.clone())
+            .collect();
+        for path in moved {
+            if let Some(mut info) = self.take_info(&path) {
+                let relative = path.strip_prefix(from).expect("filtered by prefix");
+                info.path = if relative.as_str().is_empty() {
+                    to.to_owned()
+                } else {
+                    to.join(relative)
+                };
+                self.infos.replace(info);
+            }
+        }
+        self.forget_history(to);
+        self.move_history(from, to);
+    }
+}
+
+impl WatchHandle<'_> {
+    pub fn state(&self) -> &State {
+        self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        self.state
+    }
+
+    /// Receive a [`WatchEvent`] for every change applied from now on, in order. Events are
+    /// sent while pumping, so read them after [`WatchHandle::pump`] returns. Any number of
+    /// subscribers can be listening; dropping the receiver unsubscribes.
+    pub fn subscribe(&mut self) -> Receiver<WatchEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Apply all queued events without blocking. Returns how many changed the state.
+    pub fn pump(&mut self) -> Result<usize, FilepersonError> {
+        let mut applied = 0;
+        while let Ok(event) = self.events.try_recv() {
+            applied += self.apply(event?) as usize;
+        }
+        Ok(applied)
+    }
+
+    /// Like [`WatchHandle::pump`], but waits up to `timeout` for the first event.
+    pub fn pump_timeout(&mut self, timeout: Duration) -> Result<usize, FilepersonError> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => {
+                let applied = self.apply(event?) as usize;
+                Ok(applied + self.pump()?)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(0),
+            Err(RecvTimeoutError::Disconnected) => Err(FilepersonError::WatchDisconnected),
+        }
+    }
+
+    /// Unsubscribe and apply whatever was still queued.
+    pub fn stop(mut self) -> Result<usize, FilepersonError> {
+        for root in &self.roots {
+            self.watcher.unwatch(root.as_std_path())?;
+        }
+        self.pump()
+    }
+
+    /// Apply `event` to the state and tell subscribers what changed. Returns whether anything
+    /// did.
+    fn apply(&mut self, event: Event) -> bool {
+        let changes = self.changes(event);
+        for change in &changes {
+            self.subscribers
+                .retain(|subscriber| subscriber.send(change.clone()).is_ok());
+        }
+        !changes.is_empty()
+    }
+
+    fn changes(&mut self, event: Event) -> Vec<WatchEvent> {
+        let paths: Vec<Utf8PathBuf> = event
+            .paths
+            .iter()
+            .filter_map(|p| Utf8PathBuf::from_path_buf(p.clone()).ok())
+            .collect();
+        log::debug!("(watch) {:?} {paths:?}", event.kind);
+        let state = &mut *self.state;
+        let created = |state: &mut State, path: Utf8PathBuf| {
+            state.track_path(&path).then_some(WatchEvent::Created(path))
+        };
+        let removed = |state: &mut State, path: Utf8PathBuf| {
+            state
+                .untrack_path(&path, true)
+                .then_some(WatchEvent::Removed(path))
+        };
+        match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => paths
+                .into_iter()
+                .filter_map(|p| created(state, p))
+                .collect(),
+            EventKind::Remove(_) => paths
+                .into_iter()
+                .filter_map(|p| removed(state, p))
+                .collect(),
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() == 2 => {
+                state.relocate(&paths[0], &paths[1]);
+                let mut paths = paths.into_iter();
+                vec![WatchEvent::Moved {
+                    from: paths.next().expect("checked length"),
+                    to: paths.next().expect("checked length"),
+                }]
+            }
+            // The source half of a rename: tags are kept until the matching `Both` arrives, and
+            // so is the event.
+            EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                if event.attrs.tracker().is_some() =>
+            {
+                for path in &paths {
+                    state.untrack_path(path, false);
+                }
+                vec![]
+            }
+            // Backends that can't pair rename halves only tell us that something changed.
+            EventKind::Modify(ModifyKind::Name(_)) => paths
+                .into_iter()
+                .filter_map(|path| {
+                    if path.exists() {
+                        created(state, path)
+                    } else {
+                        removed(state, path)
+                    }
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Instant};
+
+    use crate::tests::fs_fixture;
+
+    use super::*;
+
+    /// Pump until `done` holds or a few seconds have passed.
+    fn pump_until(handle: &mut WatchHandle, done: impl Fn(&State) -> bool) -> anyhow::Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(handle.state()) {
+            if Instant::now() > deadline {
+                anyhow::bail!("timed out waiting for watch events");
+            }
+            handle.pump_timeout(Duration::from_millis(100))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_create() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        let mut handle = state.watch()?;
+
+        let events = handle.subscribe();
+
+        std::fs::write(root.join("notes.txt"), "")?;
+        let new = root.join("b.wav");
+        std::fs::write(&new, "")?;
+        pump_until(&mut handle, |state| state.flat.files().any(|p| p == new))?;
+        handle.stop()?;
+        assert_eq!(events.try_recv()?, WatchEvent::Created(new.clone()));
+
+        let files: Vec<&Utf8Path> = state.root.files().collect();
+        assert_eq!(files, vec![root.join("a.wav"), new]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_remove_event() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "b.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("b.wav"), "rock")?;
+        let mut handle = state.watch()?;
+        let events = handle.subscribe();
+        drop(handle.subscribe());
+
+        let gone = root.join("b.wav");
+        std::fs::remove_file(&gone)?;
+        pump_until(&mut handle, |state| state.flat.files().all(|p| p != gone))?;
+        handle.stop()?;
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![WatchEvent::Removed(gone)]
+        );
+        assert!(state.infos.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_rename_keeps_tags() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "sub/b.wav"])?;
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(root.join("sub/b.wav"), "rock")?;
+        let mut handle = state.watch()?;
+
+        std::fs::rename(root.join("sub"), root.join("moved"))?;
+        let moved = root.join("moved/b.wav");
+        pump_until(&mut handle, |state| {
+            state.flat.files().any(|p| p == moved) && state.infos.iter().any(|f| f.path == moved)
+        })?;
+        handle.stop()?;
+
+        assert_eq!(
+            state.flat.files().collect::<Vec<_>>(),
+            vec![root.join("a.wav"), moved.clone()]
+        );
+        let tags = state
+            .files_with_tag(&"rock".into())
+            .map(|f| f.path())
+            .collect::<Vec<_>>();
+        assert_eq!(tags, vec![moved.as_path()]);
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Tags stored in filesystem extended attributes, so they travel with the file itself.
+
+use std::collections::BTreeSet;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{FileInfo, FilepersonError, State, Tag};
+
+/// Name of the extended attribute holding a file's tags.
+pub const TAGS_XATTR: &str = "user.fileperson.tags";
+
+/// Tags are written newline-terminated, so a single tag containing a comma reads back intact.
+fn format_tags(tags: &[Tag]) -> String {
+    tags.iter().map(|t| format!("{}\n", t)).collect()
+}
+
+/// Split an attribute value into tags. Newline-separated if the value contains any newline,
+/// comma-separated otherwise.
+fn parse_tags(value: &str) -> Vec<Tag> {
+    let separator = if value.contains('\n') { '\n' } else { ',' };
+    value
+        .split(separator)
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(Tag::from)
+        .collect()
+}
+
+/// The tags stored in the [`TAGS_XATTR`] attribute of `path`, `None` if it has none.
+fn read_tags(path: &Utf8Path) -> Result<Option<Vec<Tag>>, FilepersonError> {
+    match xattr::get(path, TAGS_XATTR)? {
+        Some(value) => Ok(Some(parse_tags(&String::from_utf8(value)?))),
+        None => Ok(None),
+    }
+}
+
+/// Store `tags` in the [`TAGS_XATTR`] attribute of `path`, replacing any previous value.
+fn write_tags(path: &Utf8Path, tags: &[Tag]) -> Result<(), FilepersonError> {
+    xattr::set(path, TAGS_XATTR, format_tags(tags).as_bytes())?;
+    Ok(())
+}
+
+impl FileInfo {
+    /// Replace `tags` with the list stored in the [`TAGS_XATTR`] attribute.
+    /// If the attribute is absent, tags are left unchanged.
+    pub fn load_tags_from_xattr(&mut self) -> Result<(), FilepersonError> {
+        if let Some(tags) = read_tags(&self.path)? {
+            self.set_tags(tags);
+        }
+        Ok(())
+    }
+
+    /// Store `tags` in the [`TAGS_XATTR`] attribute, replacing any previous value.
+    pub fn write_tags_to_xattr(&self) -> Result<(), FilepersonError> {
+        write_tags(&self.path, &self.tags)
+    }
+}
+
+impl State {
+    /// Write the tags of every touched file to its extended attributes, on the file
+    /// [`State::absolute_path`] resolves it to. Failures don't stop the sync; they are collected
+    /// per file instead.
+    pub fn sync_xattrs(&self) -> Vec<(Utf8PathBuf, FilepersonError)> {
+        self.infos
+            .iter()
+            .filter(|info| info.touched())
+            .filter_map(|info| {
+                write_tags(&self.absolute_path(&info.path), &info.tags)
+                    .err()
+                    .map(|e| (info.path.clone(), e))
+            })
+            .collect()
+    }
+
+    /// The reverse of [`State::sync_xattrs`]: replace the tags of every file in the tree, and of
+    /// every tracked file, with those in its [`TAGS_XATTR`] attribute. Files without the
+    /// attribute keep their tags, so a fresh [`State::new`] over the same root recovers the tags
+    /// from the attributes alone. Each changed file is one undoable edit, and the tags must pass
+    /// the state's policy and vocabulary. Failures are collected per file.
+    pub fn import_xattrs(&mut self) -> Vec<(Utf8PathBuf, FilepersonError)> {
+        let paths: BTreeSet<Utf8PathBuf> = self
+            .infos
+            .iter()
+            .map(|info| info.path.clone())
+            .chain(self.root.files().map(Utf8Path::to_owned))
+            .collect();
+        let mut failed = vec![];
+        for path in paths {
+            let imported = read_tags(&self.absolute_path(&path)).and_then(|tags| match tags {
+                Some(tags) => self.set_tags(&path, tags).map(drop),
+                None => Ok(()),
+            });
+            if let Err(e) = imported {
+                failed.push((path, e));
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use anyhow::anyhow;
+
+    use crate::tests::fs_fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_load_tags_from_xattr() -> anyhow::Result<()> {
+        let dir = tempdir::TempDir::new("fileperson")?;
+        let root = Utf8Path::from_path(dir.path()).ok_or(anyhow!("temp dir is not utf-8"))?;
+        let path = root.join("a.wav");
+        std::fs::write(&path, "")?;
+
+        let mut info = FileInfo::from(&path);
+        info.add_tag("keep");
+        info.load_tags_from_xattr()?;
+        assert_eq!(info.tags(), &vec![Tag::from("keep")]);
+
+        xattr::set(&path, TAGS_XATTR, b"rock, live")?;
+        info.load_tags_from_xattr()?;
+        assert_eq!(info.tags(), &vec![Tag::from("live"), Tag::from("rock")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattr_round_trip() -> anyhow::Result<()> {
+        let dir = tempdir::TempDir::new("fileperson")?;
+        let root = Utf8Path::from_path(dir.path()).ok_or(anyhow!("temp dir is not utf-8"))?;
+        let path = root.join("a.wav");
+        std::fs::write(&path, "")?;
+
+        let mut state = crate::tests::state_fixture();
+        state.add_tag(&path, "drums, live")?;
+        state.add_tag(&path, "kick")?;
+        state.add_tag(root.join("missing.wav"), "rock")?;
+        let errors = state.sync_xattrs();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, root.join("missing.wav"));
+
+        let mut read = FileInfo::from(&path);
+        read.load_tags_from_xattr()?;
+        assert_eq!(
+            read.tags()
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>(),
+            vec!["drums, live", "kick"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_xattrs() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["a.wav", "b.wav", "c.wav"])?;
+        let (a, b, c) = (root.join("a.wav"), root.join("b.wav"), root.join("c.wav"));
+        let mut state = State::new(&root, HashSet::from(["wav"]))?;
+        state.add_tag(&a, "drums")?;
+        state.add_tag(&a, "live")?;
+        state.add_tag(&b, "keys")?;
+        assert!(state.sync_xattrs().is_empty());
+        // changed behind fileperson's back, e.g. with setfattr
+        xattr::set(&c, TAGS_XATTR, b"pad")?;
+
+        // a fresh state knows nothing but what the files carry
+        let mut recovered = State::new(&root, HashSet::from(["wav"]))?;
+        assert!(recovered.import_xattrs().is_empty());
+        let tags = |state: &State, path: &Utf8Path| -> Vec<String> {
+            state
+                .get(path)
+                .unwrap()
+                .tags()
+                .iter()
+                .map(Tag::to_string)
+                .collect()
+        };
+        assert_eq!(tags(&recovered, &a), ["drums", "live"]);
+        assert_eq!(tags(&recovered, &b), ["keys"]);
+        assert_eq!(tags(&recovered, &c), ["pad"]);
+
+        // files without the attribute keep their tags
+        xattr::remove(&b, TAGS_XATTR)?;
+        xattr::set(&a, TAGS_XATTR, b"drums\n")?;
+        state.add_tag(&b, "lead")?;
+        assert!(state.import_xattrs().is_empty());
+        assert_eq!(tags(&state, &a), ["drums"]);
+        assert_eq!(tags(&state, &b), ["keys", "lead"]);
+        assert!(state.undo());
+        assert!(tags(&state, &c).is_empty(), "c was the last import");
+        assert_eq!(tags(&state, &a), ["drums"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattrs_relative() -> anyhow::Result<()> {
+        let (_dir, root) = fs_fixture(&["sub/a.wav"])?;
+        let mut state = State::new_relative(&root, HashSet::from(["wav"]))?;
+        state.add_tag("sub/a.wav", "drums")?;
+        assert!(state.sync_xattrs().is_empty());
+        assert_eq!(
+            read_tags(&root.join("sub/a.wav"))?,
+            Some(vec![Tag::from("drums")])
+        );
+
+        state.remove_tag("sub/a.wav", &"drums".into());
+        assert!(state.import_xattrs().is_empty());
+        assert_eq!(
+            state.get("sub/a.wav".into()).unwrap().tags(),
+            &vec![Tag::from("drums")]
+        );
+        Ok(())
+    }
+}
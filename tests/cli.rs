@@ -0,0 +1,131 @@
+use std::{
+    path::Path,
+    process::{Command, Output},
+};
+
+use tempdir::TempDir;
+
+const STATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/state.json");
+
+fn run(args: &[&str]) -> Output {
+    run_on(Path::new(STATE), args)
+}
+
+fn run_on(state: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fileperson"))
+        .arg("--state")
+        .arg(state)
+        .args(args)
+        .output()
+        .expect("failed to run fileperson")
+}
+
+/// A copy of the fixture state for subcommands that write it.
+fn scratch_state() -> (TempDir, std::path::PathBuf) {
+    let dir = TempDir::new("fileperson").unwrap();
+    let state = dir.path().join("state.json");
+    std::fs::copy(STATE, &state).unwrap();
+    (dir, state)
+}
+
+fn fileperson(args: &[&str]) -> String {
+    fileperson_on(Path::new(STATE), args)
+}
+
+fn fileperson_on(state: &Path, args: &[&str]) -> String {
+    let output = run_on(state, args);
+    assert!(
+        output.status.success(),
+        "fileperson {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).expect("output is not UTF-8")
+}
+
+#[test]
+fn test_list() {
+    assert_eq!(
+        fileperson(&["list"]),
+        "/music/a.wav\tpad\n\
+         /music/drums/kick.wav\tdrums, Kick\n\
+         /music/drums/snare.wav\tdrums\n"
+    );
+}
+
+#[test]
+fn test_list_by_tag() {
+    assert_eq!(
+        fileperson(&["list", "--tag", "DRUMS"]),
+        "/music/drums/kick.wav\tdrums, Kick\n\
+         /music/drums/snare.wav\tdrums\n"
+    );
+}
+
+#[test]
+fn test_query() {
+    assert_eq!(
+        fileperson(&["query", "drums AND NOT kick"]),
+        "/music/drums/snare.wav\tdrums\n"
+    );
+    assert_eq!(
+        fileperson(&["query", "pad OR (kick AND drums)"]),
+        "/music/a.wav\tpad\n\
+         /music/drums/kick.wav\tdrums, Kick\n"
+    );
+    let output = run(&["query", "drums AND"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_rename_and_merge() {
+    let (_dir, state) = scratch_state();
+    assert_eq!(
+        fileperson_on(&state, &["rename", "DRUMS", "perc"]),
+        "renamed DRUMS to perc on 2 files\n"
+    );
+    assert_eq!(
+        fileperson_on(&state, &["merge", "pad", "kick", "--into", "one-shot"]),
+        "merged into one-shot on 2 files\n"
+    );
+    assert_eq!(
+        fileperson_on(&state, &["list"]),
+        "/music/a.wav\tone-shot\n\
+         /music/drums/kick.wav\tone-shot, perc\n\
+         /music/drums/snare.wav\tperc\n"
+    );
+}
+
+#[test]
+fn test_verify_reports_missing() {
+    // none of the fixture's files exist
+    let output = run(&["verify"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "missing\t/music/a.wav\n\
+         missing\t/music/drums/kick.wav\n\
+         missing\t/music/drums/snare.wav\n"
+    );
+}
+
+#[test]
+fn test_playlist() {
+    let dir = TempDir::new("fileperson").unwrap();
+    let playlist = dir.path().join("dark.m3u8");
+    let playlist_arg = playlist.to_str().unwrap();
+    assert_eq!(
+        fileperson(&["playlist", "drums", "-o", playlist_arg]),
+        format!("wrote 2 files to {}\n", playlist_arg)
+    );
+    assert_eq!(
+        std::fs::read_to_string(&playlist).unwrap(),
+        "/music/drums/kick.wav\n/music/drums/snare.wav\n"
+    );
+
+    fileperson(&["playlist", "pad", "-o", playlist_arg, "--extended"]);
+    assert_eq!(
+        std::fs::read_to_string(&playlist).unwrap(),
+        "#EXTM3U\n#EXTINF:-1,a\n/music/a.wav\n"
+    );
+}